@@ -0,0 +1,3 @@
+mod store;
+
+pub use self::store::{Store, DATA_ATTRIBUTE, EXPIRES_AT_ATTRIBUTE, UID_ATTRIBUTE};
@@ -0,0 +1,168 @@
+use aws_sdk_dynamodb::{error::DisplayErrorContext, types::AttributeValue, Client};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use webauth::clock::{Clock, MonotonicClock, SystemClock};
+use webauth::session::{Session, Uuid};
+use webauth::store::{Error, Identifiable, Store as StoreTrait};
+
+/// Partition key of the table, the session uid as a string
+pub const UID_ATTRIBUTE: &str = "uid";
+/// Expiration of the session, in seconds since the Unix epoch.
+/// Configure it as the TTL attribute of the table.
+pub const EXPIRES_AT_ATTRIBUTE: &str = "expires_at";
+/// The JSON encoded session
+pub const DATA_ATTRIBUTE: &str = "data";
+
+/// A `Store` of `Session`s backed by a DynamoDB table, for serverless
+/// deployments.
+///
+/// The table must have `uid` (string) as partition key, and should have TTL
+/// enabled on the `expires_at` attribute so DynamoDB deletes expired
+/// sessions. TTL deletion can lag behind by days, so `load` checks the
+/// expiration too.
+#[derive(Clone)]
+pub struct Store {
+    client: Client,
+    table: String,
+    clock: Arc<dyn Clock>,
+}
+
+impl Store {
+    /// Creates a `Store` using the given table, checking expiration against
+    /// the system clock (guarded against backward steps).
+    pub fn new(client: Client, table: impl Into<String>) -> Self {
+        Self::with_clock(client, table, MonotonicClock::new(SystemClock))
+    }
+
+    /// Creates a `Store` using the given table and `Clock` to check
+    /// expiration.
+    pub fn with_clock(
+        client: Client,
+        table: impl Into<String>,
+        clock: impl Clock + 'static,
+    ) -> Self {
+        Self {
+            client,
+            table: table.into(),
+            clock: Arc::new(clock),
+        }
+    }
+}
+
+impl std::fmt::Debug for Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Store").field("table", &self.table).finish()
+    }
+}
+
+impl StoreTrait for Store {
+    type Object = Session;
+
+    fn load(&self, id: &Uuid) -> impl Future<Output = Result<Option<Self::Object>, Error>> + Send {
+        let request = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .key(UID_ATTRIBUTE, AttributeValue::S(id.to_string()))
+            .consistent_read(true);
+        let clock = self.clock.clone();
+        async move {
+            let output = request.send().await.map_err(sdk)?;
+            let Some(item) = output.item() else {
+                return Ok(None);
+            };
+            // TTL deletion is eventual, expired items can still be read
+            if expires_at(item)? < secs(clock.now()) {
+                return Ok(None);
+            }
+            let data = item
+                .get(DATA_ATTRIBUTE)
+                .and_then(|data| data.as_s().ok())
+                .ok_or_else(|| Error::Storage(format!("missing {} attribute", DATA_ATTRIBUTE)))?;
+            serde_json::from_str(data).map(Some).map_err(Error::Decode)
+        }
+    }
+
+    fn save(&self, obj: &Self::Object) -> impl Future<Output = Result<(), Error>> + Send {
+        let request = serde_json::to_string(obj)
+            .map_err(Error::Encode)
+            .map(|data| {
+                self.client
+                    .put_item()
+                    .table_name(&self.table)
+                    .item(UID_ATTRIBUTE, AttributeValue::S(obj.uid().to_string()))
+                    .item(
+                        EXPIRES_AT_ATTRIBUTE,
+                        AttributeValue::N(secs(*obj.expires_at()).to_string()),
+                    )
+                    .item(DATA_ATTRIBUTE, AttributeValue::S(data))
+            });
+        async move {
+            request?.send().await.map_err(sdk)?;
+            Ok(())
+        }
+    }
+
+    fn delete(&self, id: &Uuid) -> impl Future<Output = Result<(), Error>> + Send {
+        let request = self
+            .client
+            .delete_item()
+            .table_name(&self.table)
+            .key(UID_ATTRIBUTE, AttributeValue::S(id.to_string()));
+        async move {
+            request.send().await.map_err(sdk)?;
+            Ok(())
+        }
+    }
+}
+
+// Seconds since the Unix epoch (the unit of DynamoDB TTL), rounded up so a
+// session never expires early, zero before the epoch
+fn secs(time: SystemTime) -> u64 {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO);
+    since_epoch.as_secs() + u64::from(since_epoch.subsec_nanos() > 0)
+}
+
+// Reads the expiration attribute of an item
+fn expires_at(item: &HashMap<String, AttributeValue>) -> Result<u64, Error> {
+    item.get(EXPIRES_AT_ATTRIBUTE)
+        .and_then(|expires_at| expires_at.as_n().ok())
+        .and_then(|expires_at| expires_at.parse().ok())
+        .ok_or_else(|| Error::Storage(format!("invalid {} attribute", EXPIRES_AT_ATTRIBUTE)))
+}
+
+// SDK errors only display their kind ("service error"), this displays their
+// whole context while keeping them as source
+#[derive(Debug)]
+struct SdkError<E>(E);
+
+impl<E> std::fmt::Display for SdkError<E>
+where
+    E: std::error::Error,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        DisplayErrorContext(&self.0).fmt(f)
+    }
+}
+
+impl<E> std::error::Error for SdkError<E>
+where
+    E: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+fn sdk<E>(err: E) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    Error::backend(SdkError(err))
+}
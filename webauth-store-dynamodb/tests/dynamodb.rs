@@ -0,0 +1,94 @@
+//! Runs against DynamoDB local, at `DYNAMODB_ENDPOINT` (defaults to
+//! `http://localhost:8000`):
+//!
+//! ```sh
+//! docker run -p 8000:8000 amazon/dynamodb-local
+//! cargo test -p webauth-store-dynamodb -- --ignored
+//! ```
+
+use aws_sdk_dynamodb::config::{BehaviorVersion, Credentials, Region};
+use aws_sdk_dynamodb::types::{
+    AttributeDefinition, BillingMode, KeySchemaElement, KeyType, ScalarAttributeType,
+};
+use aws_sdk_dynamodb::Client;
+use std::time::Duration;
+use webauth::clock::MockClock;
+use webauth::session::{Session, Uuid};
+use webauth::store::{Identifiable, Store as _};
+use webauth_store_dynamodb::{Store, UID_ATTRIBUTE};
+
+// Creates a client to DynamoDB local and a fresh table
+async fn table() -> (Client, String) {
+    let endpoint =
+        std::env::var("DYNAMODB_ENDPOINT").unwrap_or_else(|_| "http://localhost:8000".to_owned());
+    let config = aws_sdk_dynamodb::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .endpoint_url(endpoint)
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new("local", "local", None, None, "local"))
+        .build();
+    let client = Client::from_conf(config);
+
+    let table = format!("sessions-{}", Uuid::new_v4());
+    client
+        .create_table()
+        .table_name(&table)
+        .attribute_definitions(
+            AttributeDefinition::builder()
+                .attribute_name(UID_ATTRIBUTE)
+                .attribute_type(ScalarAttributeType::S)
+                .build()
+                .unwrap(),
+        )
+        .key_schema(
+            KeySchemaElement::builder()
+                .attribute_name(UID_ATTRIBUTE)
+                .key_type(KeyType::Hash)
+                .build()
+                .unwrap(),
+        )
+        .billing_mode(BillingMode::PayPerRequest)
+        .send()
+        .await
+        .unwrap();
+    (client, table)
+}
+
+#[tokio::test]
+#[ignore = "needs DynamoDB local"]
+async fn persistence() {
+    let (client, table) = table().await;
+    let store = Store::new(client, table);
+
+    let session = Session::new(Duration::from_secs(60));
+    session.insert("hello", "world").unwrap();
+    store.save(&session).await.unwrap();
+
+    let loaded = store.load(&session.uid()).await.unwrap().unwrap();
+    assert_eq!(session.uid(), loaded.uid());
+    assert_eq!(session.expires_at(), loaded.expires_at());
+    assert_eq!(Some("world".to_owned()), loaded.get("hello").unwrap());
+
+    store.delete(&session.uid()).await.unwrap();
+    assert!(store.load(&session.uid()).await.unwrap().is_none());
+    // Deleting is idempotent
+    store.delete(&session.uid()).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs DynamoDB local"]
+async fn expiry() {
+    let (client, table) = table().await;
+    let clock = MockClock::default();
+    let store = Store::with_clock(client, table, clock.clone());
+
+    let long = Session::new(Duration::from_secs(60));
+    let short = Session::new(Duration::from_secs(10));
+    store.save(&long).await.unwrap();
+    store.save(&short).await.unwrap();
+
+    // Still in the table, not deleted by TTL yet, but not loaded
+    clock.advance(Duration::from_secs(30));
+    assert!(store.load(&long.uid()).await.unwrap().is_some());
+    assert!(store.load(&short.uid()).await.unwrap().is_none());
+}
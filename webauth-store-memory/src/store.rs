@@ -1,20 +1,33 @@
 use std::{
-    any::TypeId,
     collections::HashMap,
     hash::Hash,
-    sync::{Arc, Mutex},
-    time::SystemTime,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
 };
-use webauth::session::Session;
-use webauth::store::{Error, Identifiable, Store as StoreTrait};
+use webauth::auth::SESSION_USER_KEY;
+use webauth::clock::{Clock, MonotonicClock, SystemClock};
+use webauth::session::{Session, Uuid};
+use webauth::store::{
+    ClearableStore, Detachable, EnumerableStore, Error, Expirable, Identifiable, SessionStore,
+    Store as StoreTrait, Versioned, VersionedStore,
+};
+
+// Objects along with their version, by uid
+type Objects<Object> = HashMap<<Object as Identifiable>::Uid, (Object, u64)>;
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct Store<Object>
 where
     Object: Identifiable,
     <Object as Identifiable>::Uid: Hash + Eq + Copy,
 {
-    objects: Arc<Mutex<HashMap<<Object as Identifiable>::Uid, Object>>>,
+    objects: Arc<Mutex<Objects<Object>>>,
+    // Last version given, shared by all objects so a re-created object
+    // never gets a version it had before.
+    version: Arc<AtomicU64>,
+    clock: Arc<dyn Clock>,
 }
 
 impl<Object> Store<Object>
@@ -22,16 +35,53 @@ where
     Object: Identifiable,
     <Object as Identifiable>::Uid: Hash + Eq + Copy,
 {
+    /// Creates a new empty `Store`, checking expiration against the system
+    /// clock (guarded against backward steps).
     pub fn new() -> Self {
+        Self::with_clock(MonotonicClock::new(SystemClock))
+    }
+
+    /// Creates a new empty `Store` using the given `Clock` to check expiration.
+    pub fn with_clock(clock: impl Clock + 'static) -> Self {
         Self {
             objects: Default::default(),
+            version: Default::default(),
+            clock: Arc::new(clock),
         }
     }
+
+    // Locks the objects, recovering from poisoning: a caller panicking while
+    // holding the lock must not break every later use of the store.
+    fn objects(&self) -> MutexGuard<'_, Objects<Object>> {
+        self.objects.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Returns the object and its version, if not expired
+    fn get(&self, id: &<Object as Identifiable>::Uid) -> Option<(Object, u64)>
+    where
+        Object: Detachable + Expirable,
+    {
+        let now = self.clock.now();
+        let map = self.objects();
+        map.get(id)
+            .filter(|(obj, _)| obj.expires_at().is_none_or(|expires_at| expires_at >= now))
+            .map(|(obj, version)| (obj.detach(), *version))
+    }
+}
+
+impl<Object> Default for Store<Object>
+where
+    Object: Identifiable,
+    <Object as Identifiable>::Uid: Hash + Eq + Copy,
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<Object> StoreTrait for Store<Object>
 where
-    Object: Identifiable + Clone + Send + 'static,
+    Object: Identifiable + Expirable + Detachable + Send + 'static,
     <Object as Identifiable>::Uid: Hash + Eq + Copy,
 {
     type Object = Object;
@@ -40,21 +90,7 @@ where
         &self,
         id: &<Self::Object as Identifiable>::Uid,
     ) -> impl std::future::Future<Output = Result<Option<Self::Object>, Error>> + Send {
-        let map = self.objects.lock().expect("poisoned mutex");
-        let mut obj = map.get(id);
-        if TypeId::of::<Object>() == TypeId::of::<Session>() {
-            // Specific case for sessions which can expire, so we must check
-            // the expiration. This is a bit ugly but we don't have a ton of solutions
-            // to runtime cast from generic type.
-            if let Some(sess) = obj {
-                let sess: &Session = unsafe { std::mem::transmute::<&Object, &Session>(sess) };
-                if sess.expires_at() < &SystemTime::now() {
-                    // Session is expired
-                    obj = None;
-                }
-            }
-        }
-        let obj = obj.cloned();
+        let obj = self.get(id).map(|(obj, _)| obj);
         async move { Ok(obj) }
     }
 
@@ -62,8 +98,9 @@ where
         &self,
         obj: &Self::Object,
     ) -> impl std::future::Future<Output = Result<(), Error>> + Send {
-        let mut map = self.objects.lock().expect("poisoned mutex");
-        map.insert(obj.uid(), obj.clone());
+        let mut map = self.objects();
+        let version = self.version.fetch_add(1, Ordering::Relaxed) + 1;
+        map.insert(obj.uid(), (obj.detach(), version));
         async move { Ok(()) }
     }
 
@@ -71,8 +108,433 @@ where
         &self,
         id: &<Self::Object as Identifiable>::Uid,
     ) -> impl std::future::Future<Output = Result<(), Error>> + Send {
-        let mut map = self.objects.lock().expect("poisoned mutex");
+        let mut map = self.objects();
         map.remove(id);
         async move { Ok(()) }
     }
+
+    // Holds the lock for a single pass over the objects
+    fn purge_expired(&self) -> impl std::future::Future<Output = Result<u64, Error>> + Send {
+        let now = self.clock.now();
+        let mut map = self.objects();
+        let before = map.len();
+        map.retain(|_, (obj, _)| obj.expires_at().is_none_or(|expires_at| expires_at >= now));
+        let purged = (before - map.len()) as u64;
+        drop(map);
+        async move { Ok(purged) }
+    }
+}
+
+impl<Object> VersionedStore for Store<Object>
+where
+    Object: Identifiable + Expirable + Detachable + Send + 'static,
+    <Object as Identifiable>::Uid: Hash + Eq + Copy,
+{
+    type Version = u64;
+
+    fn load_versioned(
+        &self,
+        id: &<Self::Object as Identifiable>::Uid,
+    ) -> impl std::future::Future<Output = Result<Option<Versioned<Self>>, Error>> + Send {
+        let entry = self.get(id);
+        async move { Ok(entry) }
+    }
+
+    fn delete_if(
+        &self,
+        id: &<Self::Object as Identifiable>::Uid,
+        version: &Self::Version,
+    ) -> impl std::future::Future<Output = Result<bool, Error>> + Send {
+        let mut map = self.objects();
+        let deleted = match map.get(id) {
+            Some((_, current)) if current == version => map.remove(id).is_some(),
+            _ => false,
+        };
+        async move { Ok(deleted) }
+    }
+}
+
+impl<Object> EnumerableStore for Store<Object>
+where
+    Object: Identifiable + Expirable + Detachable + Send + 'static,
+    <Object as Identifiable>::Uid: Hash + Eq + Copy,
+{
+    fn load_all(
+        &self,
+    ) -> impl std::future::Future<Output = Result<Vec<Self::Object>, Error>> + Send {
+        let uids = self.objects().keys().copied().collect::<Vec<_>>();
+        // Through `get` to skip expired objects
+        let objects = uids
+            .iter()
+            .filter_map(|uid| self.get(uid).map(|(obj, _)| obj))
+            .collect();
+        async move { Ok(objects) }
+    }
+}
+
+impl<Object> ClearableStore for Store<Object>
+where
+    Object: Identifiable + Expirable + Detachable + Send + 'static,
+    <Object as Identifiable>::Uid: Hash + Eq + Copy,
+{
+    fn clear_all(&self) -> impl std::future::Future<Output = Result<(), Error>> + Send {
+        self.objects().clear();
+        async move { Ok(()) }
+    }
+}
+
+/// Scans all the sessions: this is linear in the number of sessions held,
+/// whether they belong to the user or not (and expired ones until purged).
+impl SessionStore for Store<Session> {
+    fn sessions_for_user(
+        &self,
+        user_uid: &Uuid,
+    ) -> impl std::future::Future<Output = Result<Vec<Session>, Error>> + Send {
+        let now = self.clock.now();
+        let sessions = self
+            .objects()
+            .values()
+            .filter(|(session, _)| !session.is_expired_at(now))
+            .filter(|(session, _)| {
+                // Data not holding a uid cannot be the user's
+                session.get::<Uuid>(SESSION_USER_KEY).ok().flatten() == Some(*user_uid)
+            })
+            .map(|(session, _)| session.detach())
+            .collect();
+        async move { Ok(sessions) }
+    }
+
+    fn delete_by_user(
+        &self,
+        user_uid: &Uuid,
+    ) -> impl std::future::Future<Output = Result<u64, Error>> + Send {
+        let mut objects = self.objects();
+        let before = objects.len();
+        objects.retain(|_, (session, _)| {
+            session.get::<Uuid>(SESSION_USER_KEY).ok().flatten() != Some(*user_uid)
+        });
+        let deleted = (before - objects.len()) as u64;
+        async move { Ok(deleted) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, SystemTime};
+    use webauth::clock::MockClock;
+
+    #[tokio::test]
+    async fn clock_backward_step() -> Result<(), Error> {
+        let mock = MockClock::default();
+        let store = Store::with_clock(MonotonicClock::new(mock.clone()));
+
+        let long = Session::new(Duration::from_secs(60));
+        let short = Session::new(Duration::from_secs(10));
+        store.save(&long).await?;
+        store.save(&short).await?;
+
+        // Short session expires, long one is still valid
+        mock.advance(Duration::from_secs(30));
+        assert!(store.load(&long.uid()).await?.is_some());
+        assert!(store.load(&short.uid()).await?.is_none());
+
+        // Clock steps back an hour: the long session must not be expired,
+        // and the short one must not come back to life.
+        mock.rewind(Duration::from_secs(60 * 60));
+        assert!(store.load(&long.uid()).await?.is_some());
+        assert!(store.load(&short.uid()).await?.is_none());
+
+        // Once past the real deadline, the long session expires
+        mock.advance(Duration::from_secs(60 * 60 + 40));
+        assert!(store.load(&long.uid()).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expirable() -> Result<(), Error> {
+        #[derive(Debug, Clone)]
+        struct Token(u64, Option<SystemTime>);
+
+        impl Detachable for Token {}
+
+        impl Identifiable for Token {
+            type Uid = u64;
+
+            fn uid(&self) -> Self::Uid {
+                self.0
+            }
+        }
+
+        impl Expirable for Token {
+            fn expires_at(&self) -> Option<SystemTime> {
+                self.1
+            }
+        }
+
+        let mock = MockClock::default();
+        let store = Store::with_clock(mock.clone());
+        store.save(&Token(1, None)).await?;
+        store
+            .save(&Token(2, Some(mock.now() + Duration::from_secs(10))))
+            .await?;
+
+        mock.advance(Duration::from_secs(30));
+        assert!(store.load(&1).await?.is_some());
+        assert!(store.load(&2).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn purge_expired() -> Result<(), Error> {
+        let mock = MockClock::default();
+        let store = Store::with_clock(mock.clone());
+        assert_eq!(0, store.purge_expired().await?);
+
+        let long = Session::new(Duration::from_secs(60));
+        let short = Session::new(Duration::from_secs(10));
+        store.save(&long).await?;
+        store.save(&short).await?;
+        assert_eq!(0, store.purge_expired().await?);
+
+        // Only the expired session is purged
+        mock.advance(Duration::from_secs(30));
+        assert_eq!(1, store.purge_expired().await?);
+        assert_eq!(1, store.objects.lock().expect("poisoned mutex").len());
+        assert!(store.load(&long.uid()).await?.is_some());
+        assert_eq!(0, store.purge_expired().await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sessions_for_user() -> Result<(), Error> {
+        let mock = MockClock::default();
+        let store = Store::with_clock(mock.clone());
+        let user = Uuid::new_v4();
+        assert!(store.sessions_for_user(&user).await?.is_empty());
+
+        let laptop = Session::new(Duration::from_secs(60));
+        laptop.insert(SESSION_USER_KEY, user).expect("insert");
+        let phone = Session::new(Duration::from_secs(10));
+        phone.insert(SESSION_USER_KEY, user).expect("insert");
+        let other = Session::new(Duration::from_secs(60));
+        other
+            .insert(SESSION_USER_KEY, Uuid::new_v4())
+            .expect("insert");
+        let anonymous = Session::new(Duration::from_secs(60));
+        for session in [&laptop, &phone, &other, &anonymous] {
+            store.save(session).await?;
+        }
+
+        let mut found = store
+            .sessions_for_user(&user)
+            .await?
+            .iter()
+            .map(Session::uid)
+            .collect::<Vec<_>>();
+        found.sort();
+        let mut expected = vec![laptop.uid(), phone.uid()];
+        expected.sort();
+        assert_eq!(expected, found);
+
+        // Expired sessions are not returned
+        mock.advance(Duration::from_secs(30));
+        let found = store.sessions_for_user(&user).await?;
+        assert_eq!(1, found.len());
+        assert_eq!(laptop.uid(), found[0].uid());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_by_user() -> Result<(), Error> {
+        let store = Store::<Session>::new();
+        let user = Uuid::new_v4();
+        let other = Session::new(Duration::from_secs(60));
+        other
+            .insert(SESSION_USER_KEY, Uuid::new_v4())
+            .expect("insert");
+        let anonymous = Session::new(Duration::from_secs(60));
+        store.save(&other).await?;
+        store.save(&anonymous).await?;
+
+        // Logged in twice
+        for _ in 0..2 {
+            let session = Session::new(Duration::from_secs(60));
+            session.insert(SESSION_USER_KEY, user).expect("insert");
+            store.save(&session).await?;
+        }
+
+        assert_eq!(2, store.delete_by_user(&user).await?);
+        assert!(store.sessions_for_user(&user).await?.is_empty());
+        assert!(store.load(&other.uid()).await?.is_some());
+        assert!(store.load(&anonymous.uid()).await?.is_some());
+        assert_eq!(0, store.delete_by_user(&user).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detached() -> Result<(), Error> {
+        let store = Store::new();
+        let session = Session::new(Duration::from_secs(60));
+        session.insert("key", "saved").expect("insert");
+        store.save(&session).await?;
+
+        // Changes after the save are not seen until saved again
+        session.insert("key", "changed").expect("insert");
+        session.cycle_uid();
+        let loaded = store.load(&session.cycled_from().unwrap()).await?.unwrap();
+        assert_eq!(Some("saved".to_owned()), loaded.get("key").expect("get"));
+        assert!(!loaded.is_modified());
+        assert!(store.load(&session.uid()).await?.is_none());
+
+        // Nor changes to a loaded session
+        loaded.insert("key", "loaded").expect("insert");
+        let reloaded = store.load(&loaded.uid()).await?.unwrap();
+        assert_eq!(Some("saved".to_owned()), reloaded.get("key").expect("get"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detached_data() -> Result<(), Error> {
+        // Any `SessionData`, not only the default one
+        let store = Store::<Session<BTreeMap<String, Value>>>::new();
+        let session = Session::new_with_data(Duration::from_secs(60), BTreeMap::new());
+        session.insert("key", "saved").expect("insert");
+        store.save(&session).await?;
+
+        session.insert("key", "changed").expect("insert");
+        session.cycle_uid();
+        let loaded = store.load(&session.cycled_from().unwrap()).await?.unwrap();
+        assert_eq!(Some("saved".to_owned()), loaded.get("key").expect("get"));
+        assert!(store.load(&session.uid()).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn versions() -> Result<(), Error> {
+        let store = Store::new();
+        let session = Session::new(Duration::from_secs(60));
+        assert!(store.load_versioned(&session.uid()).await?.is_none());
+
+        store.save(&session).await?;
+        let (_, v1) = store.load_versioned(&session.uid()).await?.expect("saved");
+        // Stable across reads
+        let (_, again) = store.load_versioned(&session.uid()).await?.expect("saved");
+        assert_eq!(v1, again);
+
+        // Changes across saves
+        store.save(&session).await?;
+        let (_, v2) = store.load_versioned(&session.uid()).await?.expect("saved");
+        assert_ne!(v1, v2);
+
+        // A re-created object does not get an old version back
+        store.delete(&session.uid()).await?;
+        store.save(&session).await?;
+        let (_, v3) = store.load_versioned(&session.uid()).await?.expect("saved");
+        assert!(v3 != v1 && v3 != v2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_if() -> Result<(), Error> {
+        let store = Store::new();
+        let session = Session::new(Duration::from_secs(60));
+        store.save(&session).await?;
+        let (_, v1) = store.load_versioned(&session.uid()).await?.expect("saved");
+
+        // Saved again since read, the version does not match anymore
+        store.save(&session).await?;
+        assert!(!store.delete_if(&session.uid(), &v1).await?);
+        assert!(store.load(&session.uid()).await?.is_some());
+
+        // Matching version deletes
+        let (_, v2) = store.load_versioned(&session.uid()).await?.expect("saved");
+        assert!(store.delete_if(&session.uid(), &v2).await?);
+        assert!(store.load(&session.uid()).await?.is_none());
+
+        // Already deleted
+        assert!(!store.delete_if(&session.uid(), &v2).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clear_all() -> Result<(), Error> {
+        let store = Store::new();
+
+        let sessions = (0..3)
+            .map(|_| Session::new(Duration::from_secs(60)))
+            .collect::<Vec<_>>();
+        for session in &sessions {
+            store.save(session).await?;
+        }
+        for session in &sessions {
+            assert!(store.load(&session.uid()).await?.is_some());
+        }
+
+        store.clear_all().await?;
+        for session in &sessions {
+            assert!(store.load(&session.uid()).await?.is_none());
+        }
+
+        // Clearing an empty store is fine
+        store.clear_all().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn poisoned() -> Result<(), Error> {
+        let store = Store::new();
+        let session = Session::new(Duration::from_secs(60));
+        store.save(&session).await?;
+
+        // A caller panics while holding the lock
+        let objects = store.objects.clone();
+        let panicked = std::thread::spawn(move || {
+            let _guard = objects.lock().expect("poisoned mutex");
+            panic!("caller panicked");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(store.objects.is_poisoned());
+
+        // The store is still usable
+        assert!(store.load(&session.uid()).await?.is_some());
+        store.delete(&session.uid()).await?;
+        assert!(store.load(&session.uid()).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn load_all() -> Result<(), Error> {
+        let mock = MockClock::default();
+        let store = Store::with_clock(mock.clone());
+        assert!(store.load_all().await?.is_empty());
+
+        let long = Session::new(Duration::from_secs(60));
+        let short = Session::new(Duration::from_secs(10));
+        store.save(&long).await?;
+        store.save(&short).await?;
+        assert_eq!(2, store.load_all().await?.len());
+
+        // Expired objects are not listed
+        mock.advance(Duration::from_secs(30));
+        let all = store.load_all().await?;
+        assert_eq!(1, all.len());
+        assert_eq!(long.uid(), all[0].uid());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,2 @@
+mod store;
+pub use self::store::RedisStore;
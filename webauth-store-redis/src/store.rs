@@ -0,0 +1,156 @@
+use redis::aio::ConnectionManager;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt::Display, future::Future, marker::PhantomData, time::SystemTime};
+use webauth::store::{ClearableStore, Error, Expirable, Identifiable, Store};
+
+/// A `Store` backed by Redis, for ephemeral resources like sessions.
+///
+/// Objects are stored as JSON under their uid prefixed by the given prefix
+/// (e.g. `session:`). Expirable objects are saved with their expiration
+/// (`SET ... PXAT`, Redis 6.2+) so Redis deletes them itself, there is
+/// nothing to purge.
+pub struct RedisStore<Object> {
+    manager: ConnectionManager,
+    prefix: String,
+    _object: PhantomData<fn() -> Object>,
+}
+
+impl<Object> RedisStore<Object> {
+    /// Creates a `Store` using the given connection manager, which handles
+    /// reconnecting, and key prefix.
+    pub fn new(manager: ConnectionManager, prefix: impl Into<String>) -> Self {
+        Self {
+            manager,
+            prefix: prefix.into(),
+            _object: PhantomData,
+        }
+    }
+
+    // Key of the object with the given uid
+    fn key(&self, uid: &impl Display) -> String {
+        format!("{}{}", self.prefix, uid)
+    }
+}
+
+impl<Object> Clone for RedisStore<Object> {
+    fn clone(&self) -> Self {
+        Self::new(self.manager.clone(), self.prefix.clone())
+    }
+}
+
+impl<Object> std::fmt::Debug for RedisStore<Object> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisStore")
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Object> Store for RedisStore<Object>
+where
+    Object: Identifiable + Expirable + Serialize + DeserializeOwned + Send + 'static,
+    Object::Uid: Display,
+{
+    type Object = Object;
+
+    fn load(
+        &self,
+        uid: &Object::Uid,
+    ) -> impl Future<Output = Result<Option<Self::Object>, Error>> + Send {
+        let mut conn = self.manager.clone();
+        let get = redis::cmd("GET").arg(self.key(uid)).to_owned();
+        async move {
+            let data: Option<Vec<u8>> = get.query_async(&mut conn).await.map_err(Error::backend)?;
+            data.map(|data| serde_json::from_slice(&data).map_err(Error::Decode))
+                .transpose()
+        }
+    }
+
+    fn save(&self, obj: &Self::Object) -> impl Future<Output = Result<(), Error>> + Send {
+        let mut conn = self.manager.clone();
+        let set = serde_json::to_vec(obj).map_err(Error::Encode).map(|data| {
+            let mut set = redis::cmd("SET");
+            set.arg(self.key(&obj.uid())).arg(data);
+            if let Some(expires_at) = obj.expires_at() {
+                set.arg("PXAT").arg(epoch_millis(expires_at));
+            }
+            set
+        });
+        async move {
+            set?.query_async::<()>(&mut conn)
+                .await
+                .map_err(Error::backend)
+        }
+    }
+
+    fn delete(&self, uid: &Object::Uid) -> impl Future<Output = Result<(), Error>> + Send {
+        let mut conn = self.manager.clone();
+        let del = redis::cmd("DEL").arg(self.key(uid)).to_owned();
+        async move {
+            del.query_async::<()>(&mut conn)
+                .await
+                .map_err(Error::backend)
+        }
+    }
+}
+
+/// Scans the keys starting with the prefix of the store (with `SCAN`, so
+/// Redis is not blocked) and deletes them, the whole database with an empty
+/// prefix. Objects saved while it runs may be left.
+impl<Object> ClearableStore for RedisStore<Object>
+where
+    Object: Identifiable + Expirable + Serialize + DeserializeOwned + Send + 'static,
+    Object::Uid: Display,
+{
+    fn clear_all(&self) -> impl Future<Output = Result<(), Error>> + Send {
+        let mut conn = self.manager.clone();
+        let pattern = format!("{}*", escape_pattern(&self.prefix));
+        async move {
+            let mut cursor = 0u64;
+            loop {
+                let (next, keys): (u64, Vec<Vec<u8>>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(&pattern)
+                    .arg("COUNT")
+                    .arg(SCAN_COUNT)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(Error::backend)?;
+                if !keys.is_empty() {
+                    redis::cmd("DEL")
+                        .arg(keys)
+                        .query_async::<()>(&mut conn)
+                        .await
+                        .map_err(Error::backend)?;
+                }
+                if next == 0 {
+                    return Ok(());
+                }
+                cursor = next;
+            }
+        }
+    }
+}
+
+// Keys scanned at once by `clear_all`
+const SCAN_COUNT: usize = 500;
+
+// Escapes the glob characters of `MATCH` patterns
+fn escape_pattern(prefix: &str) -> String {
+    let mut escaped = String::with_capacity(prefix.len());
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Milliseconds since the Unix epoch, at least one as `PXAT` refuses zero
+fn epoch_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis().try_into().unwrap_or(u64::MAX))
+        .max(1)
+}
@@ -0,0 +1,78 @@
+//! Runs against the Redis server at `REDIS_URL` (defaults to
+//! `redis://localhost`):
+//!
+//! ```sh
+//! docker run -p 6379:6379 redis
+//! cargo test -p webauth-store-redis -- --ignored
+//! ```
+
+use redis::aio::ConnectionManager;
+use std::time::{Duration, SystemTime};
+use webauth::session::{Session, Uuid};
+use webauth::store::{ClearableStore as _, Identifiable, Store as _};
+use webauth_store_redis::RedisStore;
+
+// Connects to the server, with a prefix of its own
+async fn store() -> RedisStore<Session> {
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost".to_owned());
+    let client = redis::Client::open(url).unwrap();
+    let manager = ConnectionManager::new(client).await.unwrap();
+    RedisStore::new(manager, format!("test-{}:", Uuid::new_v4()))
+}
+
+#[tokio::test]
+#[ignore = "needs Redis"]
+async fn persistence() {
+    let store = store().await;
+
+    let session = Session::new(Duration::from_secs(60));
+    assert!(store.load(&session.uid()).await.unwrap().is_none());
+
+    session.insert("hello", "world").unwrap();
+    store.save(&session).await.unwrap();
+    let loaded = store.load(&session.uid()).await.unwrap().unwrap();
+    assert_eq!(session.uid(), loaded.uid());
+    assert_eq!(Some("world".to_owned()), loaded.get("hello").unwrap());
+
+    store.delete(&session.uid()).await.unwrap();
+    assert!(store.load(&session.uid()).await.unwrap().is_none());
+    // Deleting is idempotent
+    store.delete(&session.uid()).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs Redis"]
+async fn expiry() {
+    let store = store().await;
+
+    let mut session = Session::new(Duration::from_secs(60));
+    session.set_expires_at(SystemTime::now() + Duration::from_millis(500));
+    store.save(&session).await.unwrap();
+    assert!(store.load(&session.uid()).await.unwrap().is_some());
+
+    // Deleted by Redis
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(store.load(&session.uid()).await.unwrap().is_none());
+}
+
+#[tokio::test]
+#[ignore = "needs Redis"]
+async fn clear_all() {
+    let (store, other) = (store().await, store().await);
+    let sessions = (0..3)
+        .map(|_| Session::new(Duration::from_secs(60)))
+        .collect::<Vec<_>>();
+    for session in &sessions {
+        store.save(session).await.unwrap();
+    }
+    let kept = Session::new(Duration::from_secs(60));
+    other.save(&kept).await.unwrap();
+
+    // Only the keys of the store are deleted
+    store.clear_all().await.unwrap();
+    for session in &sessions {
+        assert!(store.load(&session.uid()).await.unwrap().is_none());
+    }
+    assert!(other.load(&kept.uid()).await.unwrap().is_some());
+    other.clear_all().await.unwrap();
+}
@@ -0,0 +1,216 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use webauth::clock::{Clock, MonotonicClock, SystemClock};
+use webauth::session::{Session, Uuid};
+use webauth::store::{ClearableStore, Error, Identifiable, Store as StoreTrait};
+
+// Size of the expiration prefix of the stored values
+const EXPIRES_AT_LEN: usize = std::mem::size_of::<u64>();
+
+/// A `Store` of `Session`s backed by a `sled` tree, for applications that
+/// want persistence without a database server.
+///
+/// Sessions are keyed by the bytes of their uid. Values are the JSON encoded
+/// session, prefixed by its expiration (milliseconds since the Unix epoch,
+/// big endian) so expiration can be checked without decoding the session.
+#[derive(Clone)]
+pub struct Store {
+    tree: sled::Tree,
+    clock: Arc<dyn Clock>,
+}
+
+impl Store {
+    /// Creates a `Store` using the given tree, checking expiration against
+    /// the system clock (guarded against backward steps).
+    pub fn new(tree: sled::Tree) -> Self {
+        Self::with_clock(tree, MonotonicClock::new(SystemClock))
+    }
+
+    /// Creates a `Store` using the given tree and `Clock` to check expiration.
+    pub fn with_clock(tree: sled::Tree, clock: impl Clock + 'static) -> Self {
+        Self {
+            tree,
+            clock: Arc::new(clock),
+        }
+    }
+}
+
+impl std::fmt::Debug for Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Store")
+            .field("tree", &String::from_utf8_lossy(&self.tree.name()))
+            .finish()
+    }
+}
+
+impl StoreTrait for Store {
+    type Object = Session;
+
+    fn load(&self, id: &Uuid) -> impl Future<Output = Result<Option<Self::Object>, Error>> + Send {
+        let now = millis(self.clock.now());
+        let session = self
+            .tree
+            .get(id.as_bytes())
+            .map_err(Error::backend)
+            .and_then(|value| {
+                let Some(value) = value else {
+                    return Ok(None);
+                };
+                if expires_at(&value)? < now {
+                    return Ok(None);
+                }
+                serde_json::from_slice(&value[EXPIRES_AT_LEN..])
+                    .map(Some)
+                    .map_err(Error::Decode)
+            });
+        async move { session }
+    }
+
+    fn save(&self, obj: &Self::Object) -> impl Future<Output = Result<(), Error>> + Send {
+        let saved = serde_json::to_vec(obj)
+            .map_err(Error::Encode)
+            .and_then(|json| {
+                let mut value = Vec::with_capacity(EXPIRES_AT_LEN + json.len());
+                value.extend_from_slice(&millis(*obj.expires_at()).to_be_bytes());
+                value.extend_from_slice(&json);
+                self.tree
+                    .insert(obj.uid().as_bytes(), value)
+                    .map(|_| ())
+                    .map_err(Error::backend)
+            });
+        async move { saved }
+    }
+
+    fn delete(&self, id: &Uuid) -> impl Future<Output = Result<(), Error>> + Send {
+        let deleted = self
+            .tree
+            .remove(id.as_bytes())
+            .map(|_| ())
+            .map_err(Error::backend);
+        async move { deleted }
+    }
+
+    fn purge_expired(&self) -> impl Future<Output = Result<u64, Error>> + Send {
+        let now = millis(self.clock.now());
+        let purged = || {
+            let mut purged = 0;
+            for entry in self.tree.iter() {
+                let (key, value) = entry.map_err(Error::backend)?;
+                if expires_at(&value)? < now {
+                    self.tree.remove(key).map_err(Error::backend)?;
+                    purged += 1;
+                }
+            }
+            Ok(purged)
+        };
+        let purged = purged();
+        async move { purged }
+    }
+}
+
+/// Clears the tree, other trees of the database are left untouched.
+impl ClearableStore for Store {
+    fn clear_all(&self) -> impl Future<Output = Result<(), Error>> + Send {
+        let cleared = self.tree.clear().map_err(Error::backend);
+        async move { cleared }
+    }
+}
+
+// Milliseconds since the Unix epoch, zero before it
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis()
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
+
+// Reads the expiration prefix of a stored value
+fn expires_at(value: &[u8]) -> Result<u64, Error> {
+    value
+        .get(..EXPIRES_AT_LEN)
+        .and_then(|prefix| prefix.try_into().ok())
+        .map(u64::from_be_bytes)
+        .ok_or_else(|| Error::Storage("truncated value".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webauth::clock::MockClock;
+
+    #[tokio::test]
+    async fn persistence() -> Result<(), Error> {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let session = Session::new(Duration::from_secs(60));
+        session.insert("hello", "world").expect("insert");
+
+        {
+            let db = sled::open(dir.path()).map_err(Error::backend)?;
+            let store = Store::new(db.open_tree("sessions").map_err(Error::backend)?);
+            store.save(&session).await?;
+            db.flush().map_err(Error::backend)?;
+        }
+
+        // Reopen the database
+        let db = sled::open(dir.path()).map_err(Error::backend)?;
+        let store = Store::new(db.open_tree("sessions").map_err(Error::backend)?);
+        let loaded = store.load(&session.uid()).await?.expect("persisted");
+        assert_eq!(session.uid(), loaded.uid());
+        assert_eq!(session.expires_at(), loaded.expires_at());
+        assert_eq!(Some("world".to_owned()), loaded.get("hello").expect("get"));
+
+        store.delete(&session.uid()).await?;
+        assert!(store.load(&session.uid()).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expiry() -> Result<(), Error> {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let db = sled::open(dir.path()).map_err(Error::backend)?;
+        let clock = MockClock::default();
+        let store = Store::with_clock(
+            db.open_tree("sessions").map_err(Error::backend)?,
+            clock.clone(),
+        );
+
+        let long = Session::new(Duration::from_secs(60));
+        let short = Session::new(Duration::from_secs(10));
+        store.save(&long).await?;
+        store.save(&short).await?;
+
+        clock.advance(Duration::from_secs(30));
+        assert!(store.load(&long.uid()).await?.is_some());
+        assert!(store.load(&short.uid()).await?.is_none());
+
+        // Only the expired session is purged
+        assert_eq!(1, store.purge_expired().await?);
+        assert_eq!(1, db.open_tree("sessions").map_err(Error::backend)?.len());
+        assert_eq!(0, store.purge_expired().await?);
+        assert!(store.load(&long.uid()).await?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clear_all() -> Result<(), Error> {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let db = sled::open(dir.path()).map_err(Error::backend)?;
+        let store = Store::new(db.open_tree("sessions").map_err(Error::backend)?);
+        let other = db.open_tree("other").map_err(Error::backend)?;
+        other.insert("key", "value").map_err(Error::backend)?;
+
+        let session = Session::new(Duration::from_secs(60));
+        store.save(&session).await?;
+        store.clear_all().await?;
+        assert!(store.load(&session.uid()).await?.is_none());
+        assert_eq!(1, other.len());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS sessions (
+    uid BINARY(16) NOT NULL PRIMARY KEY,
+    expires_at DATETIME(6) NULL,
+    data JSON NOT NULL,
+    INDEX sessions_expires_at (expires_at)
+);
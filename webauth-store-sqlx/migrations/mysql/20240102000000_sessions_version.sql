@@ -0,0 +1,2 @@
+-- Drawn again on every save, for `VersionedStore`
+ALTER TABLE sessions ADD COLUMN version BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS sessions (
+    uid UUID PRIMARY KEY,
+    expires_at TIMESTAMPTZ,
+    data JSONB NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS sessions_expires_at ON sessions (expires_at);
@@ -0,0 +1,2 @@
+-- Supports the containment queries finding the sessions of a user
+CREATE INDEX IF NOT EXISTS sessions_data ON sessions USING GIN (data jsonb_path_ops);
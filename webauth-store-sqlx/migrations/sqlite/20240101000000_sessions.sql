@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS sessions (
+    uid BLOB PRIMARY KEY NOT NULL,
+    expires_at INTEGER,
+    data TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS sessions_expires_at ON sessions (expires_at);
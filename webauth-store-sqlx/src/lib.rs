@@ -1,8 +1,94 @@
+//! `Store`s backed by SQL databases, one module per feature.
+//!
+//! Each module has a `MIGRATOR` creating the `sessions` table its store
+//! reads from, to run on startup (`MIGRATOR.run(&pool).await`) or to copy
+//! into your own migrations. Running them again only applies the new ones.
+//! The column types differ per database:
+//!
+//! | feature    | `uid`        | `expires_at`            | `data`  | `version` |
+//! |------------|--------------|-------------------------|---------|-----------|
+//! | `postgres` | `UUID`       | `TIMESTAMPTZ`           | `JSONB` | `BIGINT`  |
+//! | `mysql`    | `BINARY(16)` | `DATETIME(6)`           | `JSON`  | `BIGINT`  |
+//! | `sqlite`   | `BLOB`       | `INTEGER` (epoch secs)  | `TEXT`  | `INTEGER` |
+//!
+//! `version` is drawn at random on every save, for `VersionedStore`.
+//!
+//! All of them index `expires_at` for `purge_expired`, PostgreSQL also has
+//! a GIN index on `data` for `sessions_for_user` and `delete_by_user`.
+//!
+//! The stores are not specific to sessions: they keep any `Identifiable`
+//! object serializable with serde (tokens, users, ...), the `Expirable`
+//! implementation of the object filling `expires_at`. They can read from
+//! another table instead (see `TableName`), which must have the same
+//! columns, to keep each type of object in its own table.
+
+use std::sync::Arc;
+
 #[cfg(feature = "postgres")]
-mod postgres;
+pub mod postgres;
 
 #[cfg(feature = "mysql")]
-mod mysql;
+pub mod mysql;
 
 #[cfg(feature = "sqlite")]
-mod sqlite;
+pub mod sqlite;
+
+// A random version for `VersionedStore`, drawn on every save so an object
+// re-created after being deleted never gets a version it had before
+#[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+fn new_version() -> i64 {
+    webauth::session::Uuid::new_v4().as_u64_pair().0 as i64
+}
+
+/// Name of the table a store reads from, `sessions` by default.
+///
+/// It is interpolated in the queries, so only plain identifiers are
+/// accepted: ASCII letters, digits and underscores, not starting with a
+/// digit, at most 63 characters (the PostgreSQL limit).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableName(Arc<str>);
+
+impl TableName {
+    /// Validates the given name
+    pub fn new(name: &str) -> Result<Self, InvalidTableName> {
+        let mut chars = name.chars();
+        let valid = chars
+            .next()
+            .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && name.len() <= 63;
+        if !valid {
+            return Err(InvalidTableName(name.to_owned()));
+        }
+        Ok(Self(name.into()))
+    }
+
+    /// Returns the name
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for TableName {
+    fn default() -> Self {
+        Self("sessions".into())
+    }
+}
+
+impl std::fmt::Display for TableName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A table name which is not a plain identifier (see `TableName`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidTableName(pub String);
+
+impl std::fmt::Display for InvalidTableName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid table name: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidTableName {}
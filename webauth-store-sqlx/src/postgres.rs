@@ -0,0 +1,256 @@
+use crate::TableName;
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::{migrate::Migrator, types::Json, PgPool, Postgres};
+use std::{future::Future, marker::PhantomData, time::SystemTime};
+use webauth::auth::SESSION_USER_KEY;
+use webauth::session::{Session, Uuid};
+use webauth::store::{
+    ClearableStore, Error, Expirable, Identifiable, SessionStore, Store, Versioned, VersionedStore,
+};
+
+/// Migrations creating the `sessions` table read by `PostgresStore`
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// A `Store` backed by a PostgreSQL `sessions` table.
+///
+/// Objects are stored as `jsonb` in the `data` column, along with their
+/// `uid` and `expires_at` (`timestamptz`, `NULL` if they never expire).
+/// Expiration is checked against the database clock. The table is created by
+/// the `MIGRATOR` migrations.
+pub struct PostgresStore<Object> {
+    pool: PgPool,
+    table: TableName,
+    _object: PhantomData<fn() -> Object>,
+}
+
+impl<Object> PostgresStore<Object> {
+    /// Creates a `Store` using the given pool.
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            table: TableName::default(),
+            _object: PhantomData,
+        }
+    }
+
+    /// Uses the given table instead of `sessions`, which must have the
+    /// columns the `MIGRATOR` migrations create.
+    pub fn with_table(mut self, table: TableName) -> Self {
+        self.table = table;
+        self
+    }
+}
+
+impl<Object> Clone for PostgresStore<Object> {
+    fn clone(&self) -> Self {
+        Self::new(self.pool.clone()).with_table(self.table.clone())
+    }
+}
+
+impl<Object> std::fmt::Debug for PostgresStore<Object> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PostgresStore")
+            .field("table", &self.table)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Object> Store for PostgresStore<Object>
+where
+    Object: Identifiable + Expirable + Serialize + DeserializeOwned + Send + Unpin + 'static,
+    Object::Uid: for<'q> sqlx::Encode<'q, Postgres> + sqlx::Type<Postgres> + Clone + Send + 'static,
+{
+    type Object = Object;
+
+    fn load(
+        &self,
+        uid: &Object::Uid,
+    ) -> impl Future<Output = Result<Option<Self::Object>, Error>> + Send {
+        let pool = self.pool.clone();
+        let table = self.table.clone();
+        let uid = uid.clone();
+        async move {
+            let obj: Option<Json<Object>> = sqlx::query_scalar(&format!(
+                "SELECT data FROM {} \
+                 WHERE uid = $1 AND (expires_at IS NULL OR expires_at > now())",
+                table
+            ))
+            .bind(uid)
+            .fetch_optional(&pool)
+            .await
+            .map_err(Error::backend)?;
+            Ok(obj.map(|Json(obj)| obj))
+        }
+    }
+
+    fn save(&self, obj: &Self::Object) -> impl Future<Output = Result<(), Error>> + Send {
+        let pool = self.pool.clone();
+        let table = self.table.clone();
+        let uid = obj.uid();
+        let expires_at = obj.expires_at().map(epoch_secs);
+        let data = serde_json::to_value(obj).map_err(Error::Encode);
+        async move {
+            sqlx::query(&format!(
+                "INSERT INTO {} (uid, expires_at, data, version) \
+                 VALUES ($1, to_timestamp($2), $3, $4) \
+                 ON CONFLICT (uid) DO UPDATE \
+                 SET expires_at = EXCLUDED.expires_at, data = EXCLUDED.data, \
+                 version = EXCLUDED.version",
+                table
+            ))
+            .bind(uid)
+            .bind(expires_at)
+            .bind(Json(data?))
+            .bind(crate::new_version())
+            .execute(&pool)
+            .await
+            .map_err(Error::backend)?;
+            Ok(())
+        }
+    }
+
+    fn delete(&self, uid: &Object::Uid) -> impl Future<Output = Result<(), Error>> + Send {
+        let pool = self.pool.clone();
+        let table = self.table.clone();
+        let uid = uid.clone();
+        async move {
+            sqlx::query(&format!("DELETE FROM {} WHERE uid = $1", table))
+                .bind(uid)
+                .execute(&pool)
+                .await
+                .map_err(Error::backend)?;
+            Ok(())
+        }
+    }
+
+    fn purge_expired(&self) -> impl Future<Output = Result<u64, Error>> + Send {
+        let pool = self.pool.clone();
+        let table = self.table.clone();
+        async move {
+            let result = sqlx::query(&format!("DELETE FROM {} WHERE expires_at <= now()", table))
+                .execute(&pool)
+                .await
+                .map_err(Error::backend)?;
+            Ok(result.rows_affected())
+        }
+    }
+}
+
+/// Versions are random numbers, drawn again on every save.
+impl<Object> VersionedStore for PostgresStore<Object>
+where
+    Object: Identifiable + Expirable + Serialize + DeserializeOwned + Send + Unpin + 'static,
+    Object::Uid: for<'q> sqlx::Encode<'q, Postgres> + sqlx::Type<Postgres> + Clone + Send + 'static,
+{
+    type Version = i64;
+
+    fn load_versioned(
+        &self,
+        uid: &Object::Uid,
+    ) -> impl Future<Output = Result<Option<Versioned<Self>>, Error>> + Send {
+        let pool = self.pool.clone();
+        let table = self.table.clone();
+        let uid = uid.clone();
+        async move {
+            let obj: Option<(Json<Object>, i64)> = sqlx::query_as(&format!(
+                "SELECT data, version FROM {} \
+                 WHERE uid = $1 AND (expires_at IS NULL OR expires_at > now())",
+                table
+            ))
+            .bind(uid)
+            .fetch_optional(&pool)
+            .await
+            .map_err(Error::backend)?;
+            Ok(obj.map(|(Json(obj), version)| (obj, version)))
+        }
+    }
+
+    fn delete_if(
+        &self,
+        uid: &Object::Uid,
+        version: &i64,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        let pool = self.pool.clone();
+        let table = self.table.clone();
+        let uid = uid.clone();
+        let version = *version;
+        async move {
+            let result = sqlx::query(&format!(
+                "DELETE FROM {} WHERE uid = $1 AND version = $2",
+                table
+            ))
+            .bind(uid)
+            .bind(version)
+            .execute(&pool)
+            .await
+            .map_err(Error::backend)?;
+            Ok(result.rows_affected() > 0)
+        }
+    }
+}
+
+/// Truncates the table, along with the objects of other types kept in it.
+impl<Object> ClearableStore for PostgresStore<Object>
+where
+    Object: Identifiable + Expirable + Serialize + DeserializeOwned + Send + Unpin + 'static,
+    Object::Uid: for<'q> sqlx::Encode<'q, Postgres> + sqlx::Type<Postgres> + Clone + Send + 'static,
+{
+    fn clear_all(&self) -> impl Future<Output = Result<(), Error>> + Send {
+        let pool = self.pool.clone();
+        let table = self.table.clone();
+        async move {
+            sqlx::query(&format!("TRUNCATE {}", table))
+                .execute(&pool)
+                .await
+                .map_err(Error::backend)?;
+            Ok(())
+        }
+    }
+}
+
+/// Matches the sessions with a `jsonb` containment query, which can use a GIN
+/// index on the `data` column.
+impl SessionStore for PostgresStore<Session> {
+    fn sessions_for_user(
+        &self,
+        user_uid: &Uuid,
+    ) -> impl Future<Output = Result<Vec<Session>, Error>> + Send {
+        let pool = self.pool.clone();
+        let table = self.table.clone();
+        let filter = serde_json::json!({ "data": { SESSION_USER_KEY: user_uid } });
+        async move {
+            let sessions: Vec<Json<Session>> = sqlx::query_scalar(&format!(
+                "SELECT data FROM {} \
+                 WHERE data @> $1 AND (expires_at IS NULL OR expires_at > now())",
+                table
+            ))
+            .bind(Json(filter))
+            .fetch_all(&pool)
+            .await
+            .map_err(Error::backend)?;
+            Ok(sessions.into_iter().map(|Json(session)| session).collect())
+        }
+    }
+
+    fn delete_by_user(&self, user_uid: &Uuid) -> impl Future<Output = Result<u64, Error>> + Send {
+        let pool = self.pool.clone();
+        let table = self.table.clone();
+        let filter = serde_json::json!({ "data": { SESSION_USER_KEY: user_uid } });
+        async move {
+            let result = sqlx::query(&format!("DELETE FROM {} WHERE data @> $1", table))
+                .bind(Json(filter))
+                .execute(&pool)
+                .await
+                .map_err(Error::backend)?;
+            Ok(result.rows_affected())
+        }
+    }
+}
+
+// Seconds since the Unix epoch, as taken by `to_timestamp`
+fn epoch_secs(time: SystemTime) -> f64 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => since.as_secs_f64(),
+        Err(err) => -err.duration().as_secs_f64(),
+    }
+}
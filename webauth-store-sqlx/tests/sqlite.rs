@@ -0,0 +1,241 @@
+#![cfg(feature = "sqlite")]
+
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+use std::time::{Duration, SystemTime};
+use webauth::auth::SESSION_USER_KEY;
+use webauth::session::{Session, Uuid};
+use webauth::store::{
+    ClearableStore as _, Identifiable, SessionStore as _, Store as _, VersionedStore as _,
+};
+use webauth::token::{self, OneTimeToken, Purpose};
+use webauth_store_sqlx::sqlite::{SqliteStore, MIGRATOR};
+use webauth_store_sqlx::TableName;
+
+// Every connection to `sqlite::memory:` has its own database: keep a single
+// one open for the whole test
+async fn pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
+    pool
+}
+
+#[tokio::test]
+async fn persistence() {
+    let store = SqliteStore::<Session>::new(pool().await);
+
+    let session = Session::new(Duration::from_secs(60));
+    assert!(store.load(&session.uid()).await.unwrap().is_none());
+
+    session.insert("hello", "world").unwrap();
+    store.save(&session).await.unwrap();
+    let loaded = store.load(&session.uid()).await.unwrap().unwrap();
+    assert_eq!(session.uid(), loaded.uid());
+    assert_eq!(Some("world".to_owned()), loaded.get("hello").unwrap());
+
+    // Saving again updates
+    session.insert("hello", "again").unwrap();
+    store.save(&session).await.unwrap();
+    let loaded = store.load(&session.uid()).await.unwrap().unwrap();
+    assert_eq!(Some("again".to_owned()), loaded.get("hello").unwrap());
+
+    store.delete(&session.uid()).await.unwrap();
+    assert!(store.load(&session.uid()).await.unwrap().is_none());
+    // Deleting is idempotent
+    store.delete(&session.uid()).await.unwrap();
+}
+
+#[tokio::test]
+async fn expiry() {
+    let store = SqliteStore::<Session>::new(pool().await);
+
+    let valid = Session::new(Duration::from_secs(60));
+    let mut expired = Session::new(Duration::from_secs(60));
+    expired.set_expires_at(SystemTime::now() - Duration::from_secs(10));
+    store.save(&valid).await.unwrap();
+    store.save(&expired).await.unwrap();
+
+    assert!(store.load(&valid.uid()).await.unwrap().is_some());
+    assert!(store.load(&expired.uid()).await.unwrap().is_none());
+
+    // Only the expired session is purged
+    assert_eq!(1, store.purge_expired().await.unwrap());
+    assert_eq!(0, store.purge_expired().await.unwrap());
+    assert!(store.load(&valid.uid()).await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn sessions_for_user() {
+    let store = SqliteStore::<Session>::new(pool().await);
+    let user = Uuid::new_v4();
+
+    let session = Session::new(Duration::from_secs(60));
+    session.insert(SESSION_USER_KEY, user).unwrap();
+    let mut expired = Session::new(Duration::from_secs(60));
+    expired.insert(SESSION_USER_KEY, user).unwrap();
+    expired.set_expires_at(SystemTime::now() - Duration::from_secs(10));
+    let other = Session::new(Duration::from_secs(60));
+    other.insert(SESSION_USER_KEY, Uuid::new_v4()).unwrap();
+    let anonymous = Session::new(Duration::from_secs(60));
+    for session in [&session, &expired, &other, &anonymous] {
+        store.save(session).await.unwrap();
+    }
+
+    let found = store.sessions_for_user(&user).await.unwrap();
+    assert_eq!(1, found.len());
+    assert_eq!(session.uid(), found[0].uid());
+}
+
+#[tokio::test]
+async fn table_name() {
+    let pool = pool().await;
+    sqlx::query(
+        "CREATE TABLE tenant_sessions \
+         (uid BLOB PRIMARY KEY NOT NULL, expires_at INTEGER, data TEXT NOT NULL, \
+         version INTEGER NOT NULL DEFAULT 0)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let table = TableName::new("tenant_sessions").unwrap();
+    let store = SqliteStore::<Session>::new(pool.clone()).with_table(table);
+
+    let session = Session::new(Duration::from_secs(60));
+    store.save(&session).await.unwrap();
+    assert!(store.load(&session.uid()).await.unwrap().is_some());
+    let count = |table: &'static str| {
+        let pool = pool.clone();
+        async move {
+            sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) FROM {}", table))
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        }
+    };
+    assert_eq!(1, count("tenant_sessions").await);
+    assert_eq!(0, count("sessions").await);
+
+    for invalid in [
+        "",
+        "1sessions",
+        "sessions; DROP TABLE users",
+        "\"sessions\"",
+        "séances",
+    ] {
+        assert!(TableName::new(invalid).is_err(), "{}", invalid);
+    }
+    assert!(TableName::new(&"a".repeat(64)).is_err());
+    assert_eq!("_tenant_42", TableName::new("_tenant_42").unwrap().as_str());
+}
+
+#[tokio::test]
+async fn other_objects() {
+    let pool = pool().await;
+    sqlx::query(
+        "CREATE TABLE tokens \
+         (uid BLOB PRIMARY KEY NOT NULL, expires_at INTEGER, data TEXT NOT NULL, \
+         version INTEGER NOT NULL DEFAULT 0)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let table = TableName::new("tokens").unwrap();
+    let store = SqliteStore::<OneTimeToken<Uuid>>::new(pool).with_table(table);
+    let user = Uuid::new_v4();
+
+    let ttl = Duration::from_secs(60);
+    let (raw, issued) = token::issue(&store, user, Purpose::PasswordReset, ttl)
+        .await
+        .unwrap();
+    let loaded = store.load(&issued.uid()).await.unwrap().unwrap();
+    assert_eq!(issued.validator_hash, loaded.validator_hash);
+    assert_eq!(
+        Some(user),
+        token::consume(&store, &raw, Purpose::PasswordReset)
+            .await
+            .unwrap()
+    );
+    assert!(store.load(&issued.uid()).await.unwrap().is_none());
+
+    // Expiry comes from `Expirable`
+    let expired = OneTimeToken {
+        expires_at: SystemTime::now() - Duration::from_secs(10),
+        ..issued
+    };
+    store.save(&expired).await.unwrap();
+    assert!(store.load(&expired.uid()).await.unwrap().is_none());
+    assert_eq!(1, store.purge_expired().await.unwrap());
+}
+
+#[tokio::test]
+async fn delete_by_user() {
+    let store = SqliteStore::<Session>::new(pool().await);
+    let user = Uuid::new_v4();
+    let other = Session::new(Duration::from_secs(60));
+    other.insert(SESSION_USER_KEY, Uuid::new_v4()).unwrap();
+    let anonymous = Session::new(Duration::from_secs(60));
+    store.save(&other).await.unwrap();
+    store.save(&anonymous).await.unwrap();
+
+    // Logged in twice
+    for _ in 0..2 {
+        let session = Session::new(Duration::from_secs(60));
+        session.insert(SESSION_USER_KEY, user).unwrap();
+        store.save(&session).await.unwrap();
+    }
+
+    assert_eq!(2, store.delete_by_user(&user).await.unwrap());
+    assert!(store.sessions_for_user(&user).await.unwrap().is_empty());
+    assert!(store.load(&other.uid()).await.unwrap().is_some());
+    assert!(store.load(&anonymous.uid()).await.unwrap().is_some());
+    assert_eq!(0, store.delete_by_user(&user).await.unwrap());
+}
+
+#[tokio::test]
+async fn clear_all() {
+    let store = SqliteStore::<Session>::new(pool().await);
+
+    let sessions = (0..3)
+        .map(|_| Session::new(Duration::from_secs(60)))
+        .collect::<Vec<_>>();
+    for session in &sessions {
+        store.save(session).await.unwrap();
+    }
+
+    store.clear_all().await.unwrap();
+    for session in &sessions {
+        assert!(store.load(&session.uid()).await.unwrap().is_none());
+    }
+    // Clearing an empty table is fine
+    store.clear_all().await.unwrap();
+}
+
+#[tokio::test]
+async fn versions() {
+    let store = SqliteStore::<Session>::new(pool().await);
+    let session = Session::new(Duration::from_secs(60));
+    let uid = session.uid();
+    assert!(store.load_versioned(&uid).await.unwrap().is_none());
+
+    // Reads keep the version, saves change it
+    store.save(&session).await.unwrap();
+    let (loaded, version) = store.load_versioned(&uid).await.unwrap().unwrap();
+    assert_eq!(uid, loaded.uid());
+    let (_, again) = store.load_versioned(&uid).await.unwrap().unwrap();
+    assert_eq!(version, again);
+    store.save(&session).await.unwrap();
+    let (_, saved) = store.load_versioned(&uid).await.unwrap().unwrap();
+    assert_ne!(version, saved);
+
+    // Only deleted with its current version
+    assert!(!store.delete_if(&uid, &version).await.unwrap());
+    assert!(store.load(&uid).await.unwrap().is_some());
+    assert!(store.delete_if(&uid, &saved).await.unwrap());
+    assert!(store.load(&uid).await.unwrap().is_none());
+    assert!(!store.delete_if(&uid, &saved).await.unwrap());
+}
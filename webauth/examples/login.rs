@@ -0,0 +1,105 @@
+use axum::{
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+    Form, Router,
+};
+use serde::Deserialize;
+use std::{convert::Infallible, net::SocketAddr};
+use uuid::Uuid;
+use webauth::auth::{self, AuthBackend};
+use webauth::axum::{Backend, ProtectedUser};
+use webauth::session::{Session, SessionManagerLayer};
+use webauth::store::{Detachable, Expirable, Identifiable, Store as _};
+use webauth::user::UserManagerLayer;
+use webauth_store_memory::Store;
+
+#[derive(Debug, Clone)]
+struct User {
+    uid: Uuid,
+    name: String,
+}
+
+impl Identifiable for User {
+    type Uid = Uuid;
+
+    fn uid(&self) -> Self::Uid {
+        self.uid
+    }
+}
+
+impl Detachable for User {}
+
+impl Expirable for User {}
+
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    name: String,
+}
+
+// Lets anybody in by name, a real backend would check a password
+#[derive(Clone)]
+struct Users(Store<User>);
+
+impl AuthBackend for Users {
+    type User = User;
+    type Credentials = Credentials;
+    type Error = Infallible;
+
+    async fn authenticate(&self, credentials: Credentials) -> Result<Option<User>, Infallible> {
+        let user = User {
+            uid: Uuid::new_v4(),
+            name: credentials.name,
+        };
+        // The memory store cannot fail
+        let _ = self.0.save(&user).await;
+        Ok(Some(user))
+    }
+
+    async fn get_user(&self, uid: &Uuid) -> Result<Option<User>, Infallible> {
+        Ok(self.0.load(uid).await.ok().flatten())
+    }
+}
+
+async fn login(
+    session: Session,
+    Backend(users): Backend<Users>,
+    Form(credentials): Form<Credentials>,
+) -> impl IntoResponse {
+    match users.authenticate(credentials).await {
+        Ok(Some(user)) => match auth::login(&session, &user) {
+            Ok(()) => Redirect::to("/").into_response(),
+            Err(err) => format!("unable to log in: {}", err).into_response(),
+        },
+        _ => Redirect::to("/login").into_response(),
+    }
+}
+
+async fn root(ProtectedUser(user): ProtectedUser<User>) -> impl IntoResponse {
+    format!("hello {}", user.name)
+}
+
+#[tokio::main]
+async fn main() {
+    let sessions = Store::<Session>::new();
+    let users = Users(Store::new());
+    // Shared by all the routes, to agree on the session cookie
+    let sessions = SessionManagerLayer::new(sessions, "uid");
+
+    // `curl -c cookies -d name=alice localhost:42000/login` then
+    // `curl -b cookies localhost:42000/`
+    let app = Router::new()
+        .route(
+            "/login",
+            post(login).layer(sessions.clone().with_backend(users.clone())),
+        )
+        .route(
+            "/",
+            get(root).layer(UserManagerLayer::new(sessions, users.0.clone()).with_backend(users)),
+        );
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], 42000));
+    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    axum::serve(listener, app.into_make_service())
+        .await
+        .unwrap();
+}
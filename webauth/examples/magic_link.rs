@@ -0,0 +1,135 @@
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+    Extension, Form, Router,
+};
+use serde::Deserialize;
+use std::{convert::Infallible, net::SocketAddr, time::Duration};
+use uuid::Uuid;
+use webauth::axum::ProtectedUser;
+use webauth::magic_link::{self, EmailUserStore};
+use webauth::session::{Session, SessionManagerLayer};
+use webauth::store::{Detachable, Error, Expirable, Identifiable, Store as _};
+use webauth::token::OneTimeToken;
+use webauth::user::UserManagerLayer;
+use webauth_store_memory::Store;
+
+#[derive(Debug, Clone)]
+struct User {
+    uid: Uuid,
+    email: String,
+}
+
+impl Identifiable for User {
+    type Uid = Uuid;
+
+    fn uid(&self) -> Self::Uid {
+        self.uid
+    }
+}
+
+impl Detachable for User {}
+
+impl Expirable for User {}
+
+// The memory store cannot search, look through a list instead
+#[derive(Clone)]
+struct Users(Store<User>, Vec<User>);
+
+impl webauth::store::Store for Users {
+    type Object = User;
+
+    async fn load(&self, uid: &Uuid) -> Result<Option<User>, Error> {
+        self.0.load(uid).await
+    }
+
+    async fn save(&self, user: &User) -> Result<(), Error> {
+        self.0.save(user).await
+    }
+
+    async fn delete(&self, uid: &Uuid) -> Result<(), Error> {
+        self.0.delete(uid).await
+    }
+}
+
+impl EmailUserStore for Users {
+    async fn load_by_email(&self, email: &str) -> Result<Option<User>, Error> {
+        Ok(self.1.iter().find(|user| user.email == email).cloned())
+    }
+}
+
+type Tokens = Store<OneTimeToken<String>>;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    email: String,
+}
+
+async fn request(
+    Extension(tokens): Extension<Tokens>,
+    Extension(users): Extension<Users>,
+    Form(request): Form<Request>,
+) -> impl IntoResponse {
+    let ttl = Duration::from_secs(15 * 60);
+    let sent = magic_link::issue(&tokens, &users, &request.email, ttl, |token| async move {
+        // A real application would email it
+        println!("curl -c cookies localhost:42000/login/{}", token);
+        Ok::<_, Infallible>(())
+    })
+    .await;
+    match sent {
+        // Whether the account exists or not
+        Ok(()) => "check your inbox".into_response(),
+        Err(err) => format!("unable to send the link: {}", err).into_response(),
+    }
+}
+
+async fn login(
+    session: Session,
+    Extension(tokens): Extension<Tokens>,
+    Extension(users): Extension<Users>,
+    Path(token): Path<String>,
+) -> impl IntoResponse {
+    match magic_link::consume(&tokens, &users, &session, &token).await {
+        Ok(Some(_)) => Redirect::to("/").into_response(),
+        Ok(None) => "invalid or expired link".into_response(),
+        Err(err) => format!("unable to log in: {}", err).into_response(),
+    }
+}
+
+async fn root(ProtectedUser(user): ProtectedUser<User>) -> impl IntoResponse {
+    format!("hello {}", user.email)
+}
+
+#[tokio::main]
+async fn main() {
+    let sessions = Store::<Session>::new();
+    let tokens = Tokens::new();
+    let alice = User {
+        uid: Uuid::new_v4(),
+        email: "alice@example.com".to_owned(),
+    };
+    let users = Users(Store::new(), vec![alice.clone()]);
+    users.save(&alice).await.unwrap();
+    // Shared by all the routes, to agree on the session cookie
+    let sessions = SessionManagerLayer::new(sessions, "uid");
+
+    // `curl -d email=alice@example.com localhost:42000/login`, follow the
+    // link printed, then `curl -b cookies localhost:42000/`
+    let app = Router::new()
+        .route("/login", post(request))
+        .route("/login/{token}", get(login).layer(sessions.clone()))
+        .route(
+            "/",
+            get(root).layer(UserManagerLayer::new(sessions, users.0.clone())),
+        )
+        .layer(Extension(tokens))
+        .layer(Extension(users));
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], 42000));
+    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    axum::serve(listener, app.into_make_service())
+        .await
+        .unwrap();
+}
@@ -0,0 +1,78 @@
+use axum::{response::IntoResponse, routing::get, Router};
+use std::net::SocketAddr;
+use uuid::Uuid;
+use webauth::auth::SESSION_USER_KEY;
+use webauth::axum::ProtectedUser;
+use webauth::session::{Session, SessionManagerLayer, DEFAULT_EXPIRATION};
+use webauth::store::{Detachable, Expirable, Identifiable, Store as _};
+use webauth::user::{Authorize, PermissionLayer, UserManagerLayer};
+use webauth_store_memory::Store;
+
+#[derive(Debug, Clone)]
+struct User {
+    uid: Uuid,
+    name: &'static str,
+    roles: Vec<&'static str>,
+}
+
+impl Identifiable for User {
+    type Uid = Uuid;
+
+    fn uid(&self) -> Self::Uid {
+        self.uid
+    }
+}
+
+impl Detachable for User {}
+
+impl Expirable for User {}
+
+impl Authorize for User {
+    fn has_permission(&self, permission: &str) -> bool {
+        self.roles.contains(&permission)
+    }
+}
+
+async fn root(ProtectedUser(user): ProtectedUser<User>) -> impl IntoResponse {
+    format!("hello {}", user.name)
+}
+
+async fn admin(ProtectedUser(user): ProtectedUser<User>) -> impl IntoResponse {
+    format!("hello {}, you are an admin", user.name)
+}
+
+#[tokio::main]
+async fn main() {
+    let sessions = Store::<Session>::new();
+    let users = Store::<User>::new();
+
+    // Log alice in, as a login route would
+    let alice = User {
+        uid: Uuid::new_v4(),
+        name: "alice",
+        roles: vec!["admin"],
+    };
+    users.save(&alice).await.unwrap();
+    let session = Session::new(DEFAULT_EXPIRATION);
+    session.insert(SESSION_USER_KEY, alice.uid).unwrap();
+    sessions.save(&session).await.unwrap();
+    println!("curl -b uid={} localhost:42000/admin", session.uid());
+
+    // The user manager wraps the permission layer, which needs the user
+    let app = Router::new()
+        .route("/", get(root))
+        .route(
+            "/admin",
+            get(admin).layer(PermissionLayer::<User>::new("admin")),
+        )
+        .layer(UserManagerLayer::new(
+            SessionManagerLayer::new(sessions, "uid"),
+            users,
+        ));
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], 42000));
+    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    axum::serve(listener, app.into_make_service())
+        .await
+        .unwrap();
+}
@@ -4,8 +4,11 @@ use webauth::session::{Session, SessionManagerLayer};
 use webauth_store_memory::Store;
 
 async fn root(session: Session) -> impl IntoResponse {
-    //session.insert("test", "value");
-    format!("hello world")
+    let visits = session.get_or_default::<u64>("visits").unwrap_or(0) + 1;
+    if let Err(err) = session.insert("visits", visits) {
+        return format!("unable to update session: {}", err);
+    }
+    format!("hello world, visit #{}", visits)
 }
 
 #[tokio::main]
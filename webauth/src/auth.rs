@@ -0,0 +1,253 @@
+use crate::session::{self, Session};
+use crate::store::{self, Identifiable, SessionStore};
+use serde::{de::DeserializeOwned, Serialize};
+use std::future::Future;
+use uuid::Uuid;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error<E> {
+    /// Error while reading or writing the `Session`
+    #[error(transparent)]
+    Session(#[from] session::Error),
+    /// Error from the `AuthBackend`
+    #[error("backend: {0}")]
+    Backend(E),
+}
+
+/// Authenticates users from their credentials (password, token, remote
+/// identity provider, ...).
+pub trait AuthBackend {
+    /// The authenticated user
+    type User: Identifiable;
+    /// What the user provides to authenticate
+    type Credentials;
+    /// Error of the backend (store, network, ...)
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Returns the user matching the credentials, `None` if they are invalid.
+    fn authenticate(
+        &self,
+        credentials: Self::Credentials,
+    ) -> impl Future<Output = Result<Option<Self::User>, Self::Error>> + Send;
+
+    /// Returns the user with the given uid, if any.
+    fn get_user(
+        &self,
+        uid: &<Self::User as Identifiable>::Uid,
+    ) -> impl Future<Output = Result<Option<Self::User>, Self::Error>> + Send;
+
+    /// Called on logout, for backends needing to clean up (revoke tokens,
+    /// end a remote session, ...). Does nothing by default.
+    fn logout(
+        &self,
+        _uid: &<Self::User as Identifiable>::Uid,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Key under which the authenticated user's uid is stored in the `Session`.
+/// This is the key read by the `UserManager`.
+pub const SESSION_USER_KEY: &str = "user_uid";
+
+/// Logs the given user in the `Session`.
+///
+/// The session uid is cycled first to prevent session fixation: the
+/// `SessionManager` deletes the session stored under the old uid and sends
+/// the new uid in the response cookie.
+pub fn login<U>(session: &Session, user: &U) -> Result<(), session::Error>
+where
+    U: Identifiable,
+    U::Uid: Serialize,
+{
+    session.cycle_uid();
+    session.insert(SESSION_USER_KEY, user.uid())
+}
+
+/// Logs the user out of the `Session`, letting the backend clean up first.
+///
+/// The session data is cleared and the session invalidated, so the
+/// `SessionManager` deletes it from the store and removes its cookie.
+pub async fn logout<B>(session: &mut Session, backend: &B) -> Result<(), Error<B::Error>>
+where
+    B: AuthBackend,
+    <B::User as Identifiable>::Uid: DeserializeOwned,
+{
+    if let Some(uid) = session.get::<<B::User as Identifiable>::Uid>(SESSION_USER_KEY)? {
+        backend.logout(&uid).await.map_err(Error::Backend)?;
+    }
+    session.clear();
+    session.invalidate();
+    Ok(())
+}
+
+/// Logs the user out of all its sessions, deleting them from the store
+/// (e.g. after a password reset).
+/// Returns the number of sessions deleted.
+pub async fn logout_everywhere<S>(store: &S, user_uid: &Uuid) -> Result<u64, store::Error>
+where
+    S: SessionStore,
+{
+    let deleted = store.delete_by_user(user_uid).await?;
+    tracing::debug!(user_uid = %user_uid, sessions = deleted, "logged out everywhere");
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{SessionManagerLayer, DEFAULT_EXPIRATION};
+    use crate::testing::{self, SpyStore};
+    use http::{Request, Response};
+    use std::convert::Infallible;
+    use tower_layer::Layer;
+    use uuid::Uuid;
+
+    #[derive(Debug, Clone, Copy)]
+    struct User(Uuid);
+
+    impl Identifiable for User {
+        type Uid = Uuid;
+
+        fn uid(&self) -> Self::Uid {
+            self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn login_cycles_session() {
+        let store = SpyStore::<Session>::default();
+        let session = Session::new(DEFAULT_EXPIRATION);
+        let old_uid = session.uid();
+        store.put(session);
+        let user = User(Uuid::new_v4());
+
+        let layer = SessionManagerLayer::new(store.clone(), "uid");
+        let service = layer.layer(tower::service_fn(move |req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().expect("session");
+            login(session, &user).expect("login");
+            Ok::<_, Infallible>(Response::new(session.uid().to_string()))
+        }));
+
+        let res = testing::call(service, testing::request(Some(format!("uid={}", old_uid)))).await;
+        let new_uid: Uuid = res.body().parse().expect("uid");
+
+        // The session has a new uid, the old one is gone from the store
+        assert_ne!(old_uid, new_uid);
+        assert!(store.get(&old_uid).is_none());
+        let stored = store.get(&new_uid).expect("stored session");
+        assert_eq!(Some(user.0), stored.get(SESSION_USER_KEY).expect("get"));
+
+        // And the response cookie carries the new uid
+        let cookies = testing::set_cookies(&res);
+        assert_eq!(1, cookies.len());
+        assert!(
+            cookies[0].starts_with(&format!("uid={};", new_uid)),
+            "{}",
+            cookies[0]
+        );
+    }
+
+    // Backend recording the users logged out
+    #[derive(Debug, Default)]
+    struct Backend {
+        logged_out: std::sync::Mutex<Vec<Uuid>>,
+    }
+
+    impl AuthBackend for Backend {
+        type User = User;
+        type Credentials = Uuid;
+        type Error = std::convert::Infallible;
+
+        async fn authenticate(&self, uid: Uuid) -> Result<Option<User>, Self::Error> {
+            Ok(Some(User(uid)))
+        }
+
+        async fn get_user(&self, uid: &Uuid) -> Result<Option<User>, Self::Error> {
+            Ok(Some(User(*uid)))
+        }
+
+        async fn logout(&self, uid: &Uuid) -> Result<(), Self::Error> {
+            self.logged_out.lock().expect("poisoned mutex").push(*uid);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn logout_calls_backend() -> Result<(), Error<Infallible>> {
+        let backend = Backend::default();
+        let user = backend
+            .authenticate(Uuid::new_v4())
+            .await
+            .map_err(Error::Backend)?
+            .expect("user");
+        let mut session = Session::new(DEFAULT_EXPIRATION);
+        login(&session, &user)?;
+        session.mark_saved();
+        let uid = session.uid();
+
+        logout(&mut session, &backend).await?;
+        assert_eq!(
+            vec![user.0],
+            *backend.logged_out.lock().expect("poisoned mutex")
+        );
+        assert_eq!(None, session.get::<Uuid>(SESSION_USER_KEY)?);
+        assert!(session.is_invalidated());
+        assert_eq!(uid, session.uid());
+
+        // Anonymous sessions do not reach the backend
+        logout(&mut session, &backend).await?;
+        assert_eq!(1, backend.logged_out.lock().expect("poisoned mutex").len());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn logout_deletes_session() {
+        let store = SpyStore::<Session>::default();
+        let session = Session::new(DEFAULT_EXPIRATION);
+        login(&session, &User(Uuid::new_v4())).expect("login");
+        let uid = session.uid();
+        store.put(session);
+
+        let layer = SessionManagerLayer::new(store.clone(), "uid");
+        let service = layer.layer(tower::service_fn(|req: Request<String>| async move {
+            let mut session = req.extensions().get::<Session>().expect("session").clone();
+            logout(&mut session, &Backend::default())
+                .await
+                .expect("logout");
+            Ok::<_, Infallible>(Response::new(String::new()))
+        }));
+        let res = testing::call(service, testing::request(Some(format!("uid={}", uid)))).await;
+
+        // Nothing left in the store, and the cookie is removed
+        assert!(store.objects.lock().expect("poisoned mutex").is_empty());
+        let cookies = testing::set_cookies(&res);
+        assert_eq!(1, cookies.len());
+        assert!(cookies[0].starts_with("uid=;"), "{}", cookies[0]);
+    }
+
+    #[tokio::test]
+    async fn logout_everywhere_deletes_sessions() -> Result<(), Error<Infallible>> {
+        let store = SpyStore::<Session>::default();
+        let (alice, bob) = (User(Uuid::new_v4()), User(Uuid::new_v4()));
+        let mut sessions = Vec::new();
+        for user in [Some(alice), Some(alice), Some(bob), None] {
+            let session = Session::new(DEFAULT_EXPIRATION);
+            if let Some(user) = user {
+                login(&session, &user)?;
+            }
+            sessions.push(session.uid());
+            store.put(session);
+        }
+
+        let deleted = logout_everywhere(&store, &alice.0).await.expect("logout");
+        assert_eq!(2, deleted);
+        assert!(store.get(&sessions[0]).is_none());
+        assert!(store.get(&sessions[1]).is_none());
+        assert!(store.get(&sessions[2]).is_some());
+        assert!(store.get(&sessions[3]).is_some());
+
+        Ok(())
+    }
+}
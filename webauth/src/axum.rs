@@ -1,48 +1,535 @@
-use crate::session::Session;
+use crate::auth::AuthBackend;
+use crate::session::{LazySession, Session};
 use crate::store::Identifiable;
-use axum_core::extract::FromRequestParts;
+pub use crate::user::LoginRedirect;
+use crate::user::Verifiable;
+use axum_core::{
+    extract::FromRequestParts,
+    response::{IntoResponse, Response},
+};
 use http::{request::Parts, StatusCode};
 
+/// Rejection of the extractors of this module.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// No `Session` in the request, the `SessionManagerLayer` is missing
+    #[error("No Session found, is the layer installed?")]
+    MissingSession,
+    /// No user in the request, the `UserManagerLayer` is missing
+    #[error("No Identifiable found, is the layer installed?")]
+    MissingUser,
+    /// No user in the request but a session, the visitor is not logged in
+    /// (see `UserManagerLayer::with_anonymous`), redirected to the page of
+    /// `UserManagerLayer::with_unauthenticated_redirect` if any
+    #[error("Not authenticated")]
+    Unauthenticated(Option<&'static str>),
+    /// No `AuthBackend` in the request, the layer was not given one
+    #[error("No AuthBackend found, is the layer given one?")]
+    MissingBackend,
+    /// The `Session` could not be loaded from the store (lazy loading)
+    #[error("Unable to load the Session")]
+    SessionLoad,
+    /// The user has not verified its email, redirected to the page of the
+    /// `UnverifiedRedirect` if any
+    #[error("Email address not verified")]
+    Unverified(Option<&'static str>),
+    /// No `CsrfToken` in the request, the `CsrfLayer` is missing
+    #[error("No CsrfToken found, is the layer installed?")]
+    MissingCsrfToken,
+}
+
+impl Rejection {
+    /// Returns the status code of the response
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::MissingSession
+            | Self::MissingUser
+            | Self::MissingBackend
+            | Self::SessionLoad
+            | Self::MissingCsrfToken => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Unauthenticated(Some(_)) | Self::Unverified(Some(_)) => StatusCode::SEE_OTHER,
+            Self::Unauthenticated(None) => StatusCode::UNAUTHORIZED,
+            Self::Unverified(None) => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Unauthenticated(Some(location)) | Self::Unverified(Some(location)) => {
+                (self.status(), [(http::header::LOCATION, location)]).into_response()
+            }
+            _ => (self.status(), self.to_string()).into_response(),
+        }
+    }
+}
+
 // ----------------------------------------------------------------------------
 
-#[async_trait::async_trait]
 impl<S> FromRequestParts<S> for Session
 where
     S: Sync + Send,
 {
-    type Rejection = (http::StatusCode, &'static str);
+    type Rejection = Rejection;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        parts.extensions.get::<Session>().cloned().ok_or((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "No Session found, is the layer installed?",
-        ))
+        if let Some(session) = parts.extensions.get::<Session>() {
+            return Ok(session.clone());
+        }
+        // Lazy loading is enabled, load it now
+        let lazy = parts
+            .extensions
+            .get::<LazySession>()
+            .cloned()
+            .ok_or(Rejection::MissingSession)?;
+        lazy.get().await.map_err(|err| {
+            tracing::error!(err = %err, "failed to load session");
+            Rejection::SessionLoad
+        })
     }
 }
 
 // ----------------------------------------------------------------------------
 
+/// The user the `UserManager` resolved.
+///
+/// Rejects with `Rejection::MissingUser` (a 500) when the `UserManagerLayer`
+/// is missing, and with `Rejection::Unauthenticated` when it lets anonymous
+/// visitors through: they are redirected like the `UserManagerLayer` does
+/// without it (see `UserManagerLayer::with_unauthenticated_redirect`).
 #[derive(Debug, Clone, Copy, Default)]
 pub struct ProtectedUser<U>(pub U);
 
 // Implement FromRequestParts for any type that implements Identifiable
-#[async_trait::async_trait]
 impl<S, U> FromRequestParts<S> for ProtectedUser<U>
 where
     S: Sync + Send,
     U: Identifiable + Clone + Sync + Send + 'static,
 {
-    type Rejection = (http::StatusCode, &'static str);
+    type Rejection = Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(user) = parts.extensions.get::<U>() {
+            return Ok(ProtectedUser(user.clone()));
+        }
+        // Behind the layers, there is always a session
+        if parts.extensions.get::<Session>().is_none()
+            && parts.extensions.get::<LazySession>().is_none()
+        {
+            return Err(Rejection::MissingUser);
+        }
+        let redirect = parts
+            .extensions
+            .get::<LoginRedirect>()
+            .map(|redirect| redirect.0);
+        Err(Rejection::Unauthenticated(redirect))
+    }
+}
+
+/// The user if any, for routes open to anonymous visitors (see
+/// `UserManagerLayer::with_anonymous`). Never rejects.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MaybeUser<U>(pub Option<U>);
+
+impl<S, U> FromRequestParts<S> for MaybeUser<U>
+where
+    S: Sync + Send,
+    U: Identifiable + Clone + Sync + Send + 'static,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(MaybeUser(parts.extensions.get::<U>().cloned()))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// The `Session` along with its user if any, for routes open to anonymous
+/// visitors (see `UserManagerLayer::with_anonymous`).
+/// Only rejects like the `Session` extractor, when no session is found.
+#[derive(Debug, Clone)]
+pub struct AuthSession<U> {
+    session: Session,
+    user: Option<U>,
+}
+
+impl<U> AuthSession<U> {
+    /// Returns the session
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Returns the user, `None` if anonymous
+    pub fn user(&self) -> Option<&U> {
+        self.user.as_ref()
+    }
+
+    /// Returns if there is a user
+    pub fn is_authenticated(&self) -> bool {
+        self.user.is_some()
+    }
+}
+
+impl<S, U> FromRequestParts<S> for AuthSession<U>
+where
+    S: Sync + Send,
+    U: Identifiable + Clone + Sync + Send + 'static,
+{
+    type Rejection = Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let session = Session::from_request_parts(parts, state).await?;
+        let user = parts.extensions.get::<U>().cloned();
+        Ok(AuthSession { session, user })
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// The `AuthBackend` given to the layer with `with_backend`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Backend<B>(pub B);
+
+impl<S, B> FromRequestParts<S> for Backend<B>
+where
+    S: Sync + Send,
+    B: AuthBackend + Clone + Sync + Send + 'static,
+{
+    type Rejection = Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<B>()
+            .cloned()
+            .ok_or(Rejection::MissingBackend)
+            .map(Backend)
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// Where `VerifiedUser` redirects unverified users (e.g. a "please verify
+/// your email" page), to be added to the request extensions.
+/// Without it, unverified users get a 403.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnverifiedRedirect(pub &'static str);
+
+/// Like `ProtectedUser`, but also requires the user to have verified its
+/// email address.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VerifiedUser<U>(pub U);
+
+impl<S, U> FromRequestParts<S> for VerifiedUser<U>
+where
+    S: Sync + Send,
+    U: Verifiable + Clone + Sync + Send + 'static,
+{
+    type Rejection = Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ProtectedUser(user) = ProtectedUser::<U>::from_request_parts(parts, state).await?;
+        if !user.is_verified() {
+            let redirect = parts
+                .extensions
+                .get::<UnverifiedRedirect>()
+                .map(|redirect| redirect.0);
+            return Err(Rejection::Unverified(redirect));
+        }
+        Ok(VerifiedUser(user))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(feature = "csrf")]
+impl<S> FromRequestParts<S> for crate::csrf::CsrfToken
+where
+    S: Sync + Send,
+{
+    type Rejection = Rejection;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
         parts
             .extensions
-            .get::<U>()
+            .get::<Self>()
             .cloned()
-            .ok_or((
-                http::StatusCode::INTERNAL_SERVER_ERROR,
-                "No Identifiable found, is the layer installed?",
+            .ok_or(Rejection::MissingCsrfToken)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::DEFAULT_EXPIRATION;
+    use http::Request;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct User(u64);
+
+    impl Identifiable for User {
+        type Uid = u64;
+
+        fn uid(&self) -> Self::Uid {
+            self.0
+        }
+    }
+
+    fn parts() -> Parts {
+        Request::new(()).into_parts().0
+    }
+
+    #[tokio::test]
+    async fn session() {
+        let mut parts = parts();
+        assert_eq!(
+            Rejection::MissingSession,
+            Session::from_request_parts(&mut parts, &())
+                .await
+                .unwrap_err()
+        );
+
+        let session = Session::new(DEFAULT_EXPIRATION);
+        parts.extensions.insert(session.clone());
+        let extracted = Session::from_request_parts(&mut parts, &())
+            .await
+            .expect("session");
+        assert_eq!(session.uid(), extracted.uid());
+    }
+
+    #[tokio::test]
+    async fn protected_user() {
+        let mut parts = parts();
+        assert_eq!(
+            Rejection::MissingUser,
+            ProtectedUser::<User>::from_request_parts(&mut parts, &())
+                .await
+                .unwrap_err()
+        );
+
+        parts.extensions.insert(User(42));
+        let ProtectedUser(user) = ProtectedUser::<User>::from_request_parts(&mut parts, &())
+            .await
+            .expect("user");
+        assert_eq!(User(42), user);
+    }
+
+    #[derive(Debug, Clone)]
+    struct Users;
+
+    impl AuthBackend for Users {
+        type User = User;
+        type Credentials = u64;
+        type Error = std::convert::Infallible;
+
+        async fn authenticate(&self, uid: u64) -> Result<Option<User>, Self::Error> {
+            Ok(Some(User(uid)))
+        }
+
+        async fn get_user(&self, uid: &u64) -> Result<Option<User>, Self::Error> {
+            Ok(Some(User(*uid)))
+        }
+    }
+
+    #[tokio::test]
+    async fn backend() {
+        let mut parts = parts();
+        assert_eq!(
+            Rejection::MissingBackend,
+            Backend::<Users>::from_request_parts(&mut parts, &())
+                .await
+                .unwrap_err()
+        );
+
+        parts.extensions.insert(Users);
+        let Backend(users) = Backend::<Users>::from_request_parts(&mut parts, &())
+            .await
+            .expect("backend");
+        assert_eq!(Some(User(42)), users.authenticate(42).await.expect("user"));
+    }
+
+    #[tokio::test]
+    async fn maybe_user() {
+        let mut parts = parts();
+        let MaybeUser(user) = MaybeUser::<User>::from_request_parts(&mut parts, &())
+            .await
+            .expect("infallible");
+        assert_eq!(None, user);
+
+        parts.extensions.insert(User(42));
+        let MaybeUser(user) = MaybeUser::<User>::from_request_parts(&mut parts, &())
+            .await
+            .expect("infallible");
+        assert_eq!(Some(User(42)), user);
+    }
+
+    #[tokio::test]
+    async fn auth_session() {
+        let mut parts = parts();
+        assert_eq!(
+            Rejection::MissingSession,
+            AuthSession::<User>::from_request_parts(&mut parts, &())
+                .await
+                .unwrap_err()
+        );
+
+        let session = Session::new(DEFAULT_EXPIRATION);
+        parts.extensions.insert(session.clone());
+        let anonymous = AuthSession::<User>::from_request_parts(&mut parts, &())
+            .await
+            .expect("auth session");
+        assert_eq!(session.uid(), anonymous.session().uid());
+        assert!(!anonymous.is_authenticated());
+
+        parts.extensions.insert(User(42));
+        let authenticated = AuthSession::<User>::from_request_parts(&mut parts, &())
+            .await
+            .expect("auth session");
+        assert_eq!(Some(&User(42)), authenticated.user());
+    }
+
+    #[test]
+    fn rejection_response() {
+        let res = Rejection::MissingSession.into_response();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        assert_eq!(
+            "text/plain; charset=utf-8",
+            res.headers()[http::header::CONTENT_TYPE]
+        );
+        let res = Rejection::MissingUser.into_response();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+    }
+
+    #[tokio::test]
+    async fn unauthenticated() {
+        use crate::session::SessionManagerLayer;
+        use crate::testing::SpyStore;
+        use crate::user::UserManagerLayer;
+        use ::axum::{routing::get, Router};
+        use tower::ServiceExt;
+
+        async fn handler(ProtectedUser(_): ProtectedUser<User>) {}
+
+        let request = || Request::builder().uri("/").body(String::new()).unwrap();
+        let sessions = SessionManagerLayer::new(SpyStore::<Session>::default(), "uid");
+        let users = SpyStore::<User>::default();
+
+        // The layer is missing, a programming error
+        let res = Router::new()
+            .route("/", get(handler))
+            .oneshot(request())
+            .await
+            .expect("infallible");
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+
+        // Anonymous visitor
+        let router = Router::new()
+            .route("/", get(handler))
+            .layer(UserManagerLayer::new(sessions.clone(), users.clone()).with_anonymous());
+        let res = router.oneshot(request()).await.expect("infallible");
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+        // Sent to the login page
+        let layer = UserManagerLayer::new(sessions, users)
+            .with_anonymous()
+            .with_unauthenticated_redirect("/login");
+        let router = Router::new().route("/", get(handler)).layer(layer);
+        let res = router.oneshot(request()).await.expect("infallible");
+        assert_eq!(StatusCode::SEE_OTHER, res.status());
+        assert_eq!("/login", res.headers()[http::header::LOCATION]);
+    }
+
+    #[cfg(feature = "csrf")]
+    #[tokio::test]
+    async fn csrf_token() {
+        use crate::csrf::{CsrfLayer, CsrfToken};
+        use crate::session::SessionManagerLayer;
+        use crate::testing::SpyStore;
+        use ::axum::{body::Body, routing::get, Router};
+        use tower::ServiceExt;
+
+        async fn handler(CsrfToken(token): CsrfToken) -> String {
+            token
+        }
+
+        let request = || Request::builder().uri("/").body(Body::empty()).unwrap();
+
+        // The layer is missing, a programming error
+        let res = Router::new()
+            .route("/", get(handler))
+            .oneshot(request())
+            .await
+            .expect("infallible");
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+
+        let res = Router::new()
+            .route("/", get(handler))
+            .layer(CsrfLayer::new())
+            .layer(SessionManagerLayer::new(
+                SpyStore::<Session>::default(),
+                "uid",
             ))
-            .map(|user| ProtectedUser(user))
+            .oneshot(request())
+            .await
+            .expect("infallible");
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Member {
+        uid: u64,
+        verified: bool,
+    }
+
+    impl Identifiable for Member {
+        type Uid = u64;
+
+        fn uid(&self) -> Self::Uid {
+            self.uid
+        }
+    }
+
+    impl Verifiable for Member {
+        fn is_verified(&self) -> bool {
+            self.verified
+        }
+    }
+
+    #[tokio::test]
+    async fn verified_user() {
+        let verified = Member {
+            uid: 1,
+            verified: true,
+        };
+        let mut ok = parts();
+        ok.extensions.insert(verified.clone());
+        let VerifiedUser(user) = VerifiedUser::<Member>::from_request_parts(&mut ok, &())
+            .await
+            .expect("verified user");
+        assert_eq!(verified, user);
+
+        let mut unverified = parts();
+        unverified.extensions.insert(Member {
+            uid: 2,
+            verified: false,
+        });
+        let rejection = VerifiedUser::<Member>::from_request_parts(&mut unverified, &())
+            .await
+            .unwrap_err();
+        assert_eq!(Rejection::Unverified(None), rejection);
+        assert_eq!(StatusCode::FORBIDDEN, rejection.into_response().status());
+
+        // Redirected when configured
+        unverified
+            .extensions
+            .insert(UnverifiedRedirect("/verify-email"));
+        let res = VerifiedUser::<Member>::from_request_parts(&mut unverified, &())
+            .await
+            .unwrap_err()
+            .into_response();
+        assert_eq!(StatusCode::SEE_OTHER, res.status());
+        assert_eq!("/verify-email", res.headers()[http::header::LOCATION]);
     }
 }
@@ -0,0 +1,157 @@
+use crate::_session::lock;
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// A source of wall-clock time.
+/// Stores use it to decide whether a resource has expired, which allows
+/// swapping the system clock for a `MockClock` in tests.
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current time
+    fn now(&self) -> SystemTime;
+}
+
+/// The system wall clock.
+/// It can jump backward (NTP adjustments, manual changes), wrap it in a
+/// `MonotonicClock` if that matters.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// A `Clock` that never goes backward.
+/// If the inner clock steps back, the last observed time is returned until
+/// the inner clock catches up, so an expired resource cannot come back to
+/// life after a backward step.
+#[derive(Debug, Clone, Default)]
+pub struct MonotonicClock<C> {
+    inner: C,
+    last: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl<C> MonotonicClock<C>
+where
+    C: Clock,
+{
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            last: Default::default(),
+        }
+    }
+}
+
+impl<C> Clock for MonotonicClock<C>
+where
+    C: Clock,
+{
+    fn now(&self) -> SystemTime {
+        let now = self.inner.now();
+        let mut last = lock(&self.last);
+        let now = match *last {
+            Some(last) if last > now => {
+                tracing::warn!(
+                    step = ?saturating_duration_since(last, now),
+                    "clock went backward"
+                );
+                last
+            }
+            _ => now,
+        };
+        *last = Some(now);
+        now
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// A manually driven `Clock`, for tests.
+#[derive(Debug, Clone)]
+pub struct MockClock(Arc<Mutex<SystemTime>>);
+
+impl MockClock {
+    /// Creates a new `MockClock` starting at `now`.
+    pub fn new(now: SystemTime) -> Self {
+        Self(Arc::new(Mutex::new(now)))
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, by: Duration) {
+        let mut now = lock(&self.0);
+        *now += by;
+    }
+
+    /// Moves the clock backward.
+    pub fn rewind(&self, by: Duration) {
+        let mut now = lock(&self.0);
+        *now -= by;
+    }
+
+    /// Sets the clock to the given time.
+    pub fn set(&self, to: SystemTime) {
+        *lock(&self.0) = to;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new(SystemTime::now())
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *lock(&self.0)
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// Returns how much time there is between `earlier` and `later`, or zero
+/// if `later` is actually before `earlier` (clock stepped back).
+pub fn saturating_duration_since(later: SystemTime, earlier: SystemTime) -> Duration {
+    later.duration_since(earlier).unwrap_or(Duration::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn monotonic() {
+        let mock = MockClock::default();
+        let clock = MonotonicClock::new(mock.clone());
+
+        let start = clock.now();
+        mock.advance(Duration::from_secs(10));
+        assert_eq!(start + Duration::from_secs(10), clock.now());
+
+        // Step back, the clock must hold still
+        mock.rewind(Duration::from_secs(60));
+        assert_eq!(start + Duration::from_secs(10), clock.now());
+
+        // And move again once the inner clock catches up
+        mock.advance(Duration::from_secs(70));
+        assert_eq!(start + Duration::from_secs(20), clock.now());
+    }
+
+    #[test]
+    fn saturating() {
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(5);
+
+        assert_eq!(
+            Duration::from_secs(5),
+            saturating_duration_since(later, now)
+        );
+        assert_eq!(Duration::ZERO, saturating_duration_since(now, later));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::store::{self, Store, VersionedStore};
+use crate::token::{self, OneTimeToken, Purpose};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Error of `confirm`
+#[derive(thiserror::Error, Debug)]
+pub enum ConfirmError {
+    /// The token is malformed, unknown, expired, already used or was issued
+    /// for another purpose
+    #[error("invalid confirmation token")]
+    InvalidToken,
+    /// Error from the token store
+    #[error("store: {0}")]
+    Store(#[from] store::Error),
+}
+
+/// The user and the email address a confirmation token was issued for, the
+/// "user" of its `OneTimeToken`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Confirmation<Uid> {
+    pub user_uid: Uid,
+    pub email: String,
+}
+
+/// Issues a token confirming that `email` belongs to `user_uid`, valid for
+/// `ttl`.
+///
+/// Returns the token string to send to `email` (e.g. in a link).
+pub async fn issue_confirmation<S, Uid>(
+    store: &S,
+    user_uid: Uid,
+    email: &str,
+    ttl: Duration,
+) -> Result<(String, OneTimeToken<Confirmation<Uid>>), store::Error>
+where
+    S: Store<Object = OneTimeToken<Confirmation<Uid>>>,
+{
+    let confirmation = Confirmation {
+        user_uid,
+        email: email.to_owned(),
+    };
+    token::issue(store, confirmation, Purpose::EmailVerification, ttl).await
+}
+
+/// Consumes the confirmation token, returning its user and the email it was
+/// issued for.
+///
+/// Only this email is confirmed: check it is still the address of the user
+/// before flagging it verified, it may have changed since.
+pub async fn confirm<S, Uid>(store: &S, token: &str) -> Result<(Uid, String), ConfirmError>
+where
+    S: VersionedStore<Object = OneTimeToken<Confirmation<Uid>>>,
+{
+    let confirmation = token::consume(store, token, Purpose::EmailVerification)
+        .await?
+        .ok_or(ConfirmError::InvalidToken)?;
+    Ok((confirmation.user_uid, confirmation.email))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::SpyStore;
+    use std::time::SystemTime;
+
+    type Tokens = SpyStore<OneTimeToken<Confirmation<u64>>>;
+
+    const TTL: Duration = Duration::from_secs(24 * 3600);
+
+    #[tokio::test]
+    async fn confirm_once() {
+        let tokens = Tokens::default();
+        let (raw, _) = issue_confirmation(&tokens, 42, "alice@example.com", TTL)
+            .await
+            .expect("issue");
+
+        let (user, email) = confirm(&tokens, &raw).await.expect("confirm");
+        assert_eq!((42, "alice@example.com"), (user, email.as_str()));
+        let res = confirm(&tokens, &raw).await;
+        assert!(matches!(res, Err(ConfirmError::InvalidToken)));
+    }
+
+    #[tokio::test]
+    async fn expired() {
+        let tokens = Tokens::default();
+        let (raw, token) = issue_confirmation(&tokens, 42, "alice@example.com", TTL)
+            .await
+            .expect("issue");
+        tokens.put(OneTimeToken {
+            expires_at: SystemTime::now() - Duration::from_secs(1),
+            ..token
+        });
+
+        let res = confirm(&tokens, &raw).await;
+        assert!(matches!(res, Err(ConfirmError::InvalidToken)));
+    }
+
+    #[tokio::test]
+    async fn wrong_purpose() {
+        let tokens = Tokens::default();
+        let confirmation = Confirmation {
+            user_uid: 42,
+            email: "alice@example.com".to_owned(),
+        };
+        let (raw, _) = token::issue(&tokens, confirmation, Purpose::PasswordReset, TTL)
+            .await
+            .expect("issue");
+
+        let res = confirm(&tokens, &raw).await;
+        assert!(matches!(res, Err(ConfirmError::InvalidToken)));
+    }
+}
@@ -0,0 +1,362 @@
+use crate::secret::constant_time_eq;
+use crate::session::{LazySession, Session};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use http::{header, Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower_service::Service;
+
+/// Key of the CSRF token in the session
+pub const CSRF_SESSION_KEY: &str = "csrf_token";
+/// Header in which the CSRF token can be submitted
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Field of urlencoded forms in which the CSRF token can be submitted
+pub const CSRF_FIELD: &str = "csrf_token";
+/// Default size limit (in bytes) of the urlencoded forms buffered to find the
+/// token, see `CsrfLayer::with_max_form_size`
+pub const DEFAULT_MAX_FORM_SIZE: usize = 64 * 1024;
+
+// Length (in bytes) of the tokens
+const TOKEN_LENGTH: usize = 32;
+
+/// The CSRF token of the session, found in the request extensions, to be
+/// embedded in forms (in the `CSRF_FIELD` field) or sent by scripts (in the
+/// `CSRF_HEADER` header).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsrfToken(pub String);
+
+// ----------------------------------------------------------------------------
+
+/// Protects the session against cross-site requests.
+#[derive(Debug, Clone)]
+pub struct CsrfManager<Service> {
+    inner: Service,
+    max_form_size: usize,
+}
+
+impl<ReqBody, ResBody, S> Service<Request<ReqBody>> for CsrfManager<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: http_body::Body<Data = Bytes> + From<Bytes> + Send + 'static,
+    ReqBody::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    ResBody: Default + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = std::result::Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let max_form_size = self.max_form_size;
+
+        let session = req.extensions().get::<Session>().cloned();
+        let lazy = req.extensions().get::<LazySession>().cloned();
+
+        Box::pin(async move {
+            let session = match (session, lazy) {
+                (Some(session), _) => session,
+                (None, Some(lazy)) => match lazy.get().await {
+                    Ok(session) => session,
+                    Err(err) => {
+                        tracing::error!(err = %err, "failed to load session");
+                        return Ok(status(StatusCode::INTERNAL_SERVER_ERROR));
+                    }
+                },
+                (None, None) => {
+                    tracing::error!("no Session found, is the SessionManagerLayer installed?");
+                    return Ok(status(StatusCode::INTERNAL_SERVER_ERROR));
+                }
+            };
+            let token = match session.get::<String>(CSRF_SESSION_KEY) {
+                Ok(token) => token,
+                Err(err) => {
+                    tracing::error!(err = %err, "invalid CSRF token in session");
+                    None
+                }
+            };
+
+            let token = if is_safe(req.method()) {
+                match token {
+                    Some(token) => token,
+                    None => match generate(&session) {
+                        Ok(token) => token,
+                        Err(err) => {
+                            tracing::error!(err = %err, "unable to generate CSRF token");
+                            return Ok(status(StatusCode::INTERNAL_SERVER_ERROR));
+                        }
+                    },
+                }
+            } else {
+                let (submitted, body) = match submitted(req, max_form_size).await {
+                    Ok(submitted) => submitted,
+                    Err(err) if err.is::<LengthLimitError>() => {
+                        tracing::debug!(max = max_form_size, "form too large");
+                        return Ok(status(StatusCode::PAYLOAD_TOO_LARGE));
+                    }
+                    Err(err) => {
+                        tracing::debug!(err = %err, "unable to read request body");
+                        return Ok(status(StatusCode::BAD_REQUEST));
+                    }
+                };
+                req = body;
+                match (token, submitted) {
+                    (Some(token), Some(submitted))
+                        if constant_time_eq(token.as_bytes(), submitted.as_bytes()) =>
+                    {
+                        token
+                    }
+                    _ => {
+                        tracing::debug!(method = %req.method(), "invalid CSRF token");
+                        return Ok(status(StatusCode::FORBIDDEN));
+                    }
+                }
+            };
+
+            req.extensions_mut().insert(CsrfToken(token));
+            inner.call(req).await
+        })
+    }
+}
+
+// GET, HEAD, OPTIONS and TRACE do not change anything, by convention
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+// Stores a new token in the session
+fn generate(session: &Session) -> Result<String, Box<dyn std::error::Error>> {
+    let mut bytes = [0; TOKEN_LENGTH];
+    getrandom::getrandom(&mut bytes)?;
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    session.insert(CSRF_SESSION_KEY, &token)?;
+    Ok(token)
+}
+
+// Returns the token submitted in the header, or else in the urlencoded form
+// (of at most `max_size` bytes), along with the request whose body has been
+// read
+async fn submitted<B>(
+    req: Request<B>,
+    max_size: usize,
+) -> Result<(Option<String>, Request<B>), Box<dyn std::error::Error + Send + Sync>>
+where
+    B: http_body::Body<Data = Bytes> + From<Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    if let Some(token) = req.headers().get(CSRF_HEADER) {
+        let token = token.to_str().ok().map(str::to_owned);
+        return Ok((token, req));
+    }
+    let form = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    if !form {
+        return Ok((None, req));
+    }
+
+    let (parts, body) = req.into_parts();
+    let bytes = Limited::new(body, max_size).collect().await?.to_bytes();
+    let token = form_urlencoded::parse(&bytes)
+        .find(|(key, _)| key == CSRF_FIELD)
+        .map(|(_, value)| value.into_owned());
+    Ok((token, Request::from_parts(parts, B::from(bytes))))
+}
+
+fn status<B: Default>(status: StatusCode) -> Response<B> {
+    let mut res = Response::default();
+    *res.status_mut() = status;
+    res
+}
+
+// ----------------------------------------------------------------------------
+
+/// Rejects the unsafe requests (POST, PUT, DELETE, ...) without the CSRF
+/// token of the session, with a 403, and puts the `CsrfToken` in the request
+/// extensions.
+///
+/// The token is submitted in the `CSRF_HEADER` header or, for urlencoded
+/// forms, in the `CSRF_FIELD` field (the body is then buffered, forms over
+/// `DEFAULT_MAX_FORM_SIZE` are rejected with a 413). It is created on the
+/// first safe request (GET, HEAD, ...) of the session.
+///
+/// It reads the `Session`, so it must be wrapped by the `SessionManagerLayer`.
+#[derive(Debug, Clone)]
+pub struct CsrfLayer {
+    max_form_size: usize,
+}
+
+impl Default for CsrfLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CsrfLayer {
+    pub fn new() -> Self {
+        Self {
+            max_form_size: DEFAULT_MAX_FORM_SIZE,
+        }
+    }
+
+    /// Sets the size limit (in bytes) of the urlencoded forms buffered to
+    /// find the token, larger ones being rejected with a 413 before the
+    /// token is checked.
+    pub fn with_max_form_size(mut self, max_form_size: usize) -> Self {
+        self.max_form_size = max_form_size;
+        self
+    }
+}
+
+impl<S> tower_layer::Layer<S> for CsrfLayer {
+    type Service = CsrfManager<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CsrfManager {
+            inner,
+            max_form_size: self.max_form_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionManagerLayer;
+    use crate::testing::{self, SpyStore};
+    use ::axum::body::Body;
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+    use tower_layer::Layer;
+
+    // Returns the token of the request and its body
+    async fn handler(req: Request<Body>) -> Result<Response<String>, Infallible> {
+        let token = req.extensions().get::<CsrfToken>().cloned().expect("token");
+        let body = req.into_body().collect().await.expect("body").to_bytes();
+        let body = String::from_utf8(body.to_vec()).expect("utf-8");
+        Ok(Response::new(format!("{} {}", token.0, body)))
+    }
+
+    fn request(method: Method, cookie: &str) -> http::request::Builder {
+        Request::builder()
+            .method(method)
+            .uri("/")
+            .header(header::COOKIE, cookie)
+    }
+
+    #[tokio::test]
+    async fn csrf() {
+        let service = SessionManagerLayer::new(SpyStore::<Session>::default(), "uid")
+            .layer(CsrfLayer::new().layer(tower::service_fn(handler)));
+
+        // Created on the first safe request, and kept
+        let req = Request::get("/").body(Body::empty()).expect("request");
+        let res = service.clone().oneshot(req).await.expect("infallible");
+        assert_eq!(StatusCode::OK, res.status());
+        let cookie = testing::set_cookies(&res)[0]
+            .split(';')
+            .next()
+            .expect("cookie")
+            .to_owned();
+        let token = res.body().trim().to_owned();
+        assert_eq!(43, token.len());
+        let req = request(Method::HEAD, &cookie).body(Body::empty());
+        let res = service.clone().oneshot(req.expect("request")).await;
+        assert_eq!(format!("{} ", token), *res.expect("infallible").body());
+
+        // In a header
+        let req = request(Method::POST, &cookie)
+            .header(CSRF_HEADER, &token)
+            .body(Body::from("data"));
+        let res = service.clone().oneshot(req.expect("request")).await;
+        assert_eq!(format!("{} data", token), *res.expect("infallible").body());
+
+        // In a form, whose body is still readable
+        let form = format!("name=alice&{}={}", CSRF_FIELD, token);
+        let req = request(Method::PUT, &cookie)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(form.clone()));
+        let res = service.clone().oneshot(req.expect("request")).await;
+        assert_eq!(
+            format!("{} {}", token, form),
+            *res.expect("infallible").body()
+        );
+
+        // Missing or wrong
+        let forged = URL_SAFE_NO_PAD.encode([0; TOKEN_LENGTH]);
+        for req in [
+            request(Method::POST, &cookie).body(Body::empty()),
+            request(Method::DELETE, &cookie)
+                .header(CSRF_HEADER, &forged)
+                .body(Body::empty()),
+            request(Method::PATCH, &cookie)
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(format!("{}={}", CSRF_FIELD, forged))),
+        ] {
+            let res = service.clone().oneshot(req.expect("request")).await;
+            assert_eq!(StatusCode::FORBIDDEN, res.expect("infallible").status());
+        }
+
+        // Another session does not have a token yet
+        let req = Request::post("/")
+            .header(CSRF_HEADER, &token)
+            .body(Body::empty());
+        let res = service.clone().oneshot(req.expect("request")).await;
+        assert_eq!(StatusCode::FORBIDDEN, res.expect("infallible").status());
+    }
+
+    #[tokio::test]
+    async fn max_form_size() {
+        let service = SessionManagerLayer::new(SpyStore::<Session>::default(), "uid").layer(
+            CsrfLayer::new()
+                .with_max_form_size(64)
+                .layer(tower::service_fn(handler)),
+        );
+        let req = Request::get("/").body(Body::empty()).expect("request");
+        let res = service.clone().oneshot(req).await.expect("infallible");
+        let cookie = testing::set_cookies(&res)[0]
+            .split(';')
+            .next()
+            .expect("cookie")
+            .to_owned();
+        let token = res.body().trim().to_owned();
+
+        let form = |padding: usize| {
+            let form = format!("{}={}&name={}", CSRF_FIELD, token, "a".repeat(padding));
+            request(Method::POST, &cookie)
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(form))
+                .expect("request")
+        };
+        let res = service.clone().oneshot(form(0)).await.expect("infallible");
+        assert_eq!(StatusCode::OK, res.status());
+        // Rejected even with a valid token, not to buffer it
+        let res = service.oneshot(form(64)).await.expect("infallible");
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, res.status());
+    }
+
+    #[tokio::test]
+    async fn missing_session() {
+        let service = CsrfLayer::new().layer(tower::service_fn(handler));
+        let req = Request::get("/").body(Body::empty()).expect("request");
+        let res = service.oneshot(req).await.expect("infallible");
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+    }
+}
@@ -0,0 +1,137 @@
+use http::{HeaderMap, HeaderName, Request};
+use std::net::{IpAddr, SocketAddr};
+
+/// Finds the address of the client of a request.
+///
+/// No layer of this crate uses it: it is for the application features
+/// needing the client address (rate limiting, audit logs, ...), to share one
+/// configured extractor so they all agree on the address.
+///
+/// By default only the socket peer address is used, which is the address of
+/// the last proxy when running behind one. Proxy headers are trusted only
+/// when configured with `IpExtractor::forwarded`: anybody can send a
+/// `X-Forwarded-For` header, so only the entries appended by our own proxies
+/// are meaningful.
+#[derive(Debug, Clone, Default)]
+pub struct IpExtractor {
+    forwarded: Option<(HeaderName, usize)>,
+}
+
+impl IpExtractor {
+    /// Uses the socket peer address only (the default).
+    pub fn peer() -> Self {
+        Self::default()
+    }
+
+    /// Reads the client address from the given proxy header (e.g.
+    /// `X-Forwarded-For`), behind `trusted_hops` proxies we control.
+    ///
+    /// Each proxy appends the address it received the request from, so the
+    /// client address is the `trusted_hops`-th entry from the right, the
+    /// entries on its left are whatever the client sent. When the header has
+    /// fewer entries than trusted hops, the peer address is used.
+    pub fn forwarded(header: HeaderName, trusted_hops: usize) -> Self {
+        Self {
+            forwarded: Some((header, trusted_hops)),
+        }
+    }
+
+    /// Returns the client address of the request.
+    /// The peer address is read from the `SocketAddr` in the request
+    /// extensions. With axum, it is wrapped in `ConnectInfo`: pass it to
+    /// `client_ip` instead.
+    pub fn extract<B>(&self, req: &Request<B>) -> Option<IpAddr> {
+        let peer = req.extensions().get::<SocketAddr>().map(SocketAddr::ip);
+        self.client_ip(req.headers(), peer)
+    }
+
+    /// Returns the client address from the request headers and the socket
+    /// peer address.
+    pub fn client_ip(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+        let Some((header, trusted_hops)) = &self.forwarded else {
+            return peer;
+        };
+        if *trusted_hops == 0 {
+            return peer;
+        }
+
+        // The header can be sent several times, entries are in order
+        let entries = headers
+            .get_all(header)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        let Some(index) = entries.len().checked_sub(*trusted_hops) else {
+            return peer;
+        };
+        match entries[index].parse() {
+            Ok(ip) => Some(ip),
+            Err(_) => {
+                tracing::warn!(entry = entries[index], "invalid forwarded address");
+                peer
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORWARDED_FOR: &str = "x-forwarded-for";
+
+    fn headers(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(FORWARDED_FOR, value.parse().expect("header value"));
+        }
+        headers
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().expect("ip"))
+    }
+
+    #[test]
+    fn peer() {
+        let headers = headers(&["1.1.1.1"]);
+        let peer = ip("10.0.0.1");
+
+        // Headers are ignored by default
+        assert_eq!(peer, IpExtractor::default().client_ip(&headers, peer));
+        assert_eq!(None, IpExtractor::peer().client_ip(&headers, None));
+
+        let mut req = Request::new(());
+        *req.headers_mut() = headers;
+        req.extensions_mut()
+            .insert(SocketAddr::new(peer.expect("peer"), 443));
+        assert_eq!(peer, IpExtractor::peer().extract(&req));
+    }
+
+    #[test]
+    fn trusted_hops() {
+        // The client spoofed an entry, then went through two proxies
+        let headers = headers(&["6.6.6.6, 1.1.1.1", "10.0.0.2"]);
+        let peer = ip("10.0.0.1");
+        let extractor = |hops| IpExtractor::forwarded(HeaderName::from_static(FORWARDED_FOR), hops);
+
+        assert_eq!(ip("1.1.1.1"), extractor(2).client_ip(&headers, peer));
+        assert_eq!(ip("10.0.0.2"), extractor(1).client_ip(&headers, peer));
+        assert_eq!(peer, extractor(0).client_ip(&headers, peer));
+        // Trusting too many hops picks up the spoofed entry...
+        assert_eq!(ip("6.6.6.6"), extractor(3).client_ip(&headers, peer));
+        // ...until there are not enough entries
+        assert_eq!(peer, extractor(4).client_ip(&headers, peer));
+
+        // Missing or invalid header
+        assert_eq!(peer, extractor(1).client_ip(&HeaderMap::new(), peer));
+        let invalid = self::headers(&["1.1.1.1, unknown"]);
+        assert_eq!(peer, extractor(1).client_ip(&invalid, peer));
+        assert_eq!(
+            ip("::1"),
+            extractor(1).client_ip(&self::headers(&[" ::1 "]), peer)
+        );
+    }
+}
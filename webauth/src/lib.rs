@@ -1,22 +1,130 @@
 #[cfg(feature = "axum-core")]
 pub mod axum;
 
+#[path = "./auth.rs"]
+mod _auth;
+pub mod auth {
+    pub use super::_auth::{
+        login, logout, logout_everywhere, AuthBackend, Error, SESSION_USER_KEY,
+    };
+}
+
+#[path = "./clock.rs"]
+mod _clock;
+pub mod clock {
+    pub use super::_clock::{
+        saturating_duration_since, Clock, MockClock, MonotonicClock, SystemClock,
+    };
+}
+
+#[cfg(feature = "token")]
+#[path = "./confirmation.rs"]
+mod _confirmation;
+#[cfg(feature = "token")]
+pub mod confirmation {
+    pub use super::_confirmation::{confirm, issue_confirmation, ConfirmError, Confirmation};
+}
+
+#[cfg(feature = "csrf")]
+#[path = "./csrf.rs"]
+mod _csrf;
+#[cfg(feature = "csrf")]
+pub mod csrf {
+    pub use super::_csrf::{
+        CsrfLayer, CsrfManager, CsrfToken, CSRF_FIELD, CSRF_HEADER, CSRF_SESSION_KEY,
+        DEFAULT_MAX_FORM_SIZE,
+    };
+}
+
+#[path = "./ip.rs"]
+mod _ip;
+pub mod ip {
+    pub use super::_ip::IpExtractor;
+}
+
 #[path = "./store.rs"]
 mod _store;
+#[path = "./write_behind.rs"]
+mod _write_behind;
 pub mod store {
-    pub use super::_store::{Error, Identifiable, Store};
+    pub use super::_store::{
+        ClearableStore, Detachable, EnumerableStore, Error, Expirable, Identifiable, SessionStore,
+        Store, Versioned, VersionedStore,
+    };
+    pub use super::_write_behind::WriteBehindStore;
 }
 
+#[path = "./permission.rs"]
+mod _permission;
 #[path = "./user.rs"]
 mod _user;
 pub mod user {
-    pub use super::_user::{UserManager, UserManagerLayer};
+    pub use super::_permission::{Authorize, PermissionLayer, PermissionManager};
+    pub use super::_user::{
+        BearerResolver, LoginRedirect, UserManager, UserManagerLayer, Verifiable,
+    };
 }
 
+#[cfg(feature = "token")]
+#[path = "./token.rs"]
+mod _token;
+#[cfg(feature = "token")]
+pub mod token {
+    pub use super::_token::{consume, issue, OneTimeToken, Purpose};
+}
+
+#[cfg(feature = "magic-link")]
+#[path = "./magic_link.rs"]
+mod _magic_link;
+#[cfg(feature = "magic-link")]
+pub mod magic_link {
+    pub use super::_magic_link::{consume, issue, EmailUserStore, Error};
+}
+
+#[cfg(feature = "totp")]
+#[path = "./mfa/mod.rs"]
+mod _mfa;
+#[cfg(feature = "totp")]
+pub mod mfa {
+    pub mod totp {
+        pub use crate::_mfa::totp::{Error, TotpConfig, TotpSecret, SECRET_LENGTH};
+    }
+}
+
+#[cfg(feature = "remember-me")]
+#[path = "./remember.rs"]
+mod _remember;
+#[cfg(feature = "remember-me")]
+pub mod remember {
+    pub use super::_remember::{
+        RememberMe, RememberMeLayer, RememberMeManager, RememberMeToken, DEFAULT_COOKIE_NAME,
+    };
+}
+
+#[cfg(any(
+    feature = "csrf",
+    feature = "remember-me",
+    feature = "token",
+    feature = "totp"
+))]
+mod secret;
+
 #[path = "./session.rs"]
 mod _session;
+#[path = "./summary.rs"]
+mod _summary;
 pub mod session {
-    pub use super::_session::{Session, SessionManager, SessionManagerLayer, DEFAULT_EXPIRATION};
+    pub use super::_session::{
+        CookieConfig, CookieLifetime, Error, ErrorResponse, LazySession, SaveFailurePolicy,
+        Session, SessionData, SessionFailure, SessionIdGenerator, SessionIdStrategy, SessionKey,
+        SessionManager, SessionManagerLayer, UidValidator, DEFAULT_EXPIRATION, FLASH_PREFIX,
+        SECURE_EXPIRATION,
+    };
+    pub use super::_summary::SessionSummary;
+    // Re-exports the cookie signing key we use
+    pub use tower_cookies::Key;
+    // Re-exports the cookie attribute we let configure
+    pub use tower_cookies::cookie::SameSite;
     // Re-exports the Uuid we use
     pub use uuid::Uuid;
 }
@@ -26,5 +134,23 @@ pub mod session {
 mod _password;
 #[cfg(feature = "password")]
 pub mod password {
-    pub use super::_password::{hash, verify};
+    pub use super::_password::{
+        flag_outdated_hashes, hash, seed_user, verify, verify_dummy, verify_str, BackendError,
+        CipherError, CipheredPassword, EmailPasswordBackend, EmailPasswordCredentials, Hasher,
+        PasswordPolicy, PasswordUser, PasswordUserStore, PlainPassword, PolicyViolation,
+        RehashReport, RehashUser, SeedUser, VerifyOutcome, MAX_EMAIL_LENGTH, MAX_PASSWORD_LENGTH,
+    };
+    #[cfg(feature = "tokio")]
+    pub use super::_password::{hash_async, verify_async};
+    // Re-exports the hashing parameters we let configure
+    pub use argon2::{Algorithm, Params, Version};
+    #[cfg(feature = "token")]
+    pub mod reset {
+        pub use crate::_password::reset::{
+            complete_reset, consume_reset, request_reset, ResetError,
+        };
+    }
 }
+
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,198 @@
+use crate::auth;
+use crate::session::{self, Session};
+use crate::store::{self, Identifiable, Store, VersionedStore};
+use crate::token::{self, OneTimeToken, Purpose};
+use serde::Serialize;
+use std::{future::Future, time::Duration};
+
+/// Error of the magic link helpers
+#[derive(thiserror::Error, Debug)]
+pub enum Error<E> {
+    /// Error from the token or user store
+    #[error("store: {0}")]
+    Store(#[from] store::Error),
+    /// Error while logging the user in the `Session`
+    #[error(transparent)]
+    Session(#[from] session::Error),
+    /// Error while sending the link
+    #[error("send: {0}")]
+    Send(E),
+}
+
+/// A `Store` of users able to find them by email, to send them magic links.
+pub trait EmailUserStore: Store {
+    /// Load the user with the given email, if any.
+    fn load_by_email(
+        &self,
+        email: &str,
+    ) -> impl Future<Output = Result<Option<Self::Object>, store::Error>> + Send;
+}
+
+/// Issues a magic link token for the user with the given email, valid for
+/// `ttl`, and calls `send` with it to email the link.
+///
+/// The token is bound to the email, not to the user. Unknown emails get no
+/// link but `Ok(())` too, so callers cannot tell whether the account exists
+/// (the response time still differs, send the link in the background to
+/// hide it).
+pub async fn issue<T, U, F, Fut, E>(
+    tokens: &T,
+    users: &U,
+    email: &str,
+    ttl: Duration,
+    send: F,
+) -> Result<(), Error<E>>
+where
+    T: Store<Object = OneTimeToken<String>>,
+    U: EmailUserStore,
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    if users.load_by_email(email).await?.is_none() {
+        tracing::debug!("magic link requested for an unknown email");
+        return Ok(());
+    }
+    let (token, _) = token::issue(tokens, email.to_owned(), Purpose::MagicLink, ttl).await?;
+    send(token).await.map_err(Error::Send)
+}
+
+/// Consumes the magic link token and logs its user in the `Session`, whose
+/// uid is cycled to prevent session fixation (see `auth::login`).
+///
+/// Returns the user, `None` if the token is invalid (see `token::consume`)
+/// or its email no longer belongs to a user.
+pub async fn consume<T, U>(
+    tokens: &T,
+    users: &U,
+    session: &Session,
+    token: &str,
+) -> Result<Option<U::Object>, Error<std::convert::Infallible>>
+where
+    T: VersionedStore<Object = OneTimeToken<String>>,
+    U: EmailUserStore,
+    <U::Object as Identifiable>::Uid: Serialize,
+{
+    let Some(email) = token::consume(tokens, token, Purpose::MagicLink).await? else {
+        return Ok(None);
+    };
+    let Some(user) = users.load_by_email(&email).await? else {
+        tracing::debug!("magic link of a deleted user");
+        return Ok(None);
+    };
+    auth::login(session, &user)?;
+    Ok(Some(user))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::SESSION_USER_KEY;
+    use crate::session::DEFAULT_EXPIRATION;
+    use crate::testing::SpyStore;
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct User {
+        uid: u64,
+        email: &'static str,
+    }
+
+    impl Identifiable for User {
+        type Uid = u64;
+
+        fn uid(&self) -> Self::Uid {
+            self.uid
+        }
+    }
+
+    impl EmailUserStore for SpyStore<User> {
+        async fn load_by_email(&self, email: &str) -> Result<Option<User>, store::Error> {
+            let users = self.objects.lock().expect("poisoned mutex");
+            Ok(users.values().find(|user| user.email == email).cloned())
+        }
+    }
+
+    const TTL: Duration = Duration::from_secs(600);
+
+    // Issues a link, returning the token sent if any
+    async fn issue_link(
+        tokens: &SpyStore<OneTimeToken<String>>,
+        users: &SpyStore<User>,
+        email: &str,
+    ) -> Option<String> {
+        let sent = Mutex::new(None);
+        issue(tokens, users, email, TTL, |token| {
+            *sent.lock().expect("poisoned mutex") = Some(token);
+            async { Ok::<_, Infallible>(()) }
+        })
+        .await
+        .expect("issue");
+        sent.into_inner().expect("poisoned mutex")
+    }
+
+    #[tokio::test]
+    async fn login() {
+        let tokens = SpyStore::default();
+        let users = SpyStore::default();
+        let alice = User {
+            uid: 1,
+            email: "alice@example.com",
+        };
+        users.put(alice.clone());
+
+        let token = issue_link(&tokens, &users, alice.email)
+            .await
+            .expect("sent");
+        let session = Session::new(DEFAULT_EXPIRATION);
+        let uid = session.uid();
+        let user = consume(&tokens, &users, &session, &token).await;
+        assert_eq!(Some(alice), user.expect("consume"));
+        assert_eq!(Some(1), session.get::<u64>(SESSION_USER_KEY).expect("get"));
+        assert_eq!(Some(uid), session.cycled_from());
+
+        // Single use
+        let session = Session::new(DEFAULT_EXPIRATION);
+        let user = consume(&tokens, &users, &session, &token).await;
+        assert_eq!(None, user.expect("consume"));
+        assert_eq!(None, session.get::<u64>(SESSION_USER_KEY).expect("get"));
+    }
+
+    #[tokio::test]
+    async fn unknown_email() {
+        let tokens = SpyStore::default();
+        let users = SpyStore::<User>::default();
+
+        assert_eq!(None, issue_link(&tokens, &users, "bob@example.com").await);
+        assert_eq!(0, tokens.saves());
+    }
+
+    #[tokio::test]
+    async fn expired() {
+        let tokens = SpyStore::default();
+        let users = SpyStore::default();
+        users.put(User {
+            uid: 1,
+            email: "alice@example.com",
+        });
+        let token = issue_link(&tokens, &users, "alice@example.com")
+            .await
+            .expect("sent");
+        let mut stored: Vec<OneTimeToken<String>> = tokens
+            .objects
+            .lock()
+            .expect("poisoned mutex")
+            .values()
+            .cloned()
+            .collect();
+        let stored = stored.pop().expect("stored");
+        tokens.put(OneTimeToken {
+            expires_at: std::time::SystemTime::now() - Duration::from_secs(1),
+            ..stored
+        });
+
+        let session = Session::new(DEFAULT_EXPIRATION);
+        let user = consume(&tokens, &users, &session, &token).await;
+        assert_eq!(None, user.expect("consume"));
+    }
+}
@@ -0,0 +1,334 @@
+use crate::secret::constant_time_eq;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::time::{Duration, SystemTime};
+
+/// Length (in bytes) of the secrets `TotpSecret::generate` creates, the
+/// output size of SHA-1 (RFC 4226 recommends at least 160 bits).
+pub const SECRET_LENGTH: usize = 20;
+
+// RFC 4648 base32 alphabet
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The secret is not valid base32, or is empty
+    #[error("invalid base32 secret")]
+    InvalidSecret,
+    /// No randomness available to generate a secret
+    #[error("unable to generate a secret")]
+    Random,
+}
+
+/// Parameters of the codes, the defaults are the ones authenticator apps
+/// expect (6 digits every 30 seconds, HMAC-SHA1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TotpConfig {
+    /// Number of digits of the codes
+    pub digits: u32,
+    /// How long a code is valid
+    pub period: Duration,
+    /// Number of periods before and after the current one whose codes are
+    /// accepted too, to tolerate clock drift and slow typing
+    pub skew: u64,
+}
+
+impl Default for TotpConfig {
+    fn default() -> Self {
+        Self {
+            digits: 6,
+            period: Duration::from_secs(30),
+            skew: 1,
+        }
+    }
+}
+
+impl TotpConfig {
+    // Index of the period `at` falls in, counted from the Unix epoch
+    fn step(&self, at: SystemTime) -> u64 {
+        let since_epoch = at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+        since_epoch.as_secs() / self.period.as_secs().max(1)
+    }
+}
+
+/// The secret shared with the authenticator app of a user, generating time
+/// based one-time passwords (RFC 6238).
+///
+/// Store it (with `to_base32`) along with the user, and with the last step
+/// `verify_unused` accepted, so codes cannot be replayed.
+#[derive(Clone, PartialEq, Eq)]
+pub struct TotpSecret {
+    secret: Vec<u8>,
+    config: TotpConfig,
+}
+
+impl std::fmt::Debug for TotpSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TotpSecret")
+            .field("secret", &"[redacted]")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl TotpSecret {
+    /// Generates a random secret of `SECRET_LENGTH` bytes.
+    pub fn generate() -> Result<Self, Error> {
+        let mut secret = vec![0; SECRET_LENGTH];
+        getrandom::getrandom(&mut secret).map_err(|_| Error::Random)?;
+        Ok(Self::from_bytes(secret))
+    }
+
+    /// Uses the given raw secret
+    pub fn from_bytes(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+            config: TotpConfig::default(),
+        }
+    }
+
+    /// Decodes a base32 secret, as returned by `to_base32`.
+    /// Case, padding and whitespaces are ignored.
+    pub fn from_base32(secret: &str) -> Result<Self, Error> {
+        let mut bytes = Vec::with_capacity(secret.len() * 5 / 8);
+        let (mut buffer, mut bits) = (0u32, 0);
+        for c in secret.bytes() {
+            if c == b'=' || c.is_ascii_whitespace() {
+                continue;
+            }
+            let value = BASE32
+                .iter()
+                .position(|&b| b == c.to_ascii_uppercase())
+                .ok_or(Error::InvalidSecret)?;
+            buffer = (buffer << 5) | value as u32;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((buffer >> bits) as u8);
+            }
+        }
+        if bytes.is_empty() {
+            return Err(Error::InvalidSecret);
+        }
+        Ok(Self::from_bytes(bytes))
+    }
+
+    /// Uses the given config instead of the default one
+    pub fn with_config(mut self, config: TotpConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Returns the config of the codes
+    pub fn config(&self) -> &TotpConfig {
+        &self.config
+    }
+
+    /// Encodes the secret in base32 (without padding), the format
+    /// authenticator apps expect.
+    pub fn to_base32(&self) -> String {
+        let mut encoded = String::with_capacity(self.secret.len().div_ceil(5) * 8);
+        let (mut buffer, mut bits) = (0u32, 0);
+        for &byte in &self.secret {
+            buffer = (buffer << 8) | u32::from(byte);
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                encoded.push(BASE32[((buffer >> bits) & 0x1f) as usize] as char);
+            }
+        }
+        if bits > 0 {
+            encoded.push(BASE32[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+        }
+        encoded
+    }
+
+    /// Returns the `otpauth://` URI to show as a QR code, for the user to
+    /// add the secret to its authenticator app.
+    /// `issuer` is the name of the application, `account` identifies the
+    /// user (e.g. its email).
+    pub fn provisioning_uri(&self, issuer: &str, account: &str) -> String {
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            encode(issuer),
+            encode(account),
+            self.to_base32(),
+            encode(issuer),
+            self.config.digits,
+            self.config.period.as_secs().max(1),
+        )
+    }
+
+    /// Returns the code valid at the given time
+    pub fn code_at(&self, at: SystemTime) -> String {
+        self.code(self.config.step(at))
+    }
+
+    /// Verifies the code at the given time, accepting the codes of `skew`
+    /// periods around it.
+    /// A code can be used several times while valid, prefer `verify_unused`.
+    pub fn verify(&self, code: &str, at: SystemTime) -> bool {
+        self.verify_unused(code, at, None).is_some()
+    }
+
+    /// Verifies the code at the given time like `verify`, also rejecting
+    /// codes of steps up to `last_used` (the step returned by the previous
+    /// successful call), so a code cannot be replayed.
+    /// Returns the step of the code, to be stored as the new `last_used`.
+    pub fn verify_unused(&self, code: &str, at: SystemTime, last_used: Option<u64>) -> Option<u64> {
+        let step = self.config.step(at);
+        let first = step
+            .saturating_sub(self.config.skew)
+            .max(last_used.map_or(0, |last| last.saturating_add(1)));
+        (first..=step.saturating_add(self.config.skew))
+            .find(|&step| constant_time_eq(self.code(step).as_bytes(), code.trim().as_bytes()))
+    }
+
+    // HOTP (RFC 4226) of the step
+    fn code(&self, step: u64) -> String {
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(&step.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        // Dynamic truncation
+        let offset = usize::from(hash[hash.len() - 1] & 0xf);
+        let binary = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+        let digits = self.config.digits as usize;
+        let code = u64::from(binary) % 10u64.saturating_pow(self.config.digits);
+        format!("{:0digits$}", code, digits = digits)
+    }
+}
+
+// Percent-encodes everything but the unreserved characters (RFC 3986)
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn rfc6238() {
+        // SHA-1 test vectors of RFC 6238, appendix B
+        let secret = TotpSecret::from_bytes(*b"12345678901234567890").with_config(TotpConfig {
+            digits: 8,
+            ..Default::default()
+        });
+        for (secs, code) in [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ] {
+            assert_eq!(code, secret.code_at(at(secs)), "{}", secs);
+            assert!(secret.verify(code, at(secs)));
+        }
+    }
+
+    #[test]
+    fn skew() {
+        let secret = TotpSecret::from_bytes(*b"12345678901234567890");
+        let code = secret.code_at(at(1000 * 30));
+
+        assert!(secret.verify(&code, at(1000 * 30 + 29)));
+        assert!(secret.verify(&code, at(999 * 30)));
+        assert!(secret.verify(&code, at(1001 * 30)));
+        assert!(!secret.verify(&code, at(998 * 30 + 29)));
+        assert!(!secret.verify(&code, at(1002 * 30)));
+        assert!(!secret.verify("000000", at(1000 * 30)));
+        assert!(!secret.verify("", at(1000 * 30)));
+
+        let strict = secret.with_config(TotpConfig {
+            skew: 0,
+            ..Default::default()
+        });
+        assert!(strict.verify(&code, at(1000 * 30)));
+        assert!(!strict.verify(&code, at(1001 * 30)));
+    }
+
+    #[test]
+    fn replay() {
+        let secret = TotpSecret::generate().expect("secret");
+        let now = at(1000 * 30);
+        let code = secret.code_at(now);
+
+        let last_used = secret.verify_unused(&code, now, None);
+        assert_eq!(Some(1000), last_used);
+        // Same code, still in its window
+        assert_eq!(None, secret.verify_unused(&code, now, last_used));
+        assert_eq!(None, secret.verify_unused(&code, at(1001 * 30), last_used));
+        // Codes of earlier steps cannot be used either
+        let previous = secret.code_at(at(999 * 30));
+        assert_eq!(None, secret.verify_unused(&previous, now, last_used));
+        // But the next one can
+        let next = secret.code_at(at(1001 * 30));
+        assert_eq!(Some(1001), secret.verify_unused(&next, now, last_used));
+    }
+
+    #[test]
+    fn base32() {
+        // Test vectors of RFC 4648
+        for (bytes, encoded) in [
+            (&b"f"[..], "MY"),
+            (b"fo", "MZXQ"),
+            (b"foo", "MZXW6"),
+            (b"foob", "MZXW6YQ"),
+            (b"fooba", "MZXW6YTB"),
+            (b"foobar", "MZXW6YTBOI"),
+        ] {
+            let secret = TotpSecret::from_bytes(bytes);
+            assert_eq!(encoded, secret.to_base32());
+            assert_eq!(secret, TotpSecret::from_base32(encoded).expect("decode"));
+        }
+        assert_eq!(
+            TotpSecret::from_bytes(*b"foobar"),
+            TotpSecret::from_base32("mzxw 6ytb oi======").expect("decode")
+        );
+
+        for invalid in ["", "====", "MZXW1", "MZXW6!"] {
+            assert_eq!(
+                Some(Error::InvalidSecret),
+                TotpSecret::from_base32(invalid).err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn provisioning_uri() {
+        let secret = TotpSecret::from_bytes(*b"foobar");
+        assert_eq!(
+            "otpauth://totp/My%20App:me%40example.com?secret=MZXW6YTBOI&issuer=My%20App&algorithm=SHA1&digits=6&period=30",
+            secret.provisioning_uri("My App", "me@example.com")
+        );
+        assert_eq!(
+            "TotpSecret { secret: \"[redacted]\", config: TotpConfig { digits: 6, period: 30s, skew: 1 } }",
+            format!("{:?}", secret)
+        );
+    }
+}
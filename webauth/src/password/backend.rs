@@ -0,0 +1,422 @@
+use super::{CipheredPassword, EmailPasswordCredentials, Hasher};
+use crate::auth::AuthBackend;
+use crate::store::{self, EnumerableStore, Identifiable, Store};
+use std::future::Future;
+
+/// Error of the password backend helpers
+#[derive(thiserror::Error, Debug)]
+pub enum BackendError {
+    /// Error from the user store
+    #[error("store: {0}")]
+    Store(#[from] store::Error),
+    /// Error while hashing or verifying a password
+    #[error("password: {0}")]
+    Password(argon2::password_hash::Error),
+}
+
+impl From<argon2::password_hash::Error> for BackendError {
+    fn from(err: argon2::password_hash::Error) -> Self {
+        Self::Password(err)
+    }
+}
+
+/// A user authenticating with an email and a password.
+pub trait PasswordUser: Identifiable {
+    /// Returns the email of the user
+    fn email(&self) -> &str;
+    /// Returns the ciphered password of the user
+    fn password(&self) -> &CipheredPassword;
+}
+
+/// A `PasswordUser` which can be created from scratch, used to seed users.
+pub trait SeedUser: PasswordUser {
+    /// Creates a new user
+    fn seed(email: String, password: CipheredPassword, roles: Vec<String>) -> Self;
+}
+
+/// A `PasswordUser` which can be flagged to get its password rehashed.
+pub trait RehashUser: PasswordUser {
+    /// Flags the user so its password is rehashed on next login, when the
+    /// plain password is available.
+    fn mark_for_rehash(&mut self);
+}
+
+/// A `Store` of `PasswordUser`s, able to find them by email.
+pub trait PasswordUserStore: Store
+where
+    Self::Object: PasswordUser,
+{
+    /// Load the user with the given email, if any.
+    fn load_by_email(
+        &self,
+        email: &str,
+    ) -> impl Future<Output = Result<Option<Self::Object>, store::Error>> + Send;
+}
+
+/// An `AuthBackend` authenticating `PasswordUser`s with their email and
+/// password.
+///
+/// Unknown emails and wrong passwords both authenticate nobody (`Ok(None)`),
+/// so callers cannot tell which one it was. Errors are only returned when
+/// the store fails or a stored hash is invalid.
+#[derive(Debug, Clone)]
+pub struct EmailPasswordBackend<S> {
+    store: S,
+    hasher: Hasher,
+}
+
+impl<S> EmailPasswordBackend<S> {
+    /// Creates a backend finding users in `store`, verifying their
+    /// passwords with the default `Hasher`.
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            hasher: Hasher::default(),
+        }
+    }
+
+    /// Verifies passwords with the given `Hasher`, needed when it has a
+    /// pepper.
+    pub fn with_hasher(mut self, hasher: Hasher) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    /// Returns the user store
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+}
+
+impl<S> AuthBackend for EmailPasswordBackend<S>
+where
+    S: PasswordUserStore + Sync,
+    S::Object: PasswordUser + Send,
+    <S::Object as Identifiable>::Uid: Sync,
+{
+    type User = S::Object;
+    type Credentials = EmailPasswordCredentials;
+    type Error = BackendError;
+
+    async fn authenticate(
+        &self,
+        credentials: EmailPasswordCredentials,
+    ) -> Result<Option<S::Object>, BackendError> {
+        let Some(user) = self.store.load_by_email(&credentials.email).await? else {
+            tracing::debug!("unknown email");
+            // As long as a wrong password, not to tell which emails exist
+            self.hasher.verify_dummy()?;
+            return Ok(None);
+        };
+        let password = credentials.password.as_str().as_bytes();
+        if !user.password().verify_with(password, &self.hasher)? {
+            tracing::debug!("wrong password");
+            return Ok(None);
+        }
+        Ok(Some(user))
+    }
+
+    async fn get_user(
+        &self,
+        uid: &<S::Object as Identifiable>::Uid,
+    ) -> Result<Option<S::Object>, BackendError> {
+        Ok(self.store.load(uid).await?)
+    }
+}
+
+/// Creates a user with the given credentials and roles, unless a user with
+/// the same email already exists, so it can be called on every start of the
+/// application (e.g. to create the first admin).
+/// The password is ciphered with `hasher`, give the one of the
+/// `EmailPasswordBackend` so the user can log in.
+/// Returns if the user has been created.
+pub async fn seed_user<S>(
+    store: &S,
+    credentials: EmailPasswordCredentials,
+    roles: Vec<String>,
+    hasher: &Hasher,
+) -> Result<bool, BackendError>
+where
+    S: PasswordUserStore,
+    S::Object: SeedUser,
+{
+    if store.load_by_email(&credentials.email).await?.is_some() {
+        tracing::debug!(email = credentials.email, "user already seeded");
+        return Ok(false);
+    }
+
+    let password = credentials.password.cipher_with(hasher)?;
+    let user = S::Object::seed(credentials.email, password, roles);
+    store.save(&user).await?;
+    Ok(true)
+}
+
+/// Result of `flag_outdated_hashes`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RehashReport {
+    /// Number of users checked
+    pub users: usize,
+    /// Number of users whose password needs to be rehashed
+    pub outdated: usize,
+}
+
+/// Flags the users whose password hash is weaker than the one `hasher` makes
+/// (see `CipheredPassword::needs_rehash`) to get it rehashed on next login,
+/// e.g. after upgrading the hashing parameters.
+/// Passwords cannot be rehashed offline, the plain password is needed.
+pub async fn flag_outdated_hashes<S>(
+    store: &S,
+    hasher: &Hasher,
+) -> Result<RehashReport, BackendError>
+where
+    S: EnumerableStore,
+    S::Object: RehashUser,
+{
+    let mut report = RehashReport::default();
+    for mut user in store.load_all().await? {
+        report.users += 1;
+        if user.password().needs_rehash(hasher) {
+            user.mark_for_rehash();
+            store.save(&user).await?;
+            report.outdated += 1;
+        }
+    }
+    tracing::info!(
+        users = report.users,
+        outdated = report.outdated,
+        "flagged outdated password hashes"
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::password::PlainPassword;
+    use crate::testing::SpyStore;
+    use std::sync::atomic::Ordering;
+    use uuid::Uuid;
+
+    #[derive(Debug, Clone)]
+    pub(crate) struct User {
+        pub uid: Uuid,
+        pub email: String,
+        pub password: CipheredPassword,
+        pub roles: Vec<String>,
+        pub needs_rehash: bool,
+    }
+
+    impl Identifiable for User {
+        type Uid = Uuid;
+
+        fn uid(&self) -> Self::Uid {
+            self.uid
+        }
+    }
+
+    impl PasswordUser for User {
+        fn email(&self) -> &str {
+            &self.email
+        }
+
+        fn password(&self) -> &CipheredPassword {
+            &self.password
+        }
+    }
+
+    impl SeedUser for User {
+        fn seed(email: String, password: CipheredPassword, roles: Vec<String>) -> Self {
+            Self {
+                uid: Uuid::new_v4(),
+                email,
+                password,
+                roles,
+                needs_rehash: false,
+            }
+        }
+    }
+
+    impl RehashUser for User {
+        fn mark_for_rehash(&mut self) {
+            self.needs_rehash = true;
+        }
+    }
+
+    impl PasswordUserStore for SpyStore<User> {
+        fn load_by_email(
+            &self,
+            email: &str,
+        ) -> impl Future<Output = Result<Option<User>, store::Error>> + Send {
+            let user = self
+                .objects
+                .lock()
+                .expect("poisoned mutex")
+                .values()
+                .find(|user| user.email == email)
+                .cloned();
+            async move { Ok(user) }
+        }
+    }
+
+    pub(crate) fn credentials(email: &str, password: &str) -> EmailPasswordCredentials {
+        EmailPasswordCredentials {
+            email: email.to_owned(),
+            password: password.to_owned().into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn seed() -> Result<(), BackendError> {
+        let store = SpyStore::<User>::default();
+
+        let roles = vec!["admin".to_owned()];
+        assert!(
+            seed_user(
+                &store,
+                credentials("admin@example.com", "hunter2"),
+                roles.clone(),
+                &Hasher::default()
+            )
+            .await?
+        );
+        assert_eq!(1, store.saves());
+        let admin = store
+            .load_by_email("admin@example.com")
+            .await?
+            .expect("seeded");
+        assert_eq!(roles, admin.roles);
+        assert!(admin.password().verify(b"hunter2")?);
+
+        // Seeding again is a no-op, even with another password
+        let other = credentials("admin@example.com", "other");
+        assert!(!seed_user(&store, other, roles, &Hasher::default()).await?);
+        assert_eq!(1, store.saves());
+        let again = store
+            .load_by_email("admin@example.com")
+            .await?
+            .expect("seeded");
+        assert_eq!(admin.uid, again.uid);
+        assert!(again.password().verify(b"hunter2")?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn email_password_backend() -> Result<(), BackendError> {
+        let store = SpyStore::<User>::default();
+        let hasher = Hasher::default();
+        seed_user(
+            &store,
+            credentials("me@example.com", "hunter2"),
+            vec![],
+            &hasher,
+        )
+        .await?;
+        let me = store
+            .load_by_email("me@example.com")
+            .await?
+            .expect("seeded");
+        let backend = EmailPasswordBackend::new(store);
+
+        let user = backend
+            .authenticate(credentials("me@example.com", "hunter2"))
+            .await?
+            .expect("authenticated");
+        assert_eq!(me.uid, user.uid);
+        let user = backend.get_user(&me.uid).await?.expect("user");
+        assert_eq!(me.uid, user.uid);
+
+        // Wrong password and unknown email look the same
+        for (email, password) in [
+            ("me@example.com", "hunter3"),
+            ("you@example.com", "hunter2"),
+        ] {
+            assert!(backend
+                .authenticate(credentials(email, password))
+                .await?
+                .is_none());
+        }
+
+        // Store failures are errors
+        backend.store().fail_load.store(true, Ordering::SeqCst);
+        assert!(backend.get_user(&me.uid).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn seed_peppered() -> Result<(), BackendError> {
+        let store = SpyStore::<User>::default();
+        let hasher = Hasher::default().with_pepper(b"thisisasecretpepper");
+        let me = credentials("me@example.com", "hunter2");
+        seed_user(&store, me, vec![], &hasher).await?;
+
+        // Logs in through a backend with the same pepper only
+        let backend = EmailPasswordBackend::new(store.clone()).with_hasher(hasher);
+        let user = backend
+            .authenticate(credentials("me@example.com", "hunter2"))
+            .await?;
+        assert!(user.is_some());
+        let backend = EmailPasswordBackend::new(store);
+        let user = backend
+            .authenticate(credentials("me@example.com", "hunter2"))
+            .await?;
+        assert!(user.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flag_outdated() -> Result<(), BackendError> {
+        use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+        use argon2::{Algorithm, Argon2, Params, Version};
+
+        let store = SpyStore::<User>::default();
+        let weak = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(8 * 1024, 1, 1, None).map_err(argon2::password_hash::Error::from)?,
+        );
+        for i in 0..5 {
+            let email = format!("user{}@example.com", i);
+            let password = PlainPassword::from("hunter2".to_owned()).cipher()?;
+            let mut user = User::seed(email, password, vec![]);
+            // Users 0, 2 and 4 were hashed with outdated parameters
+            if i % 2 == 0 {
+                let salt = SaltString::generate(&mut OsRng);
+                let hash = weak.hash_password(b"hunter2", &salt)?.to_string();
+                user.password = hash.as_str().try_into()?;
+            }
+            store.put(user);
+        }
+
+        let report = flag_outdated_hashes(&store, &Hasher::default()).await?;
+        assert_eq!(
+            RehashReport {
+                users: 5,
+                outdated: 3
+            },
+            report
+        );
+        assert_eq!(3, store.saves());
+        let mut flagged = store
+            .objects
+            .lock()
+            .expect("poisoned mutex")
+            .values()
+            .filter(|user| user.needs_rehash)
+            .map(|user| user.email.clone())
+            .collect::<Vec<_>>();
+        flagged.sort();
+        assert_eq!(
+            vec![
+                "user0@example.com",
+                "user2@example.com",
+                "user4@example.com"
+            ],
+            flagged
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use super::PlainPassword;
+use serde::{de, Deserialize, Deserializer};
+use std::fmt;
+
+/// Maximum length (in bytes) of an email address (RFC 5321 path limit)
+pub const MAX_EMAIL_LENGTH: usize = 254;
+/// Maximum length (in bytes) of a password.
+/// Anything longer is rejected before reaching the hasher.
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+
+/// Email and password credentials, as submitted by a login form.
+///
+/// Deserialization is bounded so untrusted input is rejected before it
+/// reaches the hasher: both fields must be strings no longer than
+/// `MAX_EMAIL_LENGTH` and `MAX_PASSWORD_LENGTH`, and unknown fields are refused.
+#[derive(Debug)]
+pub struct EmailPasswordCredentials {
+    pub email: String,
+    pub password: PlainPassword,
+}
+
+impl<'de> Deserialize<'de> for EmailPasswordCredentials {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Raw {
+            email: BoundedString<MAX_EMAIL_LENGTH>,
+            password: BoundedString<MAX_PASSWORD_LENGTH>,
+        }
+
+        let raw = Raw::deserialize(deserializer)?;
+        Ok(Self {
+            email: raw.email.0,
+            password: raw.password.0.into(),
+        })
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// A string refusing to deserialize when longer than `N` bytes.
+/// The length is checked before the string is copied.
+struct BoundedString<const N: usize>(String);
+
+impl<'de, const N: usize> Deserialize<'de> for BoundedString<N> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct Visitor<const N: usize>;
+
+        impl<'de, const N: usize> de::Visitor<'de> for Visitor<N> {
+            type Value = BoundedString<N>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a string of at most {} bytes", N)
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                if v.len() > N {
+                    return Err(E::invalid_length(v.len(), &self));
+                }
+                Ok(BoundedString(v.to_owned()))
+            }
+        }
+
+        deserializer.deserialize_str(Visitor::<N>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserialize() {
+        let creds: EmailPasswordCredentials =
+            serde_json::from_str(r#"{"email":"me@example.com","password":"hunter2"}"#)
+                .expect("should not fail");
+        assert_eq!("me@example.com", creds.email);
+
+        // Over-long fields
+        let email = "a".repeat(MAX_EMAIL_LENGTH + 1);
+        let json = format!(r#"{{"email":"{}","password":"hunter2"}}"#, email);
+        assert!(serde_json::from_str::<EmailPasswordCredentials>(&json).is_err());
+
+        let password = "a".repeat(MAX_PASSWORD_LENGTH + 1);
+        let json = format!(r#"{{"email":"me@example.com","password":"{}"}}"#, password);
+        assert!(serde_json::from_str::<EmailPasswordCredentials>(&json).is_err());
+
+        // Exactly at the limit is fine
+        let password = "a".repeat(MAX_PASSWORD_LENGTH);
+        let json = format!(r#"{{"email":"me@example.com","password":"{}"}}"#, password);
+        assert!(serde_json::from_str::<EmailPasswordCredentials>(&json).is_ok());
+
+        // Wrong shapes
+        for json in [
+            r#"{"email":{"nested":[[[[]]]]},"password":"hunter2"}"#,
+            r#"{"email":"me@example.com","password":["hunter2"]}"#,
+            r#"{"email":"me@example.com","password":"hunter2","extra":{"a":{"b":{}}}}"#,
+            r#"{"email":"me@example.com"}"#,
+        ] {
+            assert!(
+                serde_json::from_str::<EmailPasswordCredentials>(json).is_err(),
+                "{}",
+                json
+            );
+        }
+    }
+}
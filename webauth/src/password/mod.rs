@@ -1,2 +1,17 @@
+mod backend;
+mod credentials;
 mod password;
-pub use self::password::{hash, verify};
+mod policy;
+#[cfg(feature = "token")]
+pub mod reset;
+pub use self::backend::{
+    flag_outdated_hashes, seed_user, BackendError, EmailPasswordBackend, PasswordUser,
+    PasswordUserStore, RehashReport, RehashUser, SeedUser,
+};
+pub use self::credentials::{EmailPasswordCredentials, MAX_EMAIL_LENGTH, MAX_PASSWORD_LENGTH};
+pub use self::password::{
+    hash, verify, verify_dummy, verify_str, CipheredPassword, Hasher, PlainPassword, VerifyOutcome,
+};
+#[cfg(feature = "tokio")]
+pub use self::password::{hash_async, verify_async};
+pub use self::policy::{CipherError, PasswordPolicy, PolicyViolation};
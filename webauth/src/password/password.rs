@@ -2,36 +2,78 @@ use argon2::password_hash::PasswordHashString;
 use argon2::password_hash::{
     rand_core::OsRng, Error, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
 };
-use argon2::Argon2;
+use argon2::{Algorithm, Argon2, Params, Version};
+use std::sync::Arc;
+use zeroize::Zeroizing;
 
 /// Represents a plain password.
-#[derive(Debug, Clone)]
-pub struct PlainPassword(String);
+///
+/// It is wiped from memory when dropped, and cannot be cloned so no copy
+/// lingers around.
+pub struct PlainPassword(Zeroizing<String>);
+
+impl std::fmt::Debug for PlainPassword {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PlainPassword([redacted])")
+    }
+}
 
 impl From<String> for PlainPassword {
     fn from(value: String) -> Self {
-        PlainPassword(value)
+        PlainPassword(Zeroizing::new(value))
     }
 }
 
 impl PlainPassword {
-    /// Ciphers the plain password
+    /// Ciphers the plain password, with the default `Hasher`
     pub fn cipher(self) -> Result<CipheredPassword, Error> {
         self.try_into()
     }
+
+    // Returns the password, to check it
+    pub(super) fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Ciphers the plain password with the given `Hasher`
+    pub fn cipher_with(self, hasher: &Hasher) -> Result<CipheredPassword, Error> {
+        Ok(CipheredPassword(Ciphered::Argon2(
+            hasher.hash(self.0.as_bytes())?,
+        )))
+    }
 }
 
 // ----------------------------------------------------------------------------
 
-/// Represents a ciphered password.;
-#[derive(Debug, Clone)]
-pub struct CipheredPassword(PasswordHashString);
+/// Represents a ciphered password.
+///
+/// Passwords are hashed with Argon2. With the `bcrypt` feature, bcrypt
+/// hashes (`$2a$`, `$2b$`, `$2y$`) are accepted too, to migrate users of
+/// legacy systems: verify them, then rehash the password with Argon2 on
+/// login (they always need a rehash).
+#[derive(Clone)]
+pub struct CipheredPassword(Ciphered);
+
+// The hash, along with its algorithm
+#[derive(Clone)]
+enum Ciphered {
+    Argon2(PasswordHashString),
+    #[cfg(feature = "bcrypt")]
+    Bcrypt(String),
+}
+
+/// The hash is redacted as well, it could be brute-forced offline.
+impl std::fmt::Debug for CipheredPassword {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CipheredPassword([redacted])")
+    }
+}
 
 impl TryFrom<PlainPassword> for CipheredPassword {
     type Error = Error;
 
     fn try_from(value: PlainPassword) -> Result<Self, Self::Error> {
-        Ok(Self(hash(value.0.as_bytes())?))
+        value.cipher_with(&Hasher::default())
     }
 }
 
@@ -39,32 +81,302 @@ impl TryFrom<&str> for CipheredPassword {
     type Error = Error;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        Ok(Self(PasswordHash::new(value)?.serialize()))
+        #[cfg(feature = "bcrypt")]
+        if ["$2a$", "$2b$", "$2y$"]
+            .iter()
+            .any(|prefix| value.starts_with(prefix))
+        {
+            value
+                .parse::<bcrypt::HashParts>()
+                .map_err(|_| Error::PhcStringField)?;
+            return Ok(Self(Ciphered::Bcrypt(value.to_owned())));
+        }
+        Ok(Self(Ciphered::Argon2(
+            PasswordHash::new(value)?.serialize(),
+        )))
     }
 }
 
 impl CipheredPassword {
+    /// Returns the hash (a PHC string for Argon2), to store it
+    pub fn as_str(&self) -> &str {
+        match &self.0 {
+            Ciphered::Argon2(hash) => hash.as_str(),
+            #[cfg(feature = "bcrypt")]
+            Ciphered::Bcrypt(hash) => hash,
+        }
+    }
+
     pub fn verify(&self, password: &[u8]) -> Result<bool, Error> {
-        verify(password, &self.0.password_hash())
+        self.verify_with(password, &Hasher::default())
+    }
+
+    /// Verifies the password with the given `Hasher`, needed when it has a
+    /// pepper (bcrypt hashes never have one)
+    pub fn verify_with(&self, password: &[u8], hasher: &Hasher) -> Result<bool, Error> {
+        match &self.0 {
+            Ciphered::Argon2(hash) => hasher.verify(password, &hash.password_hash()),
+            #[cfg(feature = "bcrypt")]
+            Ciphered::Bcrypt(hash) => bcrypt::verify(password, hash).map_err(|_| Error::Crypto),
+        }
     }
+
+    // Returns the Argon2 hash, `None` for other algorithms
+    fn argon2(&self) -> Option<PasswordHash<'_>> {
+        match &self.0 {
+            Ciphered::Argon2(hash) => Some(hash.password_hash()),
+            #[cfg(feature = "bcrypt")]
+            Ciphered::Bcrypt(_) => None,
+        }
+    }
+
+    /// Returns if the password was hashed with another algorithm or version
+    /// than the `target` hasher, or any parameter weaker than its ones, so it
+    /// should be rehashed (which needs the plain password, e.g. on next
+    /// login). Agrees with `verify_and_check`.
+    pub fn needs_rehash(&self, target: &Hasher) -> bool {
+        self.weaker_than(target)
+    }
+
+    /// Verifies the password, also returning if the hash should be upgraded
+    /// to the `target` parameters: `(matched, needs_rehash)`.
+    ///
+    /// The hash needs a rehash when it was made with another algorithm than
+    /// the default one, or any parameter weaker than the target one. Only
+    /// reported for a matching password, as the plain password is needed to
+    /// rehash: hash it with the new parameters and store it on login.
+    ///
+    /// It verifies without a pepper: a peppered hash never matches.
+    #[deprecated(note = "use `verify_and_check`, which takes the `Hasher` and its pepper")]
+    pub fn verify_and_check_rehash(
+        &self,
+        password: &[u8],
+        target: &Params,
+    ) -> Result<(bool, bool), Error> {
+        let target = Hasher::new(Algorithm::default(), Version::default(), target.clone());
+        Ok(match self.verify_and_check(password, &target)? {
+            VerifyOutcome::Invalid => (false, false),
+            VerifyOutcome::Valid => (true, false),
+            VerifyOutcome::ValidNeedsRehash => (true, true),
+        })
+    }
+
+    /// Verifies the password with the `target` hasher (for its pepper), also
+    /// checking if the hash was made with another algorithm or version, or
+    /// any parameter weaker than the target ones.
+    /// On `ValidNeedsRehash`, hash the password with `target` and store it.
+    pub fn verify_and_check(
+        &self,
+        password: &[u8],
+        target: &Hasher,
+    ) -> Result<VerifyOutcome, Error> {
+        Ok(if !self.verify_with(password, target)? {
+            VerifyOutcome::Invalid
+        } else if self.weaker_than(target) {
+            VerifyOutcome::ValidNeedsRehash
+        } else {
+            VerifyOutcome::Valid
+        })
+    }
+
+    // Returns if the hash is weaker than the one the hasher would make
+    fn weaker_than(&self, target: &Hasher) -> bool {
+        let Some(hash) = self.argon2() else {
+            return true;
+        };
+        hash.algorithm != target.algorithm.ident()
+            || hash.version != Some(target.version.into())
+            || Params::try_from(&hash).map_or(true, |hashed| {
+                hashed.m_cost() < target.params.m_cost()
+                    || hashed.t_cost() < target.params.t_cost()
+                    || hashed.p_cost() < target.params.p_cost()
+            })
+    }
+}
+
+/// Result of `CipheredPassword::verify_and_check`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyOutcome {
+    /// The password does not match
+    Invalid,
+    /// The password matches, and its hash is up to date
+    Valid,
+    /// The password matches, but its hash is weaker than the target one
+    ValidNeedsRehash,
 }
 
 // ----------------------------------------------------------------------------
 
-/// Hash the given password
+/// Hashes passwords with Argon2, using the given algorithm, version and
+/// parameters (memory cost, iterations, parallelism) to match the hardware
+/// or the security requirements.
+/// The default is the recommended Argon2id with the default parameters.
+///
+/// Only hashing uses them: a hash carries the parameters it was made with,
+/// so passwords verify whatever the parameters of their hash.
+/// The pepper is the exception, see `Hasher::with_pepper`.
+#[derive(Debug, Clone, Default)]
+pub struct Hasher {
+    algorithm: Algorithm,
+    version: Version,
+    params: Params,
+    pepper: Option<Pepper>,
+}
+
+/// Secret key mixed in the hashes, never part of them (nor of the logs)
+#[derive(Clone)]
+struct Pepper(Arc<[u8]>);
+
+impl std::fmt::Debug for Pepper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Pepper([redacted])")
+    }
+}
+
+impl Hasher {
+    /// Creates a `Hasher` with the given algorithm, version and parameters
+    pub fn new(algorithm: Algorithm, version: Version, params: Params) -> Self {
+        Self {
+            algorithm,
+            version,
+            params,
+            pepper: None,
+        }
+    }
+
+    /// Mixes the given secret "pepper" in the hashes (Argon2 keyed mode), as
+    /// defense in depth should the hashes leak.
+    ///
+    /// Unlike the parameters, the pepper is not stored in the PHC string of
+    /// the hashes: keep it outside the database (environment, KMS, ...), the
+    /// same pepper must be given to verify them. Losing it means losing all
+    /// the passwords.
+    pub fn with_pepper(mut self, pepper: &[u8]) -> Self {
+        self.pepper = Some(Pepper(pepper.into()));
+        self
+    }
+
+    /// Returns the parameters used to hash
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    /// Hash the given password
+    pub fn hash(&self, password: &[u8]) -> Result<PasswordHashString, Error> {
+        let salt = SaltString::generate(&mut OsRng);
+        Ok(self.argon2()?.hash_password(password, &salt)?.serialize())
+    }
+
+    /// Verify that the given password matches the given hash, whatever the
+    /// parameters it was made with
+    pub fn verify(&self, password: &[u8], password_hash: &PasswordHash<'_>) -> Result<bool, Error> {
+        Ok(self
+            .argon2()?
+            .verify_password(password, password_hash)
+            .is_ok())
+    }
+
+    /// Does the work of a failing `verify`, always returning `Ok(false)`.
+    ///
+    /// Backends must call it when no user matches the credentials: without
+    /// it, unknown users are rejected faster than wrong passwords, telling
+    /// which accounts exist.
+    pub fn verify_dummy(&self) -> Result<bool, Error> {
+        // Hashing costs the same as verifying with the same parameters
+        self.hash(b"dummy password")?;
+        Ok(false)
+    }
+
+    /// Like `hash`, on the blocking thread pool of tokio, so hashing does
+    /// not block the runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn hash_async(&self, password: &[u8]) -> Result<PasswordHashString, Error> {
+        let hasher = self.clone();
+        let password = Zeroizing::new(password.to_vec());
+        spawn_blocking(move || hasher.hash(&password)).await
+    }
+
+    /// Like `verify`, on the blocking thread pool of tokio, so verifying
+    /// does not block the runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn verify_async(
+        &self,
+        password: &[u8],
+        password_hash: &PasswordHash<'_>,
+    ) -> Result<bool, Error> {
+        let hasher = self.clone();
+        let password = Zeroizing::new(password.to_vec());
+        let password_hash = password_hash.serialize();
+        spawn_blocking(move || hasher.verify(&password, &password_hash.password_hash())).await
+    }
+
+    fn argon2(&self) -> Result<Argon2<'_>, Error> {
+        let params = self.params.clone();
+        Ok(match &self.pepper {
+            Some(Pepper(pepper)) => {
+                Argon2::new_with_secret(pepper, self.algorithm, self.version, params)?
+            }
+            None => Argon2::new(self.algorithm, self.version, params),
+        })
+    }
+}
+
+/// Hash the given password, with the default `Hasher`
 pub fn hash(password: &[u8]) -> Result<PasswordHashString, Error> {
-    let salt = SaltString::generate(&mut OsRng);
-    Ok(Argon2::default()
-        .hash_password(password, &salt)?
-        .serialize())
+    Hasher::default().hash(password)
 }
 
 /// Verify that the given password matches the given hash (hash must be
-/// generated using `hash`)
+/// generated using `hash` or a `Hasher`)
 pub fn verify(password: &[u8], password_hash: &PasswordHash<'_>) -> Result<bool, Error> {
-    Ok(Argon2::default()
-        .verify_password(password, password_hash)
-        .is_ok())
+    Hasher::default().verify(password, password_hash)
+}
+
+/// Hash the given password with the default `Hasher`, on the blocking
+/// thread pool of tokio
+#[cfg(feature = "tokio")]
+pub async fn hash_async(password: &[u8]) -> Result<PasswordHashString, Error> {
+    Hasher::default().hash_async(password).await
+}
+
+/// Verify that the given password matches the given hash with the default
+/// `Hasher`, on the blocking thread pool of tokio
+#[cfg(feature = "tokio")]
+pub async fn verify_async(
+    password: &[u8],
+    password_hash: &PasswordHash<'_>,
+) -> Result<bool, Error> {
+    Hasher::default()
+        .verify_async(password, password_hash)
+        .await
+}
+
+// Runs the hashing work on the blocking thread pool, resuming its panics
+#[cfg(feature = "tokio")]
+async fn spawn_blocking<T, F>(work: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(work).await {
+        Ok(res) => res,
+        Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+        // Cancelled, the runtime is shutting down
+        Err(_) => Err(Error::Crypto),
+    }
+}
+
+/// Does the work of a failing `verify` with the default `Hasher`, see
+/// `Hasher::verify_dummy`.
+pub fn verify_dummy() -> Result<bool, Error> {
+    Hasher::default().verify_dummy()
+}
+
+/// Verify that the given password matches the given PHC string, as stored
+/// in a database column for example.
+/// Returns an error if the PHC string is malformed.
+pub fn verify_str(password: &[u8], phc: &str) -> Result<bool, Error> {
+    verify(password, &PasswordHash::new(phc)?)
 }
 
 #[cfg(test)]
@@ -94,12 +406,26 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn verify_phc_str() -> Result<(), Error> {
+        let hashed = hash(b"thisisapassword")?;
+
+        assert!(verify_str(b"thisisapassword", hashed.as_str())?);
+        assert!(!verify_str(b"wrongpassword", hashed.as_str())?);
+        assert_eq!(
+            Err(Error::PhcStringField),
+            verify_str(b"thisisapassword", "notavalidargon")
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn types() {
         let plain: PlainPassword = "thisisapassword".to_owned().into();
         let ciphered = plain.cipher().expect("should not fail");
 
-        let ciphered: CipheredPassword = ciphered.0.as_ref().try_into().expect("should not fail");
+        let ciphered: CipheredPassword = ciphered.as_str().try_into().expect("should not fail");
         assert!(ciphered
             .verify("thisisapassword".as_ref())
             .expect("should not fail"));
@@ -110,4 +436,261 @@ mod tests {
         let err = std::convert::TryInto::<CipheredPassword>::try_into("notavalidargon");
         assert_eq!(err.unwrap_err(), Error::PhcStringField,);
     }
+
+    #[test]
+    fn debug_redacted() {
+        let plain: PlainPassword = "thisisapassword".to_owned().into();
+        assert_eq!("PlainPassword([redacted])", format!("{:?}", plain));
+
+        let ciphered = plain.cipher().expect("should not fail");
+        let debug = format!("{:?}", ciphered);
+        assert_eq!("CipheredPassword([redacted])", debug);
+        let hash = ciphered.argon2().expect("argon2").hash.expect("hash");
+        assert!(!debug.contains(hash.to_string().as_str()));
+    }
+
+    #[test]
+    fn hasher() -> Result<(), Error> {
+        let hasher = Hasher::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(8 * 1024, 1, 1, None)?,
+        );
+        let plain: PlainPassword = "thisisapassword".to_owned().into();
+        let ciphered = plain.cipher_with(&hasher)?;
+        assert!(
+            ciphered
+                .as_str()
+                .starts_with("$argon2id$v=19$m=8192,t=1,p=1$"),
+            "{}",
+            ciphered.as_str()
+        );
+
+        // Verifies with any parameters, they are read from the hash
+        assert!(ciphered.verify(b"thisisapassword")?);
+        assert!(!ciphered.verify(b"wrongpassword")?);
+        let hashed = Hasher::default().hash(b"thisisapassword")?;
+        assert!(hasher.verify(b"thisisapassword", &hashed.password_hash())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dummy() -> Result<(), Error> {
+        assert!(!verify_dummy()?);
+        let hasher = Hasher::default().with_pepper(b"thisisasecretpepper");
+        assert!(!hasher.verify_dummy()?);
+
+        // Takes as long as a failing verify (generous bound for slow CI)
+        let hashed = hash(b"thisisapassword")?;
+        let start = std::time::Instant::now();
+        assert!(!verify(b"wrongpassword", &hashed.password_hash())?);
+        let verify_time = start.elapsed();
+        let start = std::time::Instant::now();
+        verify_dummy()?;
+        assert!(start.elapsed() * 4 > verify_time);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn hash_async() -> Result<(), Error> {
+        let hashed = super::hash_async(b"thisisapassword").await?;
+        assert!(verify_async(b"thisisapassword", &hashed.password_hash()).await?);
+        assert!(!verify_async(b"wrongpassword", &hashed.password_hash()).await?);
+        // Same as the sync version
+        assert!(verify(b"thisisapassword", &hashed.password_hash())?);
+
+        let hasher = Hasher::default().with_pepper(b"thisisasecretpepper");
+        let peppered = hasher.hash_async(b"thisisapassword").await?;
+        assert!(
+            hasher
+                .verify_async(b"thisisapassword", &peppered.password_hash())
+                .await?
+        );
+        assert!(!verify_async(b"thisisapassword", &peppered.password_hash()).await?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pepper() -> Result<(), Error> {
+        let hasher = Hasher::default().with_pepper(b"thisisasecretpepper");
+        assert!(!format!("{:?}", hasher).contains("thisisasecretpepper"));
+        let ciphered = PlainPassword::from("thisisapassword".to_owned()).cipher_with(&hasher)?;
+        assert!(!ciphered.as_str().contains("thisisasecretpepper"));
+
+        // Only verifies with the same pepper
+        assert!(ciphered.verify_with(b"thisisapassword", &hasher)?);
+        assert!(!ciphered.verify_with(b"wrongpassword", &hasher)?);
+        assert!(!ciphered.verify(b"thisisapassword")?);
+        let other = Hasher::default().with_pepper(b"anotherpepper");
+        assert!(!ciphered.verify_with(b"thisisapassword", &other)?);
+
+        Ok(())
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn verify_and_check_rehash() -> Result<(), Error> {
+        let weak = Params::new(8 * 1024, 1, 1, None)?;
+        let strong = Params::new(16 * 1024, 2, 1, None)?;
+        let hasher = Hasher::new(Algorithm::Argon2id, Version::V0x13, weak.clone());
+        let ciphered = PlainPassword::from("thisisapassword".to_owned()).cipher_with(&hasher)?;
+
+        // Upgrading the parameters
+        assert_eq!(
+            (true, true),
+            ciphered.verify_and_check_rehash(b"thisisapassword", &strong)?
+        );
+        // Same or weaker parameters
+        assert_eq!(
+            (true, false),
+            ciphered.verify_and_check_rehash(b"thisisapassword", &weak)?
+        );
+        assert_eq!(
+            (true, false),
+            ciphered
+                .verify_and_check_rehash(b"thisisapassword", &Params::new(4 * 1024, 1, 1, None)?)?
+        );
+        // Wrong password, nothing to rehash
+        assert_eq!(
+            (false, false),
+            ciphered.verify_and_check_rehash(b"wrongpassword", &strong)?
+        );
+
+        // Once rehashed, up to date
+        let rehashed = PlainPassword::from("thisisapassword".to_owned()).cipher_with(
+            &Hasher::new(Algorithm::Argon2id, Version::V0x13, strong.clone()),
+        )?;
+        assert_eq!(
+            (true, false),
+            rehashed.verify_and_check_rehash(b"thisisapassword", &strong)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_and_check() -> Result<(), Error> {
+        let weak = Hasher::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(8 * 1024, 1, 1, None)?,
+        );
+        let strong = Hasher::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(16 * 1024, 2, 1, None)?,
+        );
+        let ciphered = PlainPassword::from("thisisapassword".to_owned()).cipher_with(&weak)?;
+
+        assert_eq!(
+            VerifyOutcome::ValidNeedsRehash,
+            ciphered.verify_and_check(b"thisisapassword", &strong)?
+        );
+        assert_eq!(
+            VerifyOutcome::Valid,
+            ciphered.verify_and_check(b"thisisapassword", &weak)?
+        );
+        assert_eq!(
+            VerifyOutcome::Invalid,
+            ciphered.verify_and_check(b"wrongpassword", &strong)?
+        );
+
+        // Another algorithm is outdated, whatever its parameters
+        let argon2i = Hasher::new(
+            Algorithm::Argon2i,
+            Version::V0x13,
+            Params::new(16 * 1024, 2, 1, None)?,
+        );
+        let ciphered = PlainPassword::from("thisisapassword".to_owned()).cipher_with(&argon2i)?;
+        assert_eq!(
+            VerifyOutcome::ValidNeedsRehash,
+            ciphered.verify_and_check(b"thisisapassword", &strong)?
+        );
+
+        // Verified with the pepper of the target
+        let peppered = strong.with_pepper(b"thisisasecretpepper");
+        let ciphered = PlainPassword::from("thisisapassword".to_owned()).cipher_with(&peppered)?;
+        assert_eq!(
+            VerifyOutcome::Valid,
+            ciphered.verify_and_check(b"thisisapassword", &peppered)?
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "bcrypt")]
+    #[test]
+    fn bcrypt() -> Result<(), Error> {
+        // From the OpenBSD test vectors
+        let legacy: CipheredPassword =
+            "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW".try_into()?;
+        assert!(legacy.verify(b"U*U")?);
+        assert!(!legacy.verify(b"U*V")?);
+        assert!(legacy.needs_rehash(&Hasher::default()));
+        assert_eq!(
+            VerifyOutcome::ValidNeedsRehash,
+            legacy.verify_and_check(b"U*U", &Hasher::default())?
+        );
+
+        let hash = ::bcrypt::hash("thisisapassword", 4).map_err(|_| Error::Crypto)?;
+        assert!(hash.starts_with("$2b$"), "{}", hash);
+        let legacy: CipheredPassword = hash.as_str().try_into()?;
+        assert!(legacy.verify(b"thisisapassword")?);
+        assert_eq!(hash, legacy.as_str());
+        assert!(CipheredPassword::try_from("$2b$04$invalid").is_err());
+
+        // Rehashed with Argon2
+        let rehashed = PlainPassword::from("thisisapassword".to_owned()).cipher()?;
+        assert!(rehashed.as_str().starts_with("$argon2id$"));
+        assert!(!rehashed.needs_rehash(&Hasher::default()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn needs_rehash() -> Result<(), Error> {
+        let current: CipheredPassword = hash(b"thisisapassword")?.as_str().try_into()?;
+        assert!(!current.needs_rehash(&Hasher::default()));
+
+        // Weaker parameters than the current ones
+        let salt = SaltString::generate(&mut OsRng);
+        let weak = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(8 * 1024, 1, 1, None)?,
+        )
+        .hash_password(b"thisisapassword", &salt)?
+        .serialize();
+        let weak: CipheredPassword = weak.as_str().try_into()?;
+        assert!(weak.needs_rehash(&Hasher::default()));
+        // Still verifies, parameters are read from the hash
+        assert!(weak.verify(b"thisisapassword")?);
+
+        // Another algorithm
+        let argon2i = Argon2::new(Algorithm::Argon2i, Version::V0x13, Params::default())
+            .hash_password(b"thisisapassword", &salt)?
+            .serialize();
+        let argon2i: CipheredPassword = argon2i.as_str().try_into()?;
+        assert!(argon2i.needs_rehash(&Hasher::default()));
+
+        // Against a custom hasher: stronger hashes are up to date
+        let strong = Hasher::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(Params::DEFAULT_M_COST * 2, 3, 1, None)?,
+        );
+        assert!(current.needs_rehash(&strong));
+        let stronger: CipheredPassword = PlainPassword::from("thisisapassword".to_owned())
+            .cipher_with(&strong)?
+            .as_str()
+            .try_into()?;
+        assert!(!stronger.needs_rehash(&strong));
+        assert!(!stronger.needs_rehash(&Hasher::default()));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,170 @@
+use super::{CipheredPassword, Hasher, PlainPassword};
+
+/// Rule of a `PasswordPolicy` a password does not follow, to tell the user
+/// how to choose a valid one.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyViolation {
+    /// Less characters than the minimum length
+    #[error("password must be at least {0} characters long")]
+    TooShort(usize),
+    /// No uppercase letter
+    #[error("password must contain an uppercase letter")]
+    MissingUpper,
+    /// No digit
+    #[error("password must contain a digit")]
+    MissingDigit,
+    /// No symbol (anything but a letter, a digit or a whitespace)
+    #[error("password must contain a symbol")]
+    MissingSymbol,
+}
+
+/// Error of `PlainPassword::cipher_with_policy`
+#[derive(thiserror::Error, Debug)]
+pub enum CipherError {
+    /// The password does not follow the policy
+    #[error(transparent)]
+    Policy(#[from] PolicyViolation),
+    /// Error while hashing the password
+    #[error("password: {0}")]
+    Hash(argon2::password_hash::Error),
+}
+
+impl From<argon2::password_hash::Error> for CipherError {
+    fn from(err: argon2::password_hash::Error) -> Self {
+        Self::Hash(err)
+    }
+}
+
+/// Rules a new password must follow, checked before hashing it.
+///
+/// The default only requires 8 characters: length matters more than
+/// composition rules, which users work around with predictable patterns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    /// Minimum number of characters
+    pub min_length: usize,
+    /// Requires an uppercase letter
+    pub require_upper: bool,
+    /// Requires a digit
+    pub require_digit: bool,
+    /// Requires a symbol (anything but a letter, a digit or a whitespace)
+    pub require_symbol: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            require_upper: false,
+            require_digit: false,
+            require_symbol: false,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Checks the password follows the policy, returning the first rule it
+    /// does not follow.
+    pub fn validate(&self, password: &PlainPassword) -> Result<(), PolicyViolation> {
+        let password = password.as_str();
+        if password.chars().count() < self.min_length {
+            return Err(PolicyViolation::TooShort(self.min_length));
+        }
+        if self.require_upper && !password.chars().any(char::is_uppercase) {
+            return Err(PolicyViolation::MissingUpper);
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            return Err(PolicyViolation::MissingDigit);
+        }
+        if self.require_symbol
+            && !password
+                .chars()
+                .any(|c| !c.is_alphanumeric() && !c.is_whitespace())
+        {
+            return Err(PolicyViolation::MissingSymbol);
+        }
+        Ok(())
+    }
+}
+
+impl PlainPassword {
+    /// Ciphers the plain password with the given `Hasher`, if it follows
+    /// the given policy.
+    pub fn cipher_with_policy(
+        self,
+        policy: &PasswordPolicy,
+        hasher: &Hasher,
+    ) -> Result<CipheredPassword, CipherError> {
+        policy.validate(&self)?;
+        Ok(self.cipher_with(hasher)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn password(password: &str) -> PlainPassword {
+        password.to_owned().into()
+    }
+
+    #[test]
+    fn validate() {
+        let default = PasswordPolicy::default();
+        assert_eq!(Ok(()), default.validate(&password("hunter22")));
+        assert_eq!(
+            Err(PolicyViolation::TooShort(8)),
+            default.validate(&password("hunter2"))
+        );
+        // Characters are counted, not bytes
+        assert_eq!(
+            Err(PolicyViolation::TooShort(8)),
+            default.validate(&password("éééééé"))
+        );
+
+        let strict = PasswordPolicy {
+            min_length: 10,
+            require_upper: true,
+            require_digit: true,
+            require_symbol: true,
+        };
+        assert_eq!(Ok(()), strict.validate(&password("Hunter2-and-more")));
+        for (password, violation) in [
+            ("Hunter2-", PolicyViolation::TooShort(10)),
+            ("hunter2-and-more", PolicyViolation::MissingUpper),
+            ("Hunter-and-more", PolicyViolation::MissingDigit),
+            ("Hunter2 and more", PolicyViolation::MissingSymbol),
+        ] {
+            assert_eq!(
+                Err(violation),
+                strict.validate(&self::password(password)),
+                "{}",
+                password
+            );
+        }
+    }
+
+    #[test]
+    fn cipher_with_policy() {
+        let policy = PasswordPolicy::default();
+        let hasher = Hasher::default().with_pepper(b"thisisasecretpepper");
+        let ciphered = password("thisisapassword")
+            .cipher_with_policy(&policy, &hasher)
+            .expect("valid password");
+        assert!(ciphered
+            .verify_with(b"thisisapassword", &hasher)
+            .expect("verify"));
+
+        let err = password("short")
+            .cipher_with_policy(&policy, &hasher)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            CipherError::Policy(PolicyViolation::TooShort(8))
+        ));
+        assert_eq!(
+            "password must be at least 8 characters long",
+            err.to_string()
+        );
+    }
+}
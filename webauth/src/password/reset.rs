@@ -0,0 +1,139 @@
+use super::{CipheredPassword, Hasher, PlainPassword};
+use crate::store::{self, Store, VersionedStore};
+use crate::token::{self, OneTimeToken, Purpose};
+use std::time::Duration;
+
+/// Error of `consume_reset` and `complete_reset`
+#[derive(thiserror::Error, Debug)]
+pub enum ResetError {
+    /// The token is malformed, unknown, expired, already used or was issued
+    /// for another purpose
+    #[error("invalid reset token")]
+    InvalidToken,
+    /// Error from the token store
+    #[error("store: {0}")]
+    Store(#[from] store::Error),
+    /// Error while hashing the new password
+    #[error("password: {0}")]
+    Password(argon2::password_hash::Error),
+}
+
+impl From<argon2::password_hash::Error> for ResetError {
+    fn from(err: argon2::password_hash::Error) -> Self {
+        Self::Password(err)
+    }
+}
+
+/// Issues a password reset token for `user_uid`, valid for `ttl`.
+///
+/// Returns the token string to send to the user (e.g. in a link by email).
+pub async fn request_reset<S, Uid>(
+    store: &S,
+    user_uid: Uid,
+    ttl: Duration,
+) -> Result<(String, OneTimeToken<Uid>), store::Error>
+where
+    S: Store<Object = OneTimeToken<Uid>>,
+{
+    token::issue(store, user_uid, Purpose::PasswordReset, ttl).await
+}
+
+/// Consumes the reset token, returning its user.
+///
+/// For callers ciphering the new password themselves (e.g. with
+/// `PlainPassword::cipher_with_policy`), `complete_reset` does both.
+pub async fn consume_reset<S, Uid>(store: &S, token: &str) -> Result<Uid, ResetError>
+where
+    S: VersionedStore<Object = OneTimeToken<Uid>>,
+{
+    token::consume(store, token, Purpose::PasswordReset)
+        .await?
+        .ok_or(ResetError::InvalidToken)
+}
+
+/// Consumes the reset token and ciphers the new password with `hasher`.
+///
+/// Returns the user and its new password, for the caller to save it and to
+/// log the user out of its other sessions (see `auth::logout_everywhere`).
+pub async fn complete_reset<S, Uid>(
+    store: &S,
+    token: &str,
+    new_password: PlainPassword,
+    hasher: &Hasher,
+) -> Result<(Uid, CipheredPassword), ResetError>
+where
+    S: VersionedStore<Object = OneTimeToken<Uid>>,
+{
+    let user_uid = consume_reset(store, token).await?;
+    Ok((user_uid, new_password.cipher_with(hasher)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::SpyStore;
+    use std::time::SystemTime;
+
+    type Tokens = SpyStore<OneTimeToken<u64>>;
+
+    const TTL: Duration = Duration::from_secs(3600);
+
+    fn password() -> PlainPassword {
+        "correct horse battery staple".to_owned().into()
+    }
+
+    #[tokio::test]
+    async fn reset() {
+        let tokens = Tokens::default();
+        let hasher = Hasher::default();
+        let (raw, _) = request_reset(&tokens, 42, TTL).await.expect("request");
+
+        let (user, ciphered) = complete_reset(&tokens, &raw, password(), &hasher)
+            .await
+            .expect("reset");
+        assert_eq!(42, user);
+        assert!(ciphered
+            .verify(b"correct horse battery staple")
+            .expect("verify"));
+
+        // Single use
+        let res = complete_reset(&tokens, &raw, password(), &hasher).await;
+        assert!(matches!(res, Err(ResetError::InvalidToken)));
+    }
+
+    #[tokio::test]
+    async fn consume_only() {
+        let tokens = Tokens::default();
+        let (raw, _) = request_reset(&tokens, 42, TTL).await.expect("request");
+
+        assert_eq!(42, consume_reset(&tokens, &raw).await.expect("consume"));
+        let res = consume_reset(&tokens, &raw).await;
+        assert!(matches!(res, Err(ResetError::InvalidToken)));
+        let res = consume_reset(&tokens, "garbage").await;
+        assert!(matches!(res, Err(ResetError::InvalidToken)));
+    }
+
+    #[tokio::test]
+    async fn expired() {
+        let tokens = Tokens::default();
+        let (raw, token) = request_reset(&tokens, 42, TTL).await.expect("request");
+        tokens.put(OneTimeToken {
+            expires_at: SystemTime::now() - Duration::from_secs(1),
+            ..token
+        });
+
+        let res = complete_reset(&tokens, &raw, password(), &Hasher::default()).await;
+        assert!(matches!(res, Err(ResetError::InvalidToken)));
+    }
+
+    #[tokio::test]
+    async fn wrong_purpose() {
+        let tokens = Tokens::default();
+        let (raw, _) = token::issue(&tokens, 42, Purpose::EmailVerification, TTL)
+            .await
+            .expect("issue");
+
+        let res = complete_reset(&tokens, &raw, password(), &Hasher::default()).await;
+        assert!(matches!(res, Err(ResetError::InvalidToken)));
+    }
+}
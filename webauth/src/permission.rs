@@ -0,0 +1,165 @@
+use http::{Request, Response, StatusCode};
+use std::{
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower_service::Service;
+
+/// A user having permissions (roles, scopes, ...), checked by the
+/// `PermissionLayer`.
+pub trait Authorize {
+    /// Returns if the user has the given permission
+    fn has_permission(&self, permission: &str) -> bool;
+}
+
+// ----------------------------------------------------------------------------
+
+/// Rejects the requests whose user does not have a permission.
+#[derive(Debug)]
+pub struct PermissionManager<Service, User> {
+    inner: Service,
+    permission: &'static str,
+    _user: PhantomData<fn() -> User>,
+}
+
+impl<S, User> Clone for PermissionManager<S, User>
+where
+    S: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            permission: self.permission,
+            _user: PhantomData,
+        }
+    }
+}
+
+impl<ReqBody, ResBody, S, User> Service<Request<ReqBody>> for PermissionManager<S, User>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+    ResBody: Default + 'static,
+    User: Authorize + Send + Sync + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = std::result::Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        // Anonymous requests only get here with `UserManagerLayer::with_anonymous`
+        let status = match req.extensions().get::<User>() {
+            Some(user) if user.has_permission(self.permission) => None,
+            Some(_) => {
+                tracing::debug!(permission = self.permission, "permission denied");
+                Some(StatusCode::FORBIDDEN)
+            }
+            None => Some(StatusCode::UNAUTHORIZED),
+        };
+
+        Box::pin(async move {
+            if let Some(status) = status {
+                let mut res = Response::default();
+                *res.status_mut() = status;
+                return Ok(res);
+            }
+            inner.call(req).await
+        })
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// Only lets through the requests whose user has `permission`, the others
+/// get a 403 (a 401 without user).
+///
+/// It reads the user the `UserManager` put in the request extensions, so it
+/// must be wrapped by the `UserManagerLayer`.
+#[derive(Debug)]
+pub struct PermissionLayer<User> {
+    permission: &'static str,
+    _user: PhantomData<fn() -> User>,
+}
+
+impl<User> PermissionLayer<User>
+where
+    User: Authorize,
+{
+    pub fn new(permission: &'static str) -> Self {
+        Self {
+            permission,
+            _user: PhantomData,
+        }
+    }
+}
+
+impl<User> Clone for PermissionLayer<User> {
+    fn clone(&self) -> Self {
+        Self {
+            permission: self.permission,
+            _user: PhantomData,
+        }
+    }
+}
+
+impl<S, User> tower_layer::Layer<S> for PermissionLayer<User> {
+    type Service = PermissionManager<S, User>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PermissionManager {
+            inner,
+            permission: self.permission,
+            _user: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::convert::Infallible;
+    use tower_layer::Layer;
+
+    #[derive(Debug, Clone)]
+    struct User(Vec<&'static str>);
+
+    impl Authorize for User {
+        fn has_permission(&self, permission: &str) -> bool {
+            self.0.contains(&permission)
+        }
+    }
+
+    #[tokio::test]
+    async fn permission() {
+        let service = PermissionLayer::<User>::new("admin").layer(tower::service_fn(
+            |_: Request<String>| async move { Ok::<_, Infallible>(Response::new(String::new())) },
+        ));
+        let request = |user: Option<User>| {
+            let mut req = testing::request(None);
+            if let Some(user) = user {
+                req.extensions_mut().insert(user);
+            }
+            req
+        };
+
+        let res = testing::call(service.clone(), request(Some(User(vec!["admin"])))).await;
+        assert_eq!(StatusCode::OK, res.status());
+        let res = testing::call(service.clone(), request(Some(User(vec!["editor"])))).await;
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+        let res = testing::call(service, request(None)).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+    }
+}
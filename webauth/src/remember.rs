@@ -0,0 +1,562 @@
+use crate::auth::SESSION_USER_KEY;
+use crate::secret::Secret;
+use crate::session::{CookieConfig, CookieLifetime, Session};
+use crate::store::{Detachable, Error, Expirable, Identifiable, Store};
+use http::{Request, Response};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+use tower_cookies::{
+    cookie::{time, Expiration},
+    Cookie, Cookies,
+};
+use tower_service::Service;
+use uuid::Uuid;
+
+/// Name of the remember-me cookie, unless configured otherwise
+pub const DEFAULT_COOKIE_NAME: &str = "remember_me";
+
+/// A remember-me token, letting a user log back in once its session is gone.
+///
+/// The cookie holds the `selector`, to find the token, and a secret
+/// validator of which only the hash is stored: a leak of the store does not
+/// give usable cookies. Tokens are single use, a new one is issued each time
+/// a session is restored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RememberMeToken<Uid> {
+    pub selector: Uuid,
+    /// SHA-256 of the validator, encoded in base64
+    pub validator_hash: String,
+    pub user_uid: Uid,
+    pub expires_at: SystemTime,
+}
+
+impl<Uid> Identifiable for RememberMeToken<Uid> {
+    type Uid = Uuid;
+
+    fn uid(&self) -> Self::Uid {
+        self.selector
+    }
+}
+
+impl<Uid: Clone> Detachable for RememberMeToken<Uid> {}
+
+impl<Uid> Expirable for RememberMeToken<Uid> {
+    fn expires_at(&self) -> Option<SystemTime> {
+        Some(self.expires_at)
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// Issues and checks remember-me tokens, kept in a `Store`.
+///
+/// Handlers find it in the request extensions (see `RememberMeLayer` and
+/// `UserManagerLayer::with_remember_me`): call `remember` on login when the
+/// user asked to be remembered, and `forget` on logout.
+#[derive(Debug, Clone)]
+pub struct RememberMe<S> {
+    store: S,
+    duration: Duration,
+    cookie_name: &'static str,
+    cookie_config: CookieConfig,
+}
+
+impl<S, Uid> RememberMe<S>
+where
+    S: Store<Object = RememberMeToken<Uid>> + Sync,
+    Uid: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// Creates tokens valid for `duration`, stored in `store`
+    pub fn new(store: S, duration: Duration) -> Self {
+        Self {
+            store,
+            duration,
+            cookie_name: DEFAULT_COOKIE_NAME,
+            cookie_config: CookieConfig::default(),
+        }
+    }
+
+    /// Sets the name of the cookie, `DEFAULT_COOKIE_NAME` by default.
+    pub fn with_cookie_name(mut self, cookie_name: &'static str) -> Self {
+        self.cookie_name = cookie_name;
+        self
+    }
+
+    /// Sets the attributes of the cookie (see `CookieConfig`).
+    pub fn with_cookie_config(mut self, config: CookieConfig) -> Self {
+        self.cookie_config = config;
+        self
+    }
+
+    /// Issues a token for the user, sent in the remember-me cookie.
+    pub async fn remember(&self, cookies: &Cookies, user_uid: Uid) -> Result<(), Error> {
+        let secret = Secret::generate()?;
+        let token = RememberMeToken {
+            selector: secret.selector,
+            validator_hash: secret.hash(),
+            user_uid,
+            expires_at: SystemTime::now() + self.duration,
+        };
+        self.store.save(&token).await?;
+
+        cookies.add(self.cookie(secret.to_string()));
+        Ok(())
+    }
+
+    /// Deletes the token of the remember-me cookie, and the cookie.
+    /// The managers call it when a request logs its user out (see
+    /// `auth::logout`), but it has to be called explicitly to forget the
+    /// user while keeping it logged in.
+    pub async fn forget(&self, cookies: &Cookies) -> Result<(), Error> {
+        let Some(cookie) = cookies.get(self.cookie_name) else {
+            return Ok(());
+        };
+        if let Some(secret) = Secret::parse(cookie.value()) {
+            self.store.delete(&secret.selector).await?;
+        }
+        cookies.remove(self.cookie(String::new()));
+        Ok(())
+    }
+
+    /// Logs the user of the remember-me cookie in the session, unless it is
+    /// already authenticated, and rotates the token.
+    /// Failures are logged, the session is left anonymous.
+    /// Returns if the session is authenticated, to be given to `cleanup`.
+    pub(crate) async fn restore(&self, cookies: &Cookies, session: &Session) -> bool {
+        if is_authenticated(session) {
+            return true;
+        }
+        let Some(cookie) = cookies.get(self.cookie_name) else {
+            return false;
+        };
+        let user_uid = match self.check(cookie.value()).await {
+            Ok(Some(user_uid)) => user_uid,
+            Ok(None) => {
+                cookies.remove(self.cookie(String::new()));
+                return false;
+            }
+            Err(err) => {
+                tracing::warn!(err = %err, "unable to check remember-me token");
+                return false;
+            }
+        };
+
+        // Like `auth::login`, against session fixation
+        session.cycle_uid();
+        if let Err(err) = session.insert(SESSION_USER_KEY, &user_uid) {
+            tracing::warn!(err = %err, "unable to restore session");
+            return false;
+        }
+        tracing::debug!(suid = %session.uid(), "session restored from remember-me token");
+        if let Err(err) = self.remember(cookies, user_uid).await {
+            tracing::warn!(err = %err, "unable to rotate remember-me token");
+        }
+        true
+    }
+
+    /// Forgets the remember-me token if the request logged the user out of
+    /// the session, authenticated before the handler.
+    pub(crate) async fn cleanup(&self, cookies: &Cookies, session: &Session, authenticated: bool) {
+        if !authenticated || (is_authenticated(session) && !session.is_invalidated()) {
+            return;
+        }
+        if let Err(err) = self.forget(cookies).await {
+            tracing::warn!(err = %err, "unable to forget remember-me token on logout");
+        }
+    }
+
+    // Returns the user of the token of the cookie if valid, consuming it
+    async fn check(&self, value: &str) -> Result<Option<Uid>, Error> {
+        let Some(secret) = Secret::parse(value) else {
+            tracing::warn!("possible funny business, invalid remember-me cookie");
+            return Ok(None);
+        };
+        let selector = secret.selector;
+        let Some(token) = self.store.load(&selector).await? else {
+            return Ok(None);
+        };
+        // Single use, whatever the outcome
+        self.store.delete(&selector).await?;
+        if !secret.verify(&token.validator_hash) {
+            tracing::warn!(selector = %selector, "possible funny business, invalid remember-me validator");
+            return Ok(None);
+        }
+        Ok(Some(token.user_uid))
+    }
+
+    fn cookie(&self, value: String) -> Cookie<'static> {
+        let config = &self.cookie_config;
+        let mut cookie = Cookie::build((self.cookie_name, value))
+            .secure(config.secure)
+            .http_only(config.http_only)
+            .same_site(config.same_site);
+        if let Some(path) = &config.path {
+            cookie = cookie.path(path.clone());
+        }
+        if let Some(domain) = &config.domain {
+            cookie = cookie.domain(domain.clone());
+        }
+        if config.lifetime != CookieLifetime::MaxAge {
+            let expires_at = SystemTime::now() + self.duration;
+            cookie = cookie.expires(Expiration::DateTime(expires_at.into()));
+        }
+        if config.lifetime != CookieLifetime::Expires {
+            cookie = cookie.max_age(
+                time::Duration::try_from(self.duration)
+                    .expect("remember-me duration fits a time::Duration"),
+            );
+        }
+        cookie.build()
+    }
+
+    // Type-erased `restore` and `cleanup`, for the `UserManager`
+    pub(crate) fn restorer(&self) -> Restorer
+    where
+        S: Clone + Send + 'static,
+    {
+        let remember = self.clone();
+        let restore: Arc<RestoreFn> = Arc::new(move |cookies, session| {
+            let remember = remember.clone();
+            Box::pin(async move { remember.restore(&cookies, &session).await })
+        });
+        let remember = self.clone();
+        let cleanup: Arc<CleanupFn> = Arc::new(move |cookies, session, authenticated| {
+            let remember = remember.clone();
+            Box::pin(async move { remember.cleanup(&cookies, &session, authenticated).await })
+        });
+        Restorer { restore, cleanup }
+    }
+}
+
+fn is_authenticated(session: &Session) -> bool {
+    !matches!(session.get::<serde_json::Value>(SESSION_USER_KEY), Ok(None))
+}
+
+type RestoreFn =
+    dyn Fn(Cookies, Session) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync;
+type CleanupFn =
+    dyn Fn(Cookies, Session, bool) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync;
+
+/// Restores sessions from remember-me tokens, and forgets them on logout,
+/// whatever their store.
+#[derive(Clone)]
+pub(crate) struct Restorer {
+    restore: Arc<RestoreFn>,
+    cleanup: Arc<CleanupFn>,
+}
+
+impl Restorer {
+    pub(crate) async fn restore(&self, cookies: Cookies, session: Session) -> bool {
+        (self.restore)(cookies, session).await
+    }
+
+    pub(crate) async fn cleanup(&self, cookies: Cookies, session: Session, authenticated: bool) {
+        (self.cleanup)(cookies, session, authenticated).await
+    }
+}
+
+impl std::fmt::Debug for Restorer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Restorer")
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// Logs users back in from their remember-me cookie when their session is
+/// anonymous, and adds the `RememberMe` to the request extensions.
+/// Requests logging the user out also forget its remember-me token.
+#[derive(Debug, Clone)]
+pub struct RememberMeManager<Service, S> {
+    inner: Service,
+    remember: RememberMe<S>,
+}
+
+impl<ReqBody, ResBody, Svc, S, Uid> Service<Request<ReqBody>> for RememberMeManager<Svc, S>
+where
+    Svc: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    Svc::Future: Send,
+    ReqBody: Send + 'static,
+    ResBody: Send,
+    S: Store<Object = RememberMeToken<Uid>> + Clone + Send + Sync + 'static,
+    Uid: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    type Response = Svc::Response;
+    type Error = Svc::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = std::result::Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let remember = self.remember.clone();
+
+        Box::pin(async move {
+            let cookies = req.extensions().get::<Cookies>().cloned();
+            let session = req.extensions().get::<Session>().cloned();
+            let (Some(cookies), Some(session)) = (cookies, session) else {
+                // Lazy loading, or not wrapped by the `SessionManagerLayer`
+                tracing::warn!("no session found, remember-me tokens are ignored");
+                req.extensions_mut().insert(remember);
+                return inner.call(req).await;
+            };
+            let authenticated = remember.restore(&cookies, &session).await;
+            req.extensions_mut().insert(remember.clone());
+            let res = inner.call(req).await?;
+            remember.cleanup(&cookies, &session, authenticated).await;
+            Ok(res)
+        })
+    }
+}
+
+/// Layer of the `RememberMeManager`, to be wrapped by the
+/// `SessionManagerLayer` (without lazy loading).
+#[derive(Debug, Clone)]
+pub struct RememberMeLayer<S>(RememberMe<S>);
+
+impl<S, Uid> RememberMeLayer<S>
+where
+    S: Store<Object = RememberMeToken<Uid>> + Sync,
+    Uid: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// Creates tokens valid for `duration`, stored in `store`
+    pub fn new(store: S, duration: Duration) -> Self {
+        Self(RememberMe::new(store, duration))
+    }
+
+    /// Sets the name of the cookie, `DEFAULT_COOKIE_NAME` by default.
+    pub fn with_cookie_name(self, cookie_name: &'static str) -> Self {
+        Self(self.0.with_cookie_name(cookie_name))
+    }
+
+    /// Sets the attributes of the cookie (see `CookieConfig`).
+    pub fn with_cookie_config(self, config: CookieConfig) -> Self {
+        Self(self.0.with_cookie_config(config))
+    }
+}
+
+impl<Svc, S> tower_layer::Layer<Svc> for RememberMeLayer<S>
+where
+    S: Clone,
+{
+    type Service = RememberMeManager<Svc, S>;
+
+    fn layer(&self, inner: Svc) -> Self::Service {
+        RememberMeManager {
+            inner,
+            remember: self.0.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{SessionManagerLayer, DEFAULT_EXPIRATION};
+    use crate::testing::{self, SpyStore};
+    use std::convert::Infallible;
+    use tower_layer::Layer;
+
+    type Tokens = SpyStore<RememberMeToken<u64>>;
+
+    const DURATION: Duration = Duration::from_secs(30 * 24 * 3600);
+
+    // Session manager, remember-me manager, and a handler returning the user
+    // of the session, remembering user 42 on `/login`
+    fn service(
+        sessions: SpyStore<Session>,
+        tokens: Tokens,
+    ) -> impl tower_service::Service<
+        Request<String>,
+        Response = Response<String>,
+        Error = Infallible,
+        Future = impl Send,
+    > + Clone {
+        let handler = tower::service_fn(|req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().expect("session");
+            if req.uri().path() == "/login" {
+                let cookies = req.extensions().get::<Cookies>().expect("cookies");
+                let remember = req
+                    .extensions()
+                    .get::<RememberMe<Tokens>>()
+                    .expect("remember me");
+                crate::auth::login(session, &User(42)).expect("login");
+                remember.remember(cookies, 42).await.expect("remember");
+            }
+            if req.uri().path() == "/logout" {
+                // What `auth::logout` does
+                let mut session = session.clone();
+                session.clear();
+                session.invalidate();
+            }
+            let user = session.get::<u64>(SESSION_USER_KEY).expect("get");
+            Ok::<_, Infallible>(Response::new(format!("{:?}", user)))
+        });
+        SessionManagerLayer::new(sessions, "uid")
+            .layer(RememberMeLayer::new(tokens, DURATION).layer(handler))
+    }
+
+    #[derive(Debug)]
+    struct User(u64);
+
+    impl Identifiable for User {
+        type Uid = u64;
+
+        fn uid(&self) -> Self::Uid {
+            self.0
+        }
+    }
+
+    // Returns the value of the remember-me cookie set by the response
+    fn remember_cookie(res: &Response<String>) -> Option<String> {
+        testing::set_cookies(res).into_iter().find_map(|cookie| {
+            cookie
+                .strip_prefix(&format!("{}=", DEFAULT_COOKIE_NAME))
+                .map(|cookie| cookie.split(';').next().unwrap_or_default().to_owned())
+        })
+    }
+
+    fn request(path: &str, remember: Option<&str>) -> Request<String> {
+        let cookie = remember.map(|value| format!("{}={}", DEFAULT_COOKIE_NAME, value));
+        let mut req = testing::request(cookie);
+        *req.uri_mut() = path.parse().expect("uri");
+        req
+    }
+
+    #[tokio::test]
+    async fn restore_and_rotate() {
+        let sessions = SpyStore::<Session>::default();
+        let tokens = Tokens::default();
+        let service = service(sessions, tokens.clone());
+
+        let res = testing::call(service.clone(), request("/login", None)).await;
+        assert_eq!("Some(42)", res.body());
+        let cookie = remember_cookie(&res).expect("remember-me cookie");
+        let token = tokens
+            .objects
+            .lock()
+            .expect("poisoned mutex")
+            .values()
+            .next()
+            .cloned();
+        let token = token.expect("token");
+        // Only the hash of the validator is stored
+        assert!(cookie.starts_with(&format!("{}.", token.selector)));
+        assert!(!cookie.contains(&token.validator_hash));
+
+        // Without session, the user is logged back in, with a new token
+        let res = testing::call(service.clone(), request("/", Some(&cookie))).await;
+        assert_eq!("Some(42)", res.body());
+        let rotated = remember_cookie(&res).expect("rotated cookie");
+        assert_ne!(cookie, rotated);
+        assert!(tokens.get(&token.selector).is_none());
+        assert_eq!(1, tokens.objects.lock().expect("poisoned mutex").len());
+
+        // The old token cannot be used again, and its cookie is removed
+        let res = testing::call(service.clone(), request("/", Some(&cookie))).await;
+        assert_eq!("None", res.body());
+        let removed = testing::set_cookies(&res)
+            .into_iter()
+            .find(|cookie| cookie.starts_with(DEFAULT_COOKIE_NAME))
+            .expect("removal cookie");
+        assert!(
+            removed.starts_with(&format!("{}=;", DEFAULT_COOKIE_NAME)),
+            "{}",
+            removed
+        );
+
+        // The new one can
+        let res = testing::call(service, request("/", Some(&rotated))).await;
+        assert_eq!("Some(42)", res.body());
+    }
+
+    #[tokio::test]
+    async fn invalid_validator() {
+        let sessions = SpyStore::<Session>::default();
+        let tokens = Tokens::default();
+        let service = service(sessions, tokens.clone());
+
+        let res = testing::call(service.clone(), request("/login", None)).await;
+        let cookie = remember_cookie(&res).expect("remember-me cookie");
+        let (selector, _) = cookie.split_once('.').expect("selector");
+        let forged = format!("{}.{}", selector, "A".repeat(43));
+
+        // Rejected, and the token is revoked
+        let res = testing::call(service.clone(), request("/", Some(&forged))).await;
+        assert_eq!("None", res.body());
+        let res = testing::call(service.clone(), request("/", Some(&cookie))).await;
+        assert_eq!("None", res.body());
+
+        // Garbage is ignored
+        for garbage in [
+            "",
+            "garbage",
+            "not-a-uuid.AAAA",
+            &format!("{}.%%%", selector),
+        ] {
+            let res = testing::call(service.clone(), request("/", Some(garbage))).await;
+            assert_eq!("None", res.body(), "{}", garbage);
+        }
+    }
+
+    #[tokio::test]
+    async fn logout() {
+        let sessions = SpyStore::<Session>::default();
+        let tokens = Tokens::default();
+        let service = service(sessions, tokens.clone());
+
+        let res = testing::call(service.clone(), request("/login", None)).await;
+        let remember = remember_cookie(&res).expect("remember-me cookie");
+        let cookies = testing::set_cookies(&res)
+            .iter()
+            .map(|cookie| cookie.split(';').next().unwrap_or_default().to_owned())
+            .collect::<Vec<_>>()
+            .join("; ");
+        let mut req = request("/logout", None);
+        req.headers_mut()
+            .insert(http::header::COOKIE, cookies.parse().expect("header"));
+
+        // The token is revoked along with the session
+        let res = testing::call(service.clone(), req).await;
+        assert_eq!("None", res.body());
+        assert!(tokens.objects.lock().expect("poisoned mutex").is_empty());
+        assert_eq!(Some(String::new()), remember_cookie(&res));
+        let res = testing::call(service, request("/", Some(&remember))).await;
+        assert_eq!("None", res.body());
+    }
+
+    #[tokio::test]
+    async fn forget() -> Result<(), Error> {
+        let tokens = Tokens::default();
+        let remember = RememberMe::new(tokens.clone(), DURATION).with_cookie_name("stay");
+        let cookies = Cookies::default();
+        remember.remember(&cookies, 42).await?;
+        assert_eq!(1, tokens.objects.lock().expect("poisoned mutex").len());
+        assert!(cookies.get("stay").is_some());
+
+        remember.forget(&cookies).await?;
+        assert!(tokens.objects.lock().expect("poisoned mutex").is_empty());
+        assert!(cookies.get("stay").is_none());
+
+        // Already authenticated sessions are left alone
+        remember.remember(&cookies, 42).await?;
+        let session = Session::new(DEFAULT_EXPIRATION);
+        session.insert(SESSION_USER_KEY, 7).expect("insert");
+        remember.restore(&cookies, &session).await;
+        assert_eq!(Some(7), session.get::<u64>(SESSION_USER_KEY).expect("get"));
+        assert_eq!(1, tokens.objects.lock().expect("poisoned mutex").len());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+//! Secrets handed to users (remember-me cookies, links sent by email, ...),
+//! made of a public selector to find them in a store and of a random
+//! validator of which only the hash is stored.
+
+// Only `constant_time_eq` is used by the CSRF protection and TOTP alone
+#![cfg_attr(not(any(feature = "remember-me", feature = "token")), allow(dead_code))]
+
+use crate::store::Error;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+// Length (in bytes) of the validators
+const VALIDATOR_LENGTH: usize = 32;
+
+/// A selector and validator pair, as given to the user
+pub(crate) struct Secret {
+    pub selector: Uuid,
+    pub validator: Vec<u8>,
+}
+
+impl Secret {
+    /// Generates a new secret
+    pub fn generate() -> Result<Self, Error> {
+        let mut validator = vec![0; VALIDATOR_LENGTH];
+        getrandom::getrandom(&mut validator).map_err(Error::backend)?;
+        Ok(Self {
+            selector: Uuid::new_v4(),
+            validator,
+        })
+    }
+
+    /// Parses a secret formatted by `to_string`, `None` if malformed
+    pub fn parse(value: &str) -> Option<Self> {
+        let (selector, validator) = value.split_once('.')?;
+        Some(Self {
+            selector: selector.parse().ok()?,
+            validator: URL_SAFE_NO_PAD.decode(validator).ok()?,
+        })
+    }
+
+    /// Returns the hash of the validator, to be stored
+    pub fn hash(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(&self.validator))
+    }
+
+    /// Returns if the validator matches the stored hash, in constant time
+    pub fn verify(&self, hash: &str) -> bool {
+        constant_time_eq(self.hash().as_bytes(), hash.as_bytes())
+    }
+}
+
+/// URL-safe representation, `{selector}.{validator in base64}`
+impl std::fmt::Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{}",
+            self.selector,
+            URL_SAFE_NO_PAD.encode(&self.validator)
+        )
+    }
+}
+
+/// Compares without returning early, so the time taken does not tell how
+/// much of a secret is right
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -1,22 +1,27 @@
-use crate::store::Identifiable;
-use http::{Request, Response};
+use crate::_summary::{SessionSummary, SummaryConfig};
+use crate::auth::AuthBackend;
+use crate::clock::{Clock, MonotonicClock, SystemClock};
+use crate::store::{Detachable, Expirable, Identifiable};
+use http::{Extensions, HeaderName, Request, Response};
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
 use std::{
-    collections::HashMap,
+    any::Any,
+    collections::{BTreeMap, HashMap},
     future::Future,
+    marker::PhantomData,
     pin::Pin,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        Arc, Mutex, MutexGuard, PoisonError,
     },
     task::{Context, Poll},
     time::{Duration, SystemTime},
 };
 use tower_cookies::{
-    cookie::{Expiration, SameSite},
-    Cookie, CookieManager, Cookies,
+    cookie::{time, Expiration, SameSite},
+    Cookie, CookieManager, Cookies, Key,
 };
 use tower_service::Service;
 use uuid::Uuid;
@@ -26,33 +31,308 @@ pub enum Error {
     /// Error while serializing/deserializing
     #[error(transparent)]
     Serde(#[from] serde_json::Error),
+    /// Inserting would make the session hold more keys than allowed
+    #[error("too many keys in the session (max {max})")]
+    TooManyKeys { max: usize },
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
-// Session with a UUIDv4 identifier
-// and a "generic" hash map to store data.
+/// Container for the data stored in a `Session`, keyed by string and
+/// holding JSON values.
+/// Implemented for `HashMap` (the default) and `BTreeMap`, which keeps keys
+/// ordered (e.g. for a stable serialization).
+pub trait SessionData: Default + Send + 'static {
+    /// Returns the value stored under `key`
+    fn get(&self, key: &str) -> Option<&Value>;
+    /// Stores `value` under `key`, returning the replaced value if any
+    fn insert(&mut self, key: String, value: Value) -> Option<Value>;
+    /// Removes the value stored under `key`, returning it if any
+    fn remove(&mut self, key: &str) -> Option<Value>;
+    /// Removes all values
+    fn clear(&mut self);
+    /// Returns the number of values stored
+    fn len(&self) -> usize;
+    /// Returns if no value is stored
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Iterates over the stored keys and values
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, &Value)> + '_>;
+}
+
+impl SessionData for HashMap<String, Value> {
+    fn get(&self, key: &str) -> Option<&Value> {
+        HashMap::get(self, key)
+    }
+
+    fn insert(&mut self, key: String, value: Value) -> Option<Value> {
+        HashMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Value> {
+        HashMap::remove(self, key)
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, &Value)> + '_> {
+        Box::new(HashMap::iter(self))
+    }
+}
+
+impl SessionData for BTreeMap<String, Value> {
+    fn get(&self, key: &str) -> Option<&Value> {
+        BTreeMap::get(self, key)
+    }
+
+    fn insert(&mut self, key: String, value: Value) -> Option<Value> {
+        BTreeMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Value> {
+        BTreeMap::remove(self, key)
+    }
+
+    fn clear(&mut self) {
+        BTreeMap::clear(self)
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, &Value)> + '_> {
+        Box::new(BTreeMap::iter(self))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// A key of the `Session` bound to the type of its value, so reading it
+/// as another type or misspelling it does not compile:
+///
+/// ```
+/// # use webauth::session::{SessionKey, Uuid};
+/// const USER_UID: SessionKey<Uuid> = SessionKey::new("user_uid");
+/// ```
+///
+/// Values are stored like with the untyped accessors, under `name`.
+pub struct SessionKey<T> {
+    name: &'static str,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> SessionKey<T> {
+    /// Creates a key storing its value under `name`
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            _type: PhantomData,
+        }
+    }
+
+    /// Returns the name the value is stored under
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T> Clone for SessionKey<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for SessionKey<T> {}
+
+impl<T> std::fmt::Debug for SessionKey<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SessionKey").field(&self.name).finish()
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+// Session with a UUID identifier (v4 by default, see `SessionIdStrategy`)
+// and a "generic" map to store data.
 // (like the user_uid of the session, ...)
-#[derive(Debug, Clone)]
-pub struct Session {
-    uid: Uuid,
+pub struct Session<D = HashMap<String, Value>>
+where
+    D: SessionData,
+{
+    uid: Arc<Mutex<SessionUid>>,
     expires_at: SystemTime,
-    data: Arc<Mutex<HashMap<String, Value>>>,
+    data: Arc<Mutex<D>>,
     modified: Arc<AtomicBool>,
+    invalidated: Arc<AtomicBool>,
+    accessed: Arc<Mutex<Accessed>>,
+    max_keys: Option<usize>,
+}
+
+// When the session was last accessed, and the value last persisted, so
+// access tracking only writes the session once in a while.
+#[derive(Debug, Clone, Copy, Default)]
+struct Accessed {
+    last: Option<SystemTime>,
+    persisted: Option<SystemTime>,
+}
+
+// Locks a mutex of the session, recovering from poisoning: a handler
+// panicking while holding it must not break every later use of the session.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Only the keys of the data are shown, values can be tokens or personal
+/// information that must not end up in logs.
+impl<D> std::fmt::Debug for Session<D>
+where
+    D: SessionData,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        struct Keys<'a, D>(&'a Mutex<D>);
+
+        impl<D: SessionData> std::fmt::Debug for Keys<'_, D> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                let data = lock(self.0);
+                f.debug_map()
+                    .entries(data.iter().map(|(key, _)| (key, "[redacted]")))
+                    .finish()
+            }
+        }
+
+        f.debug_struct("Session")
+            .field("uid", &self.uid())
+            .field("expires_at", &self.expires_at)
+            .field("data", &Keys(&self.data))
+            .field("modified", &self.is_modified())
+            .field("last_accessed_at", &self.last_accessed_at())
+            .finish()
+    }
+}
+
+// Clones share the same data, no need for `D: Clone`
+impl<D> Clone for Session<D>
+where
+    D: SessionData,
+{
+    fn clone(&self) -> Self {
+        Self {
+            uid: self.uid.clone(),
+            expires_at: self.expires_at,
+            data: self.data.clone(),
+            modified: self.modified.clone(),
+            invalidated: self.invalidated.clone(),
+            accessed: self.accessed.clone(),
+            max_keys: self.max_keys,
+        }
+    }
+}
+
+// The uid is shared between clones of a `Session`, so a handler cycling the
+// uid is seen by the manager saving the session.
+#[derive(Debug, Clone)]
+struct SessionUid {
+    current: Uuid,
+    // The uid the session had before the first cycle since last save, which
+    // must be removed from the store.
+    cycled_from: Option<Uuid>,
+    // How `cycle_uid` generates the next one, not persisted
+    generator: IdGenerator,
+}
+
+/// Generates session uids.
+///
+/// Sessions are identified by a `Uuid` throughout the stores, but its bits
+/// can come from anywhere (e.g. another CSPRNG, or a database sequence).
+/// Uids must be unique, and unpredictable: knowing the uid of a session is
+/// all it takes to use it.
+pub trait SessionIdGenerator: std::fmt::Debug + Send + Sync + 'static {
+    /// Generates a new uid
+    fn generate(&self) -> Uuid;
+}
+
+type IdGenerator = Arc<dyn SessionIdGenerator>;
+
+/// How session uids are generated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SessionIdStrategy {
+    /// Fully random UUIDv4
+    #[default]
+    V4,
+    /// Time-ordered UUIDv7, inserted next to each other in B-tree indexes
+    /// (e.g. the primary key of the sqlx stores) instead of all over them.
+    /// The uid tells when the session was created, and has 74 random bits
+    /// instead of 122, still far out of reach of guessing.
+    V7,
 }
 
+impl SessionIdGenerator for SessionIdStrategy {
+    fn generate(&self) -> Uuid {
+        match self {
+            Self::V4 => Uuid::new_v4(),
+            Self::V7 => Uuid::now_v7(),
+        }
+    }
+}
+
+/// Prefix of the keys of flash messages in the session data
+/// (see `Session::flash`)
+pub const FLASH_PREFIX: &str = "__flash:";
+
 /// Default expiration for a `Session` (one week)
 pub const DEFAULT_EXPIRATION: Duration = Duration::from_secs(60 * 60 * 24 * 7);
 
+/// Expiration of the sessions created by `SessionManagerLayer::secure`
+pub const SECURE_EXPIRATION: Duration = Duration::from_secs(60 * 60 * 12);
+
 impl Session {
     /// Creates a new `Session`, providing when the session will expire.
     pub fn new(expires_in: Duration) -> Self {
+        Self::new_with_data(expires_in, HashMap::default())
+    }
+
+    /// Like `new`, generating its uids (this one and the ones of
+    /// `cycle_uid`) with the given strategy.
+    pub fn new_with_id_strategy(expires_in: Duration, strategy: SessionIdStrategy) -> Self {
+        Self::new_with_id(strategy.generate(), expires_in).with_id_generator(strategy)
+    }
+
+    /// Like `new`, with the given uid (see `SessionIdGenerator`).
+    pub fn new_with_id(uid: Uuid, expires_in: Duration) -> Self {
+        let session = Self::new(expires_in);
+        lock(&session.uid).current = uid;
+        session
+    }
+}
+
+impl<D> Session<D>
+where
+    D: SessionData,
+{
+    /// Creates a new `Session` holding the given data container, providing
+    /// when the session will expire.
+    pub fn new_with_data(expires_in: Duration, data: D) -> Self {
         Self {
-            uid: Uuid::new_v4(),
+            uid: Arc::new(Mutex::new(SessionUid {
+                current: Uuid::new_v4(),
+                cycled_from: None,
+                generator: Arc::new(SessionIdStrategy::V4),
+            })),
             expires_at: SystemTime::now() + expires_in,
-            data: Arc::new(Mutex::new(HashMap::default())),
+            data: Arc::new(Mutex::new(data)),
             // Creating a new session using `new` makes it unsaved/modified
             modified: Arc::new(AtomicBool::new(true)),
+            invalidated: Default::default(),
+            accessed: Default::default(),
+            max_keys: None,
         }
     }
 
@@ -61,39 +341,247 @@ impl Session {
         &self.expires_at
     }
 
+    /// Pushes the expiration back to `extend_by` from now (sliding
+    /// expiration), marking the session modified.
+    /// To avoid a store write on every request, nothing happens unless the
+    /// session would gain more than a tenth of `extend_by`.
+    /// Returns if the session has been extended.
+    pub fn touch(&mut self, extend_by: Duration) -> bool {
+        self.touch_at(SystemTime::now(), extend_by)
+    }
+
+    /// Like `touch`, extending the session from the given time.
+    pub fn touch_at(&mut self, now: SystemTime, extend_by: Duration) -> bool {
+        let expires_at = now + extend_by;
+        let gain = crate::clock::saturating_duration_since(expires_at, self.expires_at);
+        if gain <= extend_by / 10 {
+            return false;
+        }
+        self.set_expires_at(expires_at);
+        true
+    }
+
+    /// Sets when the `Session` expires, marking it modified.
+    pub fn set_expires_at(&mut self, expires_at: SystemTime) {
+        self.expires_at = expires_at;
+        self.modified.store(true, Ordering::Release);
+    }
+
+    /// Returns if the `Session` is expired at the given time.
+    pub fn is_expired_at(&self, now: SystemTime) -> bool {
+        self.expires_at < now
+    }
+
+    /// Returns how long until the `Session` expires, from the given time.
+    /// Returns zero if it already expired.
+    pub fn expires_in_at(&self, now: SystemTime) -> Duration {
+        crate::clock::saturating_duration_since(self.expires_at, now)
+    }
+
     /// Returns if the session is modified
     pub fn is_modified(&self) -> bool {
         self.modified.load(Ordering::Acquire)
     }
 
-    /// Mark the session as saved
+    /// Mark the session as saved, clearing the modified flag.
+    ///
+    /// The `SessionManager` calls it after saving the session at the end of
+    /// the request. Code persisting the session itself mid-request (e.g. a
+    /// streaming handler flushing to the store) should call it after a
+    /// successful write, so the session is not saved again unless modified
+    /// after that.
     pub fn mark_saved(&self) {
+        lock(&self.uid).cycled_from = None;
+        let mut accessed = lock(&self.accessed);
+        accessed.persisted = accessed.last;
+        drop(accessed);
         self.modified.store(false, Ordering::Release)
     }
 
+    /// Ends the session, e.g. on logout: the `SessionManager` deletes it from
+    /// the store and removes its cookie instead of saving it, even if it is
+    /// written to afterwards.
+    pub fn invalidate(&self) {
+        self.invalidated.store(true, Ordering::Release)
+    }
+
+    /// Returns if the session has been invalidated
+    pub fn is_invalidated(&self) -> bool {
+        self.invalidated.load(Ordering::Acquire)
+    }
+
+    /// Returns a copy of the session sharing nothing with it, unlike clones:
+    /// changes made to one are not seen by the other (see `Detachable`).
+    pub fn snapshot(&self) -> Self {
+        let mut data = D::default();
+        for (key, value) in lock(&self.data).iter() {
+            data.insert(key.clone(), value.clone());
+        }
+        Self {
+            uid: Arc::new(Mutex::new(lock(&self.uid).clone())),
+            expires_at: self.expires_at,
+            data: Arc::new(Mutex::new(data)),
+            modified: Arc::new(AtomicBool::new(self.is_modified())),
+            invalidated: Arc::new(AtomicBool::new(self.is_invalidated())),
+            accessed: Arc::new(Mutex::new(*lock(&self.accessed))),
+            max_keys: self.max_keys,
+        }
+    }
+
+    /// Returns when the session was last accessed, if tracked.
+    pub fn last_accessed_at(&self) -> Option<SystemTime> {
+        lock(&self.accessed).last
+    }
+
+    /// Records an access to the session at `now`.
+    /// The access time alone does not need to be persisted on every request:
+    /// the session is only marked modified if the persisted access time is
+    /// older than `threshold`, so read-heavy sessions are written once in a
+    /// while. Returns if the session has been marked modified.
+    pub fn touch_access(&self, now: SystemTime, threshold: Duration) -> bool {
+        let mut accessed = lock(&self.accessed);
+        accessed.last = Some(accessed.last.map_or(now, |last| last.max(now)));
+        let stale = accessed.persisted.is_none_or(|persisted| {
+            crate::clock::saturating_duration_since(now, persisted) > threshold
+        });
+        if stale {
+            self.modified.store(true, Ordering::Release);
+        }
+        stale
+    }
+
     /// Regenerate a new unique identifier for the session.
-    /// This can be useful to keep a session while changing it's unique identifier.
+    /// This can be useful to keep a session while changing it's unique identifier,
+    /// for example on login to prevent session fixation.
+    /// The `SessionManager` deletes the session stored under the identifier
+    /// it was loaded with when saving it (cycling several times in a request
+    /// only leaves that one to delete), and the cookie it sends back always
+    /// carries the identifier the session is saved under.
     /// Returns the replaced Uuid.
-    pub fn cycle_uid(&mut self) -> Uuid {
-        let old_uid = self.uid;
+    pub fn cycle_uid(&self) -> Uuid {
+        let mut uid = lock(&self.uid);
+        let old_uid = uid.current;
 
-        self.uid = Uuid::new_v4();
+        uid.current = uid.generator.generate();
+        uid.cycled_from.get_or_insert(old_uid);
         self.modified.store(true, Ordering::Release);
         old_uid
     }
 
+    /// Returns the identifier the session was stored under before being
+    /// cycled, if it has been cycled since it was last saved.
+    pub fn cycled_from(&self) -> Option<Uuid> {
+        lock(&self.uid).cycled_from
+    }
+
+    /// Limits the number of keys the session can hold, inserting a new key
+    /// past the limit fails with `Error::TooManyKeys`.
+    /// The limit is not persisted, the `SessionManager` sets it on every
+    /// session it loads if configured to.
+    pub fn with_max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = Some(max_keys);
+        self
+    }
+
+    /// Sets how `cycle_uid` generates uids (see `Session::new_with_id` for
+    /// the uid of new sessions).
+    /// The generator is not persisted, the `SessionManager` sets it on every
+    /// session it loads.
+    pub fn with_id_generator(self, generator: impl SessionIdGenerator) -> Self {
+        lock(&self.uid).generator = Arc::new(generator);
+        self
+    }
+
     /// Insert a new data in the session.
     pub fn insert(&self, key: &str, value: impl Serialize) -> Result<()> {
-        let mut map = self.data.lock().expect("poisoned mutex");
-        map.insert(key.to_string(), serde_json::to_value(value)?);
+        let value = serde_json::to_value(value)?;
+        let mut map = lock(&self.data);
+        self.check_max_keys(&*map, std::iter::once(key))?;
+        map.insert(key.to_string(), value);
         self.modified.store(true, Ordering::Release);
         Ok(())
     }
 
+    /// Insert several data in the session, under the same lock.
+    /// Nothing is inserted if one of the values cannot be serialized or if
+    /// there would be too many keys.
+    pub fn extend<K, V>(&self, entries: impl IntoIterator<Item = (K, V)>) -> Result<()>
+    where
+        K: Into<String>,
+        V: Serialize,
+    {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| Ok((key.into(), serde_json::to_value(value)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let mut map = lock(&self.data);
+        self.check_max_keys(&*map, entries.iter().map(|(key, _)| key.as_str()))?;
+        for (key, value) in entries {
+            map.insert(key, value);
+        }
+        self.modified.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    // Checks inserting the given keys does not exceed `max_keys`
+    fn check_max_keys<'a>(&self, data: &D, keys: impl Iterator<Item = &'a str>) -> Result<()> {
+        let Some(max) = self.max_keys else {
+            return Ok(());
+        };
+        let mut new_keys = keys
+            .filter(|key| data.get(key).is_none())
+            .collect::<Vec<_>>();
+        new_keys.sort_unstable();
+        new_keys.dedup();
+        if data.len() + new_keys.len() > max {
+            return Err(Error::TooManyKeys { max });
+        }
+        Ok(())
+    }
+
+    /// Stores `new` under `key` only if the current value is `expected`
+    /// (`None` meaning no value), checking and setting under the same lock.
+    /// Values are compared once serialized to JSON.
+    /// Returns if the value has been swapped, an error if that would add a
+    /// key past `with_max_keys`.
+    pub fn compare_and_swap<T: Serialize>(
+        &self,
+        key: &str,
+        expected: Option<T>,
+        new: T,
+    ) -> Result<bool> {
+        let expected = expected.map(serde_json::to_value).transpose()?;
+        let new = serde_json::to_value(new)?;
+        let mut map = lock(&self.data);
+        if map.get(key) != expected.as_ref() {
+            return Ok(false);
+        }
+        self.check_max_keys(&*map, std::iter::once(key))?;
+        map.insert(key.to_string(), new);
+        self.modified.store(true, Ordering::Release);
+        Ok(true)
+    }
+
+    /// Moves the value stored under `from` to `to` (replacing any value
+    /// there), without deserializing it, e.g. to migrate the session schema.
+    /// Returns if a value was moved, nothing happens if `from` is absent.
+    pub fn rename_key(&self, from: &str, to: &str) -> Result<bool> {
+        let mut map = lock(&self.data);
+        if from == to {
+            return Ok(map.get(from).is_some());
+        }
+        let Some(value) = map.remove(from) else {
+            return Ok(false);
+        };
+        map.insert(to.to_string(), value);
+        self.modified.store(true, Ordering::Release);
+        Ok(true)
+    }
+
     /// Get a value from the data stored in the session.
     /// Data stored must be JSON-serializable.
     pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
-        let map = self.data.lock().expect("poisoned mutex");
+        let map = lock(&self.data);
         map.get(key)
             .cloned()
             .map(serde_json::from_value)
@@ -101,9 +589,47 @@ impl Session {
             .map_err(Into::into)
     }
 
+    /// Get a value from the data stored in the session, like `get`, but a
+    /// value which cannot be deserialized as `T` (e.g. stored before a
+    /// schema change) is handled as absent instead of failing.
+    pub fn get_lenient<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        Ok(self.get(key).unwrap_or_else(|err| {
+            tracing::warn!(err = %err, key = key, "unable to deserialize session value, ignoring it");
+            None
+        }))
+    }
+
+    /// Get a value from the data stored in the session, or the default
+    /// value of `T` if there is none.
+    /// Still fails if a value is stored but cannot be deserialized as `T`.
+    pub fn get_or_default<T: DeserializeOwned + Default>(&self, key: &str) -> Result<T> {
+        self.get(key).map(Option::unwrap_or_default)
+    }
+
+    /// Get a value from the data stored in the session, or else store the
+    /// one computed by `f` and return it, under the same lock: concurrent
+    /// requests all get the same value.
+    /// `f` is called under the lock, it must not use the session.
+    /// Still fails if a value is stored but cannot be deserialized as `T`.
+    pub fn get_or_insert_with<T, F>(&self, key: &str, f: F) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> T,
+    {
+        let mut map = lock(&self.data);
+        if let Some(value) = map.get(key) {
+            return serde_json::from_value(value.clone()).map_err(Into::into);
+        }
+        self.check_max_keys(&*map, std::iter::once(key))?;
+        let value = f();
+        map.insert(key.to_string(), serde_json::to_value(&value)?);
+        self.modified.store(true, Ordering::Release);
+        Ok(value)
+    }
+
     /// Removes an item from the data stored in the session, returning the value if any.
     pub fn remove<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>> {
-        let mut map = self.data.lock().expect("poisoned mutex");
+        let mut map = lock(&self.data);
         let res = map
             .remove(key)
             .map(serde_json::from_value)
@@ -115,18 +641,160 @@ impl Session {
         Ok(res)
     }
 
+    /// Get the value of a typed key (see `SessionKey`).
+    pub fn get_keyed<T: DeserializeOwned>(&self, key: SessionKey<T>) -> Result<Option<T>> {
+        self.get(key.name)
+    }
+
+    /// Insert the value of a typed key (see `SessionKey`).
+    pub fn insert_keyed<T: Serialize>(&self, key: SessionKey<T>, value: &T) -> Result<()> {
+        self.insert(key.name, value)
+    }
+
+    /// Removes the value of a typed key (see `SessionKey`), returning it if any.
+    pub fn remove_keyed<T: DeserializeOwned>(&mut self, key: SessionKey<T>) -> Result<Option<T>> {
+        self.remove(key.name)
+    }
+
+    /// Stores a flash message, for the next request to read it with
+    /// `take_flash` (e.g. "profile saved" before redirecting).
+    /// Flash messages are kept apart from the regular data, their keys are
+    /// prefixed with `FLASH_PREFIX`.
+    pub fn flash(&self, key: &str, value: impl Serialize) -> Result<()> {
+        self.insert(&format!("{}{}", FLASH_PREFIX, key), value)
+    }
+
+    /// Reads and removes a flash message, so it is gone for the next
+    /// requests (the session is marked modified for the removal to persist).
+    /// A message which cannot be deserialized as `T` is kept.
+    pub fn take_flash<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        let key = format!("{}{}", FLASH_PREFIX, key);
+        let mut map = lock(&self.data);
+        let Some(value) = map.get(&key) else {
+            return Ok(None);
+        };
+        let value = serde_json::from_value(value.clone())?;
+        map.remove(&key);
+        self.modified.store(true, Ordering::Release);
+        Ok(Some(value))
+    }
+
     /// Clear all data stored
     pub fn clear(&mut self) {
-        self.data.lock().expect("poisoned mutex").clear();
+        lock(&self.data).clear();
         self.modified.store(true, Ordering::Release);
     }
 }
 
-impl Identifiable for Session {
+/// A snapshot, marked saved
+impl<D> Detachable for Session<D>
+where
+    D: SessionData,
+{
+    fn detach(&self) -> Self {
+        let session = self.snapshot();
+        session.mark_saved();
+        session
+    }
+}
+
+impl<D> Expirable for Session<D>
+where
+    D: SessionData,
+{
+    fn expires_at(&self) -> Option<SystemTime> {
+        Some(self.expires_at)
+    }
+}
+
+impl<D> Identifiable for Session<D>
+where
+    D: SessionData,
+{
     type Uid = Uuid;
 
     fn uid(&self) -> Self::Uid {
-        self.uid
+        lock(&self.uid).current
+    }
+}
+
+/// Serializes the uid, the expiration, the last access time if tracked and
+/// the data, in the order given by the data container.
+impl<D> Serialize for Session<D>
+where
+    D: SessionData,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        struct Data<'a, D>(&'a Mutex<D>);
+
+        impl<D: SessionData> Serialize for Data<'_, D> {
+            fn serialize<S: Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                serializer.collect_map(lock(self.0).iter())
+            }
+        }
+
+        #[derive(Serialize)]
+        #[serde(bound = "")]
+        struct Repr<'a, D: SessionData> {
+            uid: Uuid,
+            expires_at: SystemTime,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            last_accessed_at: Option<SystemTime>,
+            data: Data<'a, D>,
+        }
+
+        Repr {
+            uid: self.uid(),
+            expires_at: self.expires_at,
+            last_accessed_at: self.last_accessed_at(),
+            data: Data(&self.data),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// A deserialized `Session` is considered saved (not modified).
+impl<'de, D> Deserialize<'de> for Session<D>
+where
+    D: SessionData,
+{
+    fn deserialize<De: Deserializer<'de>>(
+        deserializer: De,
+    ) -> std::result::Result<Self, De::Error> {
+        #[derive(Deserialize)]
+        struct Repr {
+            uid: Uuid,
+            expires_at: SystemTime,
+            #[serde(default)]
+            last_accessed_at: Option<SystemTime>,
+            data: serde_json::Map<String, Value>,
+        }
+
+        let repr = Repr::deserialize(deserializer)?;
+        let mut data = D::default();
+        for (key, value) in repr.data {
+            data.insert(key, value);
+        }
+
+        Ok(Self {
+            uid: Arc::new(Mutex::new(SessionUid {
+                current: repr.uid,
+                cycled_from: None,
+                generator: Arc::new(SessionIdStrategy::default()),
+            })),
+            expires_at: repr.expires_at,
+            data: Arc::new(Mutex::new(data)),
+            modified: Arc::new(AtomicBool::new(false)),
+            invalidated: Default::default(),
+            accessed: Arc::new(Mutex::new(Accessed {
+                last: repr.last_accessed_at,
+                persisted: repr.last_accessed_at,
+            })),
+            max_keys: None,
+        })
     }
 }
 
@@ -140,7 +808,24 @@ where
 {
     pub(crate) inner: Service,
     pub(crate) store: Store,
+    pub(crate) read_store: Option<Store>,
     pub(crate) cookie_name: &'static str,
+    pub(crate) summary: Option<SummaryConfig>,
+    pub(crate) uid_validator: Option<UidValidator>,
+    pub(crate) cookie_config: CookieConfig,
+    pub(crate) lazy: bool,
+    pub(crate) access_threshold: Option<Duration>,
+    pub(crate) max_keys: Option<usize>,
+    pub(crate) fallback_header: Option<HeaderName>,
+    pub(crate) cookie_key: Option<Key>,
+    pub(crate) save_failure_policy: SaveFailurePolicy,
+    pub(crate) expiration: Duration,
+    pub(crate) rolling: Option<Duration>,
+    pub(crate) error_response: Option<ErrorResponse>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) extensions: Extensions,
+    pub(crate) async_persist: bool,
+    pub(crate) id_generator: IdGenerator,
 }
 
 /// Implement the `Service` trait for `SessionManager`
@@ -149,7 +834,7 @@ where
     S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
     S::Future: Send,
     ReqBody: Send + 'static,
-    ResBody: Default + Send,
+    ResBody: Default + Send + 'static,
     Store: crate::store::Store<Object = Session> + Clone + Send + 'static,
 {
     type Response = S::Response;
@@ -167,7 +852,27 @@ where
         let clone = self.inner.clone();
         let mut inner = std::mem::replace(&mut self.inner, clone);
         let store = self.store.clone();
+        let read_store = self.read_store.clone().unwrap_or_else(|| store.clone());
         let cookie_name = self.cookie_name;
+        let summary_config = self.summary.clone();
+        let uid_validator = self.uid_validator.clone();
+        let cookie_config = self.cookie_config.clone();
+        let lazy_loading = self.lazy;
+        let access_threshold = self.access_threshold;
+        let load_config = LoadConfig {
+            clock: self.clock.clone(),
+            max_keys: self.max_keys,
+            expiration: self.expiration,
+            rolling: self.rolling,
+            id_generator: self.id_generator.clone(),
+        };
+        let fallback_header = self.fallback_header.clone();
+        let cookie_key = self.cookie_key.clone();
+        let save_failure_policy = self.save_failure_policy;
+        let error_response = self.error_response.clone();
+        let clock = self.clock.clone();
+        let async_persist = self.async_persist;
+        req.extensions_mut().extend(self.extensions.clone());
 
         Box::pin(async move {
             // Start by fetching the cookie storing the session uid.
@@ -177,69 +882,201 @@ where
                 return inner.call(req).await;
             };
 
-            let session_uid = cookies.get(cookie_name).and_then(|cookie| {
-                cookie
-                    .value()
+            // With private cookies, a plaintext uid (set before they were
+            // enabled) is accepted once, and re-issued encrypted.
+            let (cookie_uid, reissue) = match &cookie_key {
+                Some(key) => match cookies.private(key).get(cookie_name) {
+                    Some(cookie) => (Some(cookie.value().to_owned()), false),
+                    None => {
+                        let plaintext = cookies
+                            .get(cookie_name)
+                            .map(|cookie| cookie.value().to_owned());
+                        let reissue = plaintext.is_some();
+                        (plaintext, reissue)
+                    }
+                },
+                None => (
+                    cookies
+                        .get(cookie_name)
+                        .map(|cookie| cookie.value().to_owned()),
+                    false,
+                ),
+            };
+            // Fall back to the configured header if there is no cookie
+            let raw_uid = cookie_uid.or_else(|| {
+                let header = fallback_header.as_ref()?;
+                let value = req.headers().get(header)?.to_str().ok()?;
+                Some(value.trim().to_owned())
+            });
+            let session_uid = raw_uid.and_then(|raw_uid| {
+                raw_uid
                     .parse::<Uuid>()
                     .map_err(|err| {
-                        tracing::warn!(err = %err, uid = cookie.value(), "possible funny business, unable to parse uid");
+                        tracing::warn!(err = %err, uid = raw_uid, "possible funny business, unable to parse uid");
                     })
                     .ok()
-            });
-            // Fetch the session from the uid.
-            // Here, multiple scenarios are possible:
-            // - We don't have a session uid, might mean a new visitor or invalid uid,
-            //   so we generate a new session.
-            // - We have a session uid but we cannot fetch a proper session from it,
-            //   so, again, we generate a new one
-            // - Or we fetch a valid session and everything is fine
-            let session = match session_uid {
-                Some(suid) => {
-                    // Load the session from the store
-                    match store.load(&suid).await {
-                        // Either the session has been deleted or it expired
-                        Ok(None) => Session::new(DEFAULT_EXPIRATION),
-                        Ok(Some(session)) => session,
-                        Err(err) => {
-                            tracing::error!(err = %err, "failed to load session");
-
-                            let mut res = Response::default();
-                            *res.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
-                            return Ok(res);
+                    .filter(|uid| {
+                        let valid = uid_validator.as_ref().is_none_or(|validator| validator.validate(uid));
+                        if !valid {
+                            tracing::warn!(uid = %uid, "possible funny business, uid rejected by validator");
                         }
+                        valid
+                    })
+            });
+            // With lazy loading, the store is only hit if the handler asks
+            // for the session.
+            let (session, lazy) = if lazy_loading {
+                let lazy = LazySession::new(read_store, session_uid, load_config);
+                req.extensions_mut().insert(lazy.clone());
+                (None, Some(lazy))
+            } else {
+                let session = match load_session(read_store, session_uid, load_config).await {
+                    Ok(session) => session,
+                    Err(err) => {
+                        tracing::error!(err = %err, "failed to load session");
+                        return Ok(failure_response(&error_response, SessionFailure::Load(err)));
                     }
-                }
-                None => Session::new(DEFAULT_EXPIRATION),
+                };
+                tracing::trace!(uid = %session.uid(), "session used");
+                req.extensions_mut().insert(session.clone());
+                (Some(session), None)
             };
-
-            tracing::trace!(uid = %session.uid(), "session used");
-            req.extensions_mut().insert(session.clone());
+            // The summary is checked against the loaded session, or the uid
+            // of the cookie if not loaded yet
+            let summary = summary_config.as_ref().map(|config| {
+                config.read(
+                    &cookies,
+                    session
+                        .as_ref()
+                        .map_or(session_uid, |session| Some(session.uid())),
+                )
+            });
+            if let Some(summary) = &summary {
+                req.extensions_mut().insert(summary.clone());
+            }
 
             let res = inner.call(req).await?;
 
-            // Save the session if modified
-            if session.is_modified() {
-                if let Err(err) = store.save(&session).await {
-                    tracing::error!(err = %err, "failed to save session");
+            let session = match (session, lazy) {
+                (Some(session), _) => session,
+                (None, Some(lazy)) => match lazy.loaded() {
+                    Some(session) => session,
+                    // The summary cookie is written along with the session
+                    None if summary.as_ref().is_some_and(SessionSummary::is_modified) => {
+                        match lazy.get().await {
+                            Ok(session) => session,
+                            Err(err) => {
+                                tracing::error!(err = %err, "failed to load session");
+                                return Ok(failure_response(
+                                    &error_response,
+                                    SessionFailure::Load(err),
+                                ));
+                            }
+                        }
+                    }
+                    // Nothing to save if the session has not been loaded
+                    None => return Ok(res),
+                },
+                (None, None) => return Ok(res),
+            };
 
-                    // TODO: return 500
-                    let mut res = Response::default();
-                    *res.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+            // Invalidated, delete the session and its cookies instead of
+            // saving it. Nothing was stored if the request had no uid.
+            if session.is_invalidated() {
+                if session_uid.is_none() {
                     return Ok(res);
                 }
+                for uid in std::iter::once(session.uid()).chain(session.cycled_from()) {
+                    let Err(err) = store.delete(&uid).await else {
+                        continue;
+                    };
+                    tracing::error!(err = %err, uid = %uid, "failed to delete invalidated session");
+                    if save_failure_policy != SaveFailurePolicy::LogAndContinue {
+                        return Ok(failure_response(
+                            &error_response,
+                            SessionFailure::Delete(err),
+                        ));
+                    }
+                }
+                let removal = build_cookie(
+                    cookie_name,
+                    String::new(),
+                    &session,
+                    &cookie_config,
+                    clock.now(),
+                );
+                match &cookie_key {
+                    Some(key) => cookies.private(key).remove(removal),
+                    None => cookies.remove(removal),
+                }
+                if let Some(config) = &summary_config {
+                    cookies.remove(build_cookie(
+                        config.cookie_name,
+                        String::new(),
+                        &session,
+                        &cookie_config,
+                        clock.now(),
+                    ));
+                }
+                return Ok(res);
+            }
+
+            // A new session left untouched is not worth persisting
+            let persisted = session_uid.is_some() || session.is_modified();
+            if let (Some(threshold), true) = (access_threshold, persisted) {
+                session.touch_access(clock.now(), threshold);
+            }
+
+            // Save the session if modified
+            let modified = session.is_modified();
+            let retries = match save_failure_policy {
+                SaveFailurePolicy::Retry(retries) => retries,
+                _ => 0,
+            };
+            #[cfg(feature = "tokio")]
+            if modified && async_persist {
+                let (store, session) = (store.clone(), session.clone());
+                tokio::spawn(async move {
+                    match persist(store, &session, retries).await {
+                        Ok(()) => session.mark_saved(),
+                        Err(failure) => {
+                            tracing::error!(failure = ?failure, uid = %session.uid(), "failed to persist session in the background, changes are lost");
+                        }
+                    }
+                });
+            }
+            if modified && !async_persist {
+                match persist(store, &session, retries).await {
+                    Ok(()) => (),
+                    Err(failure) if save_failure_policy == SaveFailurePolicy::LogAndContinue => {
+                        tracing::warn!(failure = ?failure, uid = %session.uid(), "failed to persist session, continuing");
+                        return Ok(res);
+                    }
+                    Err(failure) => return Ok(failure_response(&error_response, failure)),
+                }
                 // Mark the session as saved so in case of in memory caching
                 // the next time we won't save again.
                 session.mark_saved();
-
+            }
+            if modified || reissue {
                 // Add the cookie to the jar
-                cookies.add(
-                    Cookie::build((cookie_name, session.uid().to_string()))
-                        .secure(true)
-                        .http_only(true)
-                        .same_site(SameSite::None)
-                        .expires(Expiration::DateTime((*session.expires_at()).into()))
-                        .build(),
+                let cookie = build_cookie(
+                    cookie_name,
+                    session.uid().to_string(),
+                    &session,
+                    &cookie_config,
+                    clock.now(),
                 );
+                match &cookie_key {
+                    Some(key) => cookies.private(key).add(cookie),
+                    None => cookies.add(cookie),
+                }
+            }
+
+            if let (Some(config), Some(summary)) = (summary_config, summary) {
+                config.write(&cookies, &summary, &session, |name, value| {
+                    build_cookie(name, value, &session, &cookie_config, clock.now())
+                });
             }
 
             Ok(res)
@@ -247,37 +1084,604 @@ where
     }
 }
 
-// ----------------------------------------------------------------------------
-
-#[derive(Debug, Clone)]
-pub struct SessionManagerLayer<S>
-where
-    S: crate::store::Store<Object = Session>,
-{
-    store: S,
-    cookie_name: &'static str,
-}
-
-impl<Store> SessionManagerLayer<Store>
+// Deletes the session stored under the uid it was cycled from, if any, so it
+// cannot be used anymore, then saves the session (retrying `retries` times).
+async fn persist<Store>(
+    store: Store,
+    session: &Session,
+    retries: usize,
+) -> std::result::Result<(), SessionFailure>
 where
     Store: crate::store::Store<Object = Session>,
 {
-    pub fn new(store: Store, cookie_name: &'static str) -> Self {
-        Self { store, cookie_name }
+    if let Some(old_uid) = session.cycled_from() {
+        if let Err(err) = store.delete(&old_uid).await {
+            tracing::error!(err = %err, uid = %old_uid, "failed to delete cycled session");
+            return Err(SessionFailure::Delete(err));
+        }
+    }
+    let mut saved = store.save(session).await;
+    for attempt in 1..=retries {
+        let Err(err) = &saved else { break };
+        tracing::warn!(err = %err, attempt = attempt, "failed to save session, retrying");
+        saved = store.save(session).await;
     }
+    saved.map_err(|err| {
+        tracing::error!(err = %err, "failed to save session");
+        SessionFailure::Save(err)
+    })
 }
 
-impl<S, Store> tower_layer::Layer<S> for SessionManagerLayer<Store>
-where
-    Store: crate::store::Store<Object = Session> + Clone,
-{
+/// A store operation of the `SessionManager` which failed, making it reply
+/// with an error instead of the handler's response.
+#[derive(Debug)]
+pub enum SessionFailure {
+    /// Loading the session before calling the handler
+    Load(crate::store::Error),
+    /// Saving the session after the handler
+    Save(crate::store::Error),
+    /// Deleting a cycled or invalidated session after the handler
+    Delete(crate::store::Error),
+}
+
+// Builds error responses with a body of type `B`
+type ErrorResponseFn<B> = Box<dyn Fn(SessionFailure) -> Response<B> + Send + Sync>;
+
+/// Builds the responses sent on `SessionFailure`s, set with
+/// `SessionManagerLayer::with_error_response`.
+/// The layer does not know the body type of the service it wraps, so it is
+/// only checked when a failure happens.
+#[derive(Clone)]
+pub struct ErrorResponse(Arc<dyn Any + Send + Sync>);
+
+impl std::fmt::Debug for ErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ErrorResponse")
+    }
+}
+
+// The response sent on failure, a bodyless 500 unless an `ErrorResponse`
+// building a body of the right type is set
+fn failure_response<B>(
+    error_response: &Option<ErrorResponse>,
+    failure: SessionFailure,
+) -> Response<B>
+where
+    B: Default + 'static,
+{
+    let build = error_response
+        .as_ref()
+        .and_then(|error_response| error_response.0.downcast_ref::<ErrorResponseFn<B>>());
+    if let Some(build) = build {
+        return build(failure);
+    }
+    if error_response.is_some() {
+        tracing::warn!(
+            body = std::any::type_name::<B>(),
+            "error response builder does not match the response body type"
+        );
+    }
+    let mut res = Response::default();
+    *res.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+    res
+}
+
+/// What the `SessionManager` does when persisting the session fails at the
+/// end of a request: saving it, or deleting the session it was cycled from
+/// or an invalidated one (`SessionFailure::Save` and `SessionFailure::Delete`).
+/// Loading failures always fail the request, the handler has not run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SaveFailurePolicy {
+    /// Replace the handler's response with a 500
+    #[default]
+    FailRequest,
+    /// Log a warning and return the handler's response, without the session
+    /// cookie. Changes made to the session during the request are lost.
+    /// An invalidated session failing to be deleted still gets its cookie
+    /// removed, but stays usable with a copy of the cookie until it expires.
+    LogAndContinue,
+    /// Retry saving up to the given number of times, then fail the request
+    /// (deletes are not retried)
+    Retry(usize),
+}
+
+// How the `SessionManager` sets up the sessions it loads or creates
+#[derive(Debug, Clone)]
+struct LoadConfig {
+    clock: Arc<dyn Clock>,
+    max_keys: Option<usize>,
+    // Expiration of new sessions
+    expiration: Duration,
+    // Extension of loaded sessions, for sliding expiration
+    rolling: Option<Duration>,
+    id_generator: IdGenerator,
+}
+
+// Fetch the session from the uid.
+// Here, multiple scenarios are possible:
+// - We don't have a session uid, might mean a new visitor or invalid uid,
+//   so we generate a new session.
+// - We have a session uid but we cannot fetch a proper session from it,
+//   so, again, we generate a new one
+// - Or we fetch a valid session and everything is fine
+async fn load_session<Store>(
+    store: Store,
+    session_uid: Option<Uuid>,
+    config: LoadConfig,
+) -> std::result::Result<Session, crate::store::Error>
+where
+    Store: crate::store::Store<Object = Session>,
+{
+    let mut session = match session_uid {
+        // Load the session from the store
+        Some(suid) => match store.load(&suid).await? {
+            // Stores should not return expired sessions, but an expired
+            // session must not be extended in any case
+            Some(mut session) if !session.is_expired_at(config.clock.now()) => {
+                if let Some(extend_by) = config.rolling {
+                    session.touch_at(config.clock.now(), extend_by);
+                }
+                session
+            }
+            // Either the session has been deleted or it expired
+            _ => Session::new_with_id(config.id_generator.generate(), config.expiration),
+        },
+        // No cookie, nothing to load. The new session is neither saved nor
+        // sent to the client unless the handler writes to it.
+        None => {
+            let session = Session::new_with_id(config.id_generator.generate(), config.expiration);
+            session.modified.store(false, Ordering::Release);
+            session
+        }
+    };
+    session.max_keys = config.max_keys;
+    lock(&session.uid).generator = config.id_generator;
+    Ok(session)
+}
+
+type LoadFuture =
+    Pin<Box<dyn Future<Output = std::result::Result<Session, crate::store::Error>> + Send>>;
+
+/// A `Session` loaded from the store on first access, found in the request
+/// extensions instead of the `Session` when lazy loading is enabled.
+/// Requests never accessing it do not hit the store.
+#[derive(Clone)]
+pub struct LazySession {
+    // `Store` is not required to be `Sync`
+    load: Arc<Mutex<Box<dyn Fn() -> LoadFuture + Send>>>,
+    session: Arc<Mutex<Option<Session>>>,
+}
+
+impl LazySession {
+    fn new<Store>(store: Store, session_uid: Option<Uuid>, config: LoadConfig) -> Self
+    where
+        Store: crate::store::Store<Object = Session> + Clone + Send + 'static,
+    {
+        Self {
+            load: Arc::new(Mutex::new(Box::new(move || {
+                let store = store.clone();
+                Box::pin(load_session(store, session_uid, config.clone()))
+            }))),
+            session: Default::default(),
+        }
+    }
+
+    /// Returns the session, loading it from the store on first access.
+    pub async fn get(&self) -> std::result::Result<Session, crate::store::Error> {
+        if let Some(session) = self.loaded() {
+            return Ok(session);
+        }
+        let load = (lock(&self.load))();
+        let session = load.await?;
+        tracing::trace!(uid = %session.uid(), "session used");
+        // Keep the first one loaded if accessed concurrently
+        let mut loaded = lock(&self.session);
+        Ok(loaded.get_or_insert(session).clone())
+    }
+
+    /// Returns the session if it has already been loaded.
+    pub fn loaded(&self) -> Option<Session> {
+        lock(&self.session).clone()
+    }
+}
+
+impl std::fmt::Debug for LazySession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LazySession")
+            .field("session", &self.loaded())
+            .finish()
+    }
+}
+
+/// Checks the session uids received from clients, uids which are rejected
+/// are handled as invalid sessions.
+#[derive(Clone)]
+pub struct UidValidator(Arc<dyn Fn(&Uuid) -> bool + Send + Sync>);
+
+impl UidValidator {
+    /// Creates a validator from the given predicate
+    pub fn new(validator: impl Fn(&Uuid) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(validator))
+    }
+
+    /// Creates a validator accepting only RFC 4122 uids of the given version
+    pub fn version(version: uuid::Version) -> Self {
+        Self::new(move |uid| {
+            uid.get_variant() == uuid::Variant::RFC4122 && uid.get_version() == Some(version)
+        })
+    }
+
+    /// Returns if the uid is valid
+    pub fn validate(&self, uid: &Uuid) -> bool {
+        (self.0)(uid)
+    }
+}
+
+impl std::fmt::Debug for UidValidator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UidValidator")
+    }
+}
+
+/// Which attributes carry the lifetime of the cookies.
+/// `Max-Age` takes precedence when both are present, but some older clients
+/// only honor `Expires`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CookieLifetime {
+    /// Only `Expires`, set to the session `expires_at`
+    Expires,
+    /// Only `Max-Age`, set to the time left before the session expires
+    MaxAge,
+    /// Both `Expires` and `Max-Age`
+    #[default]
+    Both,
+}
+
+/// Attributes of the cookies set by the `SessionManager`.
+/// The defaults are meant for production behind HTTPS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CookieConfig {
+    /// Only send the cookie over HTTPS, `true` by default
+    pub secure: bool,
+    /// Hide the cookie from JavaScript, `true` by default
+    pub http_only: bool,
+    /// `Lax` by default
+    pub same_site: SameSite,
+    /// `/` by default, so the session is shared by the whole site
+    pub path: Option<String>,
+    /// Unset by default, so the cookie is only sent to the host setting it
+    pub domain: Option<String>,
+    /// Which attributes carry the lifetime of the cookie
+    pub lifetime: CookieLifetime,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            secure: true,
+            http_only: true,
+            same_site: SameSite::Lax,
+            path: Some("/".to_owned()),
+            domain: None,
+            lifetime: CookieLifetime::default(),
+        }
+    }
+}
+
+/// Builds a cookie living as long as the session, from `now`
+fn build_cookie(
+    name: &'static str,
+    value: String,
+    session: &Session,
+    config: &CookieConfig,
+    now: SystemTime,
+) -> Cookie<'static> {
+    let mut cookie = Cookie::build((name, value))
+        .secure(config.secure)
+        .http_only(config.http_only)
+        .same_site(config.same_site);
+    if let Some(path) = &config.path {
+        cookie = cookie.path(path.clone());
+    }
+    if let Some(domain) = &config.domain {
+        cookie = cookie.domain(domain.clone());
+    }
+    let lifetime = config.lifetime;
+    if lifetime != CookieLifetime::MaxAge {
+        cookie = cookie.expires(Expiration::DateTime((*session.expires_at()).into()));
+    }
+    if lifetime != CookieLifetime::Expires {
+        let max_age = session.expires_in_at(now);
+        cookie = cookie.max_age(
+            time::Duration::try_from(max_age).expect("session lifetime fits a time::Duration"),
+        );
+    }
+    cookie.build()
+}
+
+// ----------------------------------------------------------------------------
+
+#[derive(Debug, Clone)]
+pub struct SessionManagerLayer<S>
+where
+    S: crate::store::Store<Object = Session>,
+{
+    store: S,
+    read_store: Option<S>,
+    cookie_name: &'static str,
+    summary: Option<SummaryConfig>,
+    uid_validator: Option<UidValidator>,
+    cookie_config: CookieConfig,
+    lazy: bool,
+    access_threshold: Option<Duration>,
+    max_keys: Option<usize>,
+    fallback_header: Option<HeaderName>,
+    cookie_key: Option<Key>,
+    save_failure_policy: SaveFailurePolicy,
+    expiration: Duration,
+    rolling: Option<Duration>,
+    error_response: Option<ErrorResponse>,
+    clock: Arc<dyn Clock>,
+    extensions: Extensions,
+    async_persist: bool,
+    id_generator: IdGenerator,
+}
+
+impl<Store> SessionManagerLayer<Store>
+where
+    Store: crate::store::Store<Object = Session>,
+{
+    pub fn new(store: Store, cookie_name: &'static str) -> Self {
+        Self {
+            store,
+            read_store: None,
+            cookie_name,
+            summary: None,
+            uid_validator: None,
+            cookie_config: CookieConfig::default(),
+            lazy: false,
+            access_threshold: None,
+            max_keys: None,
+            fallback_header: None,
+            cookie_key: None,
+            save_failure_policy: SaveFailurePolicy::default(),
+            expiration: DEFAULT_EXPIRATION,
+            rolling: None,
+            error_response: None,
+            clock: Arc::new(MonotonicClock::new(SystemClock)),
+            extensions: Extensions::new(),
+            async_persist: false,
+            id_generator: Arc::new(SessionIdStrategy::default()),
+        }
+    }
+
+    /// Creates a layer with safer settings than `new`, to be preferred
+    /// unless something specific is needed:
+    /// - the session cookie is encrypted with `key` (private cookies)
+    /// - it is `SameSite=Strict`, so never sent along cross-site requests
+    ///   (which prevents CSRF, but a user following a link from another site
+    ///   arrives logged out)
+    /// - sessions expire after `SECURE_EXPIRATION`
+    ///
+    /// Cookies are `Secure` and `HttpOnly` like with `new`. The other
+    /// builders can still be used to adjust these settings.
+    pub fn secure(store: Store, cookie_name: &'static str, key: Key) -> Self {
+        Self::new(store, cookie_name)
+            .with_private_cookies(key)
+            .with_same_site(SameSite::Strict)
+            .with_expiration(SECURE_EXPIRATION)
+    }
+
+    /// Loads sessions from `read_store` (e.g. a read replica) while saves and
+    /// deletes still go to the main store.
+    ///
+    /// Replication is usually asynchronous: a session saved on one request
+    /// might not be loadable from the replica on the next one, in which case
+    /// a new session is created. Only use this if your replica lag is small
+    /// compared to the time between two requests of the same client.
+    pub fn with_read_store(mut self, read_store: Store) -> Self {
+        self.read_store = Some(read_store);
+        self
+    }
+
+    /// Keeps a `SessionSummary` in a cookie named `cookie_name`, signed with
+    /// `key`. Handlers get it from the request extensions, next to the `Session`.
+    pub fn with_summary(mut self, key: Key, cookie_name: &'static str) -> Self {
+        self.summary = Some(SummaryConfig { key, cookie_name });
+        self
+    }
+
+    /// Rejects session uids not passing the validator, e.g.
+    /// `UidValidator::version(Version::Random)` to only accept UUIDv4.
+    /// A rejected uid is handled like an unknown one: a new session is created.
+    pub fn with_uid_validator(mut self, validator: UidValidator) -> Self {
+        self.uid_validator = Some(validator);
+        self
+    }
+
+    /// Sets which attributes carry the lifetime of the cookies, both
+    /// `Expires` and `Max-Age` by default.
+    pub fn with_cookie_lifetime(mut self, lifetime: CookieLifetime) -> Self {
+        self.cookie_config.lifetime = lifetime;
+        self
+    }
+
+    /// Loads the session from the store only when the handler first accesses
+    /// it, through the `LazySession` found in the request extensions instead
+    /// of the `Session`. Useful when many routes never use the session
+    /// (routes behind a `UserManagerLayer` always load it, for the user).
+    /// A `SessionSummary` is then checked against the session uid of the
+    /// cookie, so handlers only reading it do not load the session. It is
+    /// not known whether the session still exists until it is loaded.
+    pub fn with_lazy_loading(mut self) -> Self {
+        self.lazy = true;
+        self
+    }
+
+    /// Tracks when sessions are accessed (see `Session::last_accessed_at`).
+    /// To avoid a store write on every request, the access time is only
+    /// persisted once it advanced by more than `threshold`.
+    pub fn with_access_tracking(mut self, threshold: Duration) -> Self {
+        self.access_threshold = Some(threshold);
+        self
+    }
+
+    /// Limits the number of keys sessions can hold (see `Session::with_max_keys`).
+    pub fn with_max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = Some(max_keys);
+        self
+    }
+
+    /// Reads the session uid from the given header when the request has no
+    /// session cookie (e.g. behind proxies dropping cookies, or in tests).
+    /// The session cookie is still sent back with `Set-Cookie`.
+    pub fn with_fallback_header(mut self, header: HeaderName) -> Self {
+        self.fallback_header = Some(header);
+        self
+    }
+
+    /// Encrypts the session cookie with `key` (private cookies), so the
+    /// session uid is never exposed to the client.
+    /// The encryption is authenticated: a tampered cookie is handled like an
+    /// invalid uid, without hitting the store.
+    /// Plaintext cookies set before enabling this are accepted once and
+    /// re-issued encrypted.
+    pub fn with_private_cookies(mut self, key: Key) -> Self {
+        self.cookie_key = Some(key);
+        self
+    }
+
+    /// Sets what to do when saving the session fails, the request fails
+    /// with a 500 by default.
+    pub fn with_save_failure_policy(mut self, policy: SaveFailurePolicy) -> Self {
+        self.save_failure_policy = policy;
+        self
+    }
+
+    /// Sets the attributes of the cookies (see `CookieConfig`).
+    pub fn with_cookie_config(mut self, config: CookieConfig) -> Self {
+        self.cookie_config = config;
+        self
+    }
+
+    /// Sets the `SameSite` attribute of the cookies, `Lax` by default.
+    pub fn with_same_site(mut self, same_site: SameSite) -> Self {
+        self.cookie_config.same_site = same_site;
+        self
+    }
+
+    /// Sets how the uids of sessions are generated, UUIDv4 by default.
+    /// Since any `Uuid` is accepted from clients, switching strategy keeps
+    /// existing sessions valid (unless a `UidValidator` rejects them).
+    pub fn with_id_strategy(self, strategy: SessionIdStrategy) -> Self {
+        self.with_id_generator(strategy)
+    }
+
+    /// Generates the uids of sessions with a custom `SessionIdGenerator`,
+    /// like `with_id_strategy`.
+    pub fn with_id_generator(mut self, generator: impl SessionIdGenerator) -> Self {
+        self.id_generator = Arc::new(generator);
+        self
+    }
+
+    /// Sets the expiration of new sessions, `DEFAULT_EXPIRATION` by default.
+    pub fn with_expiration(mut self, expiration: Duration) -> Self {
+        self.expiration = expiration;
+        self
+    }
+
+    /// Enables sliding expiration: each request loading a session pushes its
+    /// expiration back to `extend_by` from now, so active users stay logged
+    /// in (see `Session::touch` for how often it is actually saved).
+    pub fn with_rolling(mut self, extend_by: Duration) -> Self {
+        self.rolling = Some(extend_by);
+        self
+    }
+
+    /// Sets the `Clock` used to check expiration and to track accesses,
+    /// the system clock (guarded against backward steps) by default.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Builds the response sent when the session cannot be loaded or
+    /// persisted, instead of an empty 500.
+    /// `B` must be the response body type of the wrapped service (e.g.
+    /// `axum::body::Body`), otherwise the builder is ignored with a warning.
+    pub fn with_error_response<B, F>(mut self, build: F) -> Self
+    where
+        B: 'static,
+        F: Fn(SessionFailure) -> Response<B> + Send + Sync + 'static,
+    {
+        let build: ErrorResponseFn<B> = Box::new(build);
+        self.error_response = Some(ErrorResponse(Arc::new(build)));
+        self
+    }
+
+    /// Adds the `AuthBackend` to the request extensions, so handlers (e.g.
+    /// a login route) can authenticate users without building it themselves.
+    pub fn with_backend<B>(mut self, backend: B) -> Self
+    where
+        B: AuthBackend + Clone + Send + Sync + 'static,
+    {
+        self.extensions.insert(backend);
+        self
+    }
+
+    // Adds a value to the request extensions (see `with_backend`).
+    #[cfg(feature = "remember-me")]
+    pub(crate) fn with_extension<T: Clone + Send + Sync + 'static>(mut self, value: T) -> Self {
+        self.extensions.insert(value);
+        self
+    }
+
+    /// Saves modified sessions in a background task once the response is
+    /// produced, instead of making the client wait for the store. The
+    /// cookie is still set on the response. Requires a tokio runtime.
+    ///
+    /// This trades consistency for latency:
+    /// - a failed save cannot fail the request anymore, it is only logged
+    ///   (the `SaveFailurePolicy` retries still apply), and the changes made
+    ///   to the session are lost
+    /// - the next request of the client may be handled before the save
+    ///   completes, and see the previous session (or none for a new one)
+    /// - saves still running when the process stops are lost
+    #[cfg(feature = "tokio")]
+    pub fn with_async_persist(mut self, async_persist: bool) -> Self {
+        self.async_persist = async_persist;
+        self
+    }
+}
+
+impl<S, Store> tower_layer::Layer<S> for SessionManagerLayer<Store>
+where
+    Store: crate::store::Store<Object = Session> + Clone,
+{
     type Service = CookieManager<SessionManager<S, Store>>;
 
     fn layer(&self, inner: S) -> Self::Service {
         let manager = SessionManager {
             inner,
             store: self.store.clone(),
+            read_store: self.read_store.clone(),
             cookie_name: self.cookie_name,
+            summary: self.summary.clone(),
+            uid_validator: self.uid_validator.clone(),
+            cookie_config: self.cookie_config.clone(),
+            lazy: self.lazy,
+            access_threshold: self.access_threshold,
+            max_keys: self.max_keys,
+            fallback_header: self.fallback_header.clone(),
+            cookie_key: self.cookie_key.clone(),
+            save_failure_policy: self.save_failure_policy,
+            expiration: self.expiration,
+            rolling: self.rolling,
+            error_response: self.error_response.clone(),
+            clock: self.clock.clone(),
+            extensions: self.extensions.clone(),
+            async_persist: self.async_persist,
+            id_generator: self.id_generator.clone(),
         };
 
         CookieManager::new(manager)
@@ -288,6 +1692,83 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::MockClock;
+    use crate::testing::{self, SpyStore};
+    use std::convert::Infallible;
+    use tower_layer::Layer;
+
+    #[test]
+    fn keyed() -> Result<()> {
+        const USER_UID: SessionKey<Uuid> = SessionKey::new("user_uid");
+        let mut session = Session::new(DEFAULT_EXPIRATION);
+        let uid = Uuid::new_v4();
+
+        assert_eq!(None, session.get_keyed(USER_UID)?);
+        session.insert_keyed(USER_UID, &uid)?;
+        assert_eq!(Some(uid), session.get_keyed(USER_UID)?);
+        // Same representation as the untyped accessors
+        assert_eq!(Some(uid), session.get("user_uid")?);
+        assert_eq!(Some(uid), session.remove_keyed(USER_UID)?);
+        assert_eq!(None, session.get::<Uuid>("user_uid")?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flash() {
+        let store = SpyStore::<Session>::default();
+        let service = SessionManagerLayer::new(store, "uid").layer(tower::service_fn(
+            |req: Request<String>| async move {
+                let session = req.extensions().get::<Session>().expect("session");
+                let body = match req.uri().path() {
+                    "/save" => {
+                        session.flash("notice", "profile saved").expect("flash");
+                        String::new()
+                    }
+                    _ => format!(
+                        "{:?}",
+                        session.take_flash::<String>("notice").expect("take")
+                    ),
+                };
+                Ok::<_, Infallible>(Response::new(body))
+            },
+        ));
+        let request = |path: &str, cookie: Option<String>| {
+            let mut req = testing::request(cookie);
+            *req.uri_mut() = path.parse().expect("uri");
+            req
+        };
+
+        let res = testing::call(service.clone(), request("/save", None)).await;
+        let cookie = testing::set_cookies(&res)[0]
+            .split(';')
+            .next()
+            .expect("cookie")
+            .to_owned();
+        // Kept apart from the regular data
+        let res = testing::call(service.clone(), request("/", Some(cookie.clone()))).await;
+        assert_eq!("Some(\"profile saved\")", res.body());
+        // Gone once taken
+        let res = testing::call(service, request("/", Some(cookie))).await;
+        assert_eq!("None", res.body());
+
+        let session = Session::new(DEFAULT_EXPIRATION);
+        session.flash("notice", 42).expect("flash");
+        assert_eq!(None, session.get::<u32>("notice").expect("get"));
+        // Not lost when taken as the wrong type
+        session.mark_saved();
+        assert!(session.take_flash::<String>("notice").is_err());
+        assert!(!session.is_modified());
+        assert_eq!(Some(42), session.take_flash::<u32>("notice").expect("take"));
+        assert!(session.is_modified());
+        session.flash("notice", 42).expect("flash");
+        assert_eq!(
+            Some(42),
+            session
+                .get::<u32>(&format!("{}notice", FLASH_PREFIX))
+                .expect("get")
+        );
+    }
 
     #[test]
     fn store() -> Result<()> {
@@ -323,13 +1804,26 @@ mod tests {
 
     #[test]
     fn cycle_uid() {
-        let mut session = Session::new(DEFAULT_EXPIRATION);
+        let session = Session::new(DEFAULT_EXPIRATION);
+        session.mark_saved();
+        assert_eq!(None, session.cycled_from());
 
-        let uid = *session.uid();
+        let uid = session.uid();
         let old_uid = session.cycle_uid();
 
         assert_eq!(uid, old_uid);
-        assert_ne!(old_uid, *session.uid());
+        assert_ne!(old_uid, session.uid());
+        assert!(session.is_modified());
+
+        // Clones see the new uid, and cycling again keeps the stored one
+        let clone = session.clone();
+        assert_eq!(session.uid(), clone.uid());
+        clone.cycle_uid();
+        assert_eq!(session.uid(), clone.uid());
+        assert_eq!(Some(uid), session.cycled_from());
+
+        session.mark_saved();
+        assert_eq!(None, session.cycled_from());
     }
 
     #[test]
@@ -358,4 +1852,1134 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn mark_saved() -> Result<()> {
+        let session = Session::new(DEFAULT_EXPIRATION);
+        session.insert("hello", "world")?;
+        assert!(session.is_modified());
+
+        session.mark_saved();
+        assert!(!session.is_modified());
+        // Data is kept
+        assert_eq!(Some("world".to_owned()), session.get("hello")?);
+        assert!(!session.is_modified());
+
+        session.insert("hello", "again")?;
+        assert!(session.is_modified());
+
+        Ok(())
+    }
+
+    #[test]
+    fn debug_redacted() -> Result<()> {
+        let session = Session::new(DEFAULT_EXPIRATION);
+        session.insert("token", "supersecrettoken")?;
+
+        let debug = format!("{:?}", session);
+        assert!(debug.contains("token"), "{}", debug);
+        assert!(!debug.contains("supersecrettoken"), "{}", debug);
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_or_default() -> Result<()> {
+        let session = Session::new(DEFAULT_EXPIRATION);
+
+        assert_eq!(0u64, session.get_or_default::<u64>("counter")?);
+        assert!(!session.get_or_default::<bool>("flag")?);
+
+        session.insert("counter", 42u64)?;
+        assert_eq!(42u64, session.get_or_default::<u64>("counter")?);
+
+        session.insert("flag", "not a bool")?;
+        assert!(session.get_or_default::<bool>("flag").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_or_insert_with() -> Result<()> {
+        let session = Session::new(DEFAULT_EXPIRATION);
+        session.mark_saved();
+
+        let cart: Uuid = session.get_or_insert_with("cart", Uuid::new_v4)?;
+        assert_eq!(Some(cart), session.get("cart")?);
+        assert!(session.is_modified());
+
+        // Already there, `f` is not called and nothing changes
+        session.mark_saved();
+        let again = session.get_or_insert_with("cart", || -> Uuid { unreachable!() })?;
+        assert_eq!(cart, again);
+        assert!(!session.is_modified());
+
+        session.insert("flag", "not a bool")?;
+        assert!(session.get_or_insert_with("flag", || true).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn custom_data() -> Result<()> {
+        let session = Session::new_with_data(DEFAULT_EXPIRATION, BTreeMap::default());
+        session.insert("zeta", 1)?;
+        session.insert("alpha", 2)?;
+        session.insert("mid", 3)?;
+
+        // Keys are serialized in order
+        let json = serde_json::to_string(&session)?;
+        let data = &json[json.find(r#""data""#).expect("data")..];
+        assert_eq!(r#""data":{"alpha":2,"mid":3,"zeta":1}}"#, data);
+        assert_eq!(json, serde_json::to_string(&session)?);
+
+        // And it round-trips
+        let restored: Session<BTreeMap<String, Value>> = serde_json::from_str(&json)?;
+        assert_eq!(session.uid(), restored.uid());
+        assert_eq!(session.expires_at(), restored.expires_at());
+        assert_eq!(Some(2), restored.get("alpha")?);
+        assert!(!restored.is_modified());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn uid_validator() {
+        let store = SpyStore::<Session>::default();
+        let v4 = Session::new(DEFAULT_EXPIRATION);
+        let v1 = testing::session_with_uid(
+            uuid::Builder::from_random_bytes(*Uuid::new_v4().as_bytes())
+                .with_version(uuid::Version::Mac)
+                .into_uuid(),
+        );
+        store.put(v4.clone());
+        store.put(v1.clone());
+
+        let layer = SessionManagerLayer::new(store.clone(), "uid")
+            .with_uid_validator(UidValidator::version(uuid::Version::Random));
+        let service = layer.layer(tower::service_fn(|req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().expect("session");
+            Ok::<_, Infallible>(Response::new(session.uid().to_string()))
+        }));
+
+        let res = testing::call(
+            service.clone(),
+            testing::request(Some(format!("uid={}", v4.uid()))),
+        )
+        .await;
+        assert_eq!(v4.uid().to_string(), *res.body());
+        assert_eq!(1, store.loads());
+
+        // Rejected without hitting the store, a new session is created
+        let res = testing::call(service, testing::request(Some(format!("uid={}", v1.uid())))).await;
+        assert_ne!(v1.uid().to_string(), *res.body());
+        assert_eq!(1, store.loads());
+    }
+
+    #[tokio::test]
+    async fn id_strategy() {
+        let v7 = Some(uuid::Version::SortRand);
+        let session = Session::new_with_id_strategy(DEFAULT_EXPIRATION, SessionIdStrategy::V7);
+        assert_eq!(v7, session.uid().get_version());
+        session.cycle_uid();
+        assert_eq!(v7, session.uid().get_version());
+        // Time-ordered
+        let later = Session::new_with_id_strategy(DEFAULT_EXPIRATION, SessionIdStrategy::V7);
+        assert!(session.uid() < later.uid());
+
+        // A v4 session loaded by a v7 manager keeps its uid until cycled
+        let store = SpyStore::<Session>::default();
+        let v4 = Session::new(DEFAULT_EXPIRATION).uid();
+        store.put(testing::session_with_uid(v4));
+        let layer =
+            SessionManagerLayer::new(store.clone(), "uid").with_id_strategy(SessionIdStrategy::V7);
+        let service = layer.layer(tower::service_fn(|req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().expect("session");
+            let uid = session.uid();
+            if req.uri().path() == "/cycle" {
+                session.cycle_uid();
+            }
+            Ok::<_, Infallible>(Response::new(format!("{} {}", uid, session.uid())))
+        }));
+
+        let res = testing::call(service.clone(), testing::request(None)).await;
+        let (uid, _) = res.body().split_once(' ').expect("uids");
+        assert_eq!(v7, uid.parse::<Uuid>().expect("uid").get_version());
+
+        let mut req = testing::request(Some(format!("uid={}", v4)));
+        *req.uri_mut() = "/cycle".parse().expect("uri");
+        let res = testing::call(service, req).await;
+        let (uid, cycled) = res.body().split_once(' ').expect("uids");
+        assert_eq!(v4.to_string(), uid);
+        assert_eq!(v7, cycled.parse::<Uuid>().expect("uid").get_version());
+    }
+
+    #[tokio::test]
+    async fn id_generator() {
+        // Unique even when generated in a tight loop, v7 uids only share
+        // their millisecond timestamp
+        for strategy in [SessionIdStrategy::V4, SessionIdStrategy::V7] {
+            let uids: std::collections::HashSet<_> =
+                (0..100_000).map(|_| strategy.generate()).collect();
+            assert_eq!(100_000, uids.len(), "{:?}", strategy);
+        }
+
+        #[derive(Debug, Default)]
+        struct Sequence(std::sync::atomic::AtomicU64);
+
+        impl SessionIdGenerator for Sequence {
+            fn generate(&self) -> Uuid {
+                Uuid::from_u64_pair(0, self.0.fetch_add(1, Ordering::SeqCst) + 1)
+            }
+        }
+
+        let layer = SessionManagerLayer::new(SpyStore::<Session>::default(), "uid")
+            .with_id_generator(Sequence::default());
+        let service = layer.layer(tower::service_fn(|req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().expect("session");
+            let uid = session.uid();
+            session.cycle_uid();
+            Ok::<_, Infallible>(Response::new(format!("{} {}", uid, session.uid())))
+        }));
+        let res = testing::call(service, testing::request(None)).await;
+        assert_eq!(
+            format!(
+                "{} {}",
+                Uuid::from_u64_pair(0, 1),
+                Uuid::from_u64_pair(0, 2)
+            ),
+            *res.body()
+        );
+
+        let uid = Uuid::new_v4();
+        assert_eq!(uid, Session::new_with_id(uid, DEFAULT_EXPIRATION).uid());
+    }
+
+    #[tokio::test]
+    async fn read_store() {
+        let write = SpyStore::<Session>::default();
+        let read = SpyStore::<Session>::default();
+
+        let session = Session::new(DEFAULT_EXPIRATION);
+        read.put(session.clone());
+
+        let layer = SessionManagerLayer::new(write.clone(), "uid").with_read_store(read.clone());
+        let service = layer.layer(tower::service_fn(|req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().expect("session");
+            session.insert("hello", "world").expect("insert");
+            Ok::<_, Infallible>(Response::new(String::new()))
+        }));
+
+        let res = testing::call(
+            service,
+            testing::request(Some(format!("uid={}", session.uid()))),
+        )
+        .await;
+        assert_eq!(http::StatusCode::OK, res.status());
+
+        // Loaded from the replica, saved to the primary
+        assert_eq!(1, read.loads());
+        assert_eq!(0, read.saves());
+        assert_eq!(0, write.loads());
+        assert_eq!(1, write.saves());
+        let saved = write.get(&session.uid()).expect("saved session");
+        assert_eq!(Some("world".to_owned()), saved.get("hello").expect("get"));
+    }
+
+    #[tokio::test]
+    async fn cookie_lifetime() {
+        let service = |lifetime| {
+            SessionManagerLayer::new(SpyStore::<Session>::default(), "uid")
+                .with_cookie_lifetime(lifetime)
+                .layer(tower::service_fn(|req: Request<String>| async move {
+                    let session = req.extensions().get::<Session>().expect("session");
+                    session.insert("hello", "world").expect("insert");
+                    let expires_at = session
+                        .expires_at()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .expect("after epoch");
+                    Ok::<_, Infallible>(Response::new(expires_at.as_secs().to_string()))
+                }))
+        };
+        let cookie = |res: &Response<String>| {
+            let set_cookie = testing::set_cookies(res).pop().expect("cookie");
+            Cookie::parse(set_cookie).expect("valid cookie")
+        };
+
+        let res = testing::call(service(CookieLifetime::default()), testing::request(None)).await;
+        let both = cookie(&res);
+        let expires = both
+            .expires_datetime()
+            .expect("Expires is set")
+            .unix_timestamp();
+        assert_eq!(res.body(), &expires.to_string());
+        let max_age = both.max_age().expect("Max-Age is set");
+        assert!(max_age <= time::Duration::try_from(DEFAULT_EXPIRATION).expect("duration"));
+        assert!(
+            max_age
+                > time::Duration::try_from(DEFAULT_EXPIRATION).expect("duration")
+                    - time::Duration::minutes(1)
+        );
+
+        let res = testing::call(service(CookieLifetime::Expires), testing::request(None)).await;
+        let expires = cookie(&res);
+        assert!(expires.expires_datetime().is_some());
+        assert!(expires.max_age().is_none());
+
+        let res = testing::call(service(CookieLifetime::MaxAge), testing::request(None)).await;
+        let max_age = cookie(&res);
+        assert!(max_age.expires().is_none());
+        assert!(max_age.max_age().is_some());
+    }
+
+    #[test]
+    fn compare_and_swap() -> Result<()> {
+        let session = Session::new(DEFAULT_EXPIRATION);
+        session.mark_saved();
+
+        // Absent to present
+        assert!(session.compare_and_swap("form", None, "pending")?);
+        assert_eq!(Some("pending".to_owned()), session.get("form")?);
+        assert!(session.is_modified());
+        session.mark_saved();
+
+        // Successful swap
+        assert!(session.compare_and_swap("form", Some("pending"), "processed")?);
+        assert_eq!(Some("processed".to_owned()), session.get("form")?);
+
+        // Failed swaps, the value is left untouched
+        session.mark_saved();
+        assert!(!session.compare_and_swap("form", Some("pending"), "processed again")?);
+        assert!(!session.compare_and_swap("form", None, "processed again")?);
+        assert_eq!(Some("processed".to_owned()), session.get("form")?);
+        assert!(!session.is_modified());
+
+        Ok(())
+    }
+
+    #[test]
+    fn touch() {
+        let mut session = Session::new(Duration::from_secs(60));
+        session.mark_saved();
+        let expires_at = *session.expires_at();
+
+        assert!(session.touch(Duration::from_secs(3600)));
+        assert!(session.is_modified());
+        assert!(*session.expires_at() > expires_at + Duration::from_secs(3000));
+
+        // Not worth it right after
+        session.mark_saved();
+        let expires_at = *session.expires_at();
+        assert!(!session.touch(Duration::from_secs(3600)));
+        assert!(!session.is_modified());
+        assert_eq!(expires_at, *session.expires_at());
+
+        // Never shortened
+        assert!(!session.touch(Duration::from_secs(60)));
+        assert_eq!(expires_at, *session.expires_at());
+    }
+
+    #[tokio::test]
+    async fn rolling() {
+        let store = SpyStore::<Session>::default();
+        let session = Session::new(Duration::from_secs(60));
+        store.put(session.clone());
+        let service = SessionManagerLayer::new(store.clone(), "uid")
+            .with_rolling(Duration::from_secs(3600))
+            .layer(tower::service_fn(|_: Request<String>| async move {
+                Ok::<_, Infallible>(Response::new(String::new()))
+            }));
+        let cookie = format!("uid={}", session.uid());
+
+        // Extended, saved, and the cookie is sent again with the new lifetime
+        let res = testing::call(service.clone(), testing::request(Some(cookie.clone()))).await;
+        assert_eq!(1, store.saves());
+        let stored = store.get(&session.uid()).expect("stored");
+        assert!(*stored.expires_at() > *session.expires_at() + Duration::from_secs(3000));
+        let set_cookie = testing::set_cookies(&res).pop().expect("cookie");
+        let max_age = Cookie::parse(set_cookie)
+            .expect("valid cookie")
+            .max_age()
+            .expect("Max-Age");
+        assert!(max_age > time::Duration::minutes(50));
+
+        // Right after, nothing to write
+        let res = testing::call(service, testing::request(Some(cookie))).await;
+        assert_eq!(1, store.saves());
+        assert!(testing::set_cookies(&res).is_empty());
+    }
+
+    #[tokio::test]
+    async fn cookie_max_age_clock() {
+        let store = SpyStore::<Session>::default();
+        let clock = MockClock::default();
+        let session = Session::new(Duration::from_secs(60 * 60));
+        store.put(session.clone());
+        let service = SessionManagerLayer::new(store, "uid")
+            .with_clock(clock.clone())
+            .layer(tower::service_fn(|req: Request<String>| async move {
+                let session = req.extensions().get::<Session>().expect("session");
+                session.insert("hello", "world").expect("insert");
+                Ok::<_, Infallible>(Response::new(String::new()))
+            }));
+
+        // Max-Age is the time left at the time of the clock
+        clock.advance(Duration::from_secs(40 * 60));
+        let req = testing::request(Some(format!("uid={}", session.uid())));
+        let res = testing::call(service, req).await;
+        let set_cookie = testing::set_cookies(&res).pop().expect("cookie");
+        let max_age = Cookie::parse(set_cookie)
+            .expect("valid cookie")
+            .max_age()
+            .expect("Max-Age");
+        assert!(max_age <= time::Duration::minutes(20));
+        assert!(max_age > time::Duration::minutes(19));
+    }
+
+    #[tokio::test]
+    async fn rolling_clock() {
+        let store = SpyStore::<Session>::default();
+        let clock = MockClock::default();
+        let session = Session::new(Duration::from_secs(60 * 60));
+        store.put(session.clone());
+        let service = |rolling| {
+            let layer = SessionManagerLayer::new(store.clone(), "uid").with_clock(clock.clone());
+            let layer = match rolling {
+                true => layer.with_rolling(Duration::from_secs(60 * 60)),
+                false => layer,
+            };
+            layer.layer(tower::service_fn(|req: Request<String>| async move {
+                let session = req.extensions().get::<Session>().expect("session");
+                Ok::<_, Infallible>(Response::new(session.uid().to_string()))
+            }))
+        };
+        let request = || testing::request(Some(format!("uid={}", session.uid())));
+
+        // Active every 40 minutes, kept alive past the initial hour
+        clock.advance(Duration::from_secs(40 * 60));
+        let res = testing::call(service(true), request()).await;
+        assert_eq!(session.uid().to_string(), *res.body());
+        clock.advance(Duration::from_secs(40 * 60));
+        let res = testing::call(service(true), request()).await;
+        assert_eq!(session.uid().to_string(), *res.body());
+        assert_eq!(2, store.saves());
+
+        // Once expired, a new session is created instead of extending it
+        clock.advance(Duration::from_secs(2 * 60 * 60));
+        let res = testing::call(service(true), request()).await;
+        assert_ne!(session.uid().to_string(), *res.body());
+
+        // Without rolling, the session expires after an hour
+        clock.set(SystemTime::now());
+        let session = Session::new(Duration::from_secs(60 * 60));
+        store.put(session.clone());
+        let request = || testing::request(Some(format!("uid={}", session.uid())));
+        clock.advance(Duration::from_secs(40 * 60));
+        let res = testing::call(service(false), request()).await;
+        assert_eq!(session.uid().to_string(), *res.body());
+        clock.advance(Duration::from_secs(40 * 60));
+        let res = testing::call(service(false), request()).await;
+        assert_ne!(session.uid().to_string(), *res.body());
+    }
+
+    #[tokio::test]
+    async fn cookie_config() {
+        let service = |config| {
+            SessionManagerLayer::new(SpyStore::<Session>::default(), "uid")
+                .with_cookie_config(config)
+                .layer(tower::service_fn(|req: Request<String>| async move {
+                    let session = req.extensions().get::<Session>().expect("session");
+                    session.insert("hello", "world").expect("insert");
+                    Ok::<_, Infallible>(Response::new(String::new()))
+                }))
+        };
+
+        let res = testing::call(service(CookieConfig::default()), testing::request(None)).await;
+        let set_cookie = testing::set_cookies(&res).pop().expect("cookie");
+        for flag in [
+            "HttpOnly",
+            "Secure",
+            "SameSite=Lax",
+            "Path=/",
+            "Max-Age=",
+            "Expires=",
+        ] {
+            assert!(set_cookie.contains(flag), "{} in {}", flag, set_cookie);
+        }
+        assert!(!set_cookie.contains("Domain"), "{}", set_cookie);
+
+        let config = CookieConfig {
+            secure: false,
+            http_only: false,
+            same_site: SameSite::Strict,
+            path: Some("/app".to_owned()),
+            domain: Some("example.com".to_owned()),
+            lifetime: CookieLifetime::MaxAge,
+        };
+        let res = testing::call(service(config), testing::request(None)).await;
+        let set_cookie = testing::set_cookies(&res).pop().expect("cookie");
+        for flag in [
+            "SameSite=Strict",
+            "Path=/app",
+            "Domain=example.com",
+            "Max-Age=",
+        ] {
+            assert!(set_cookie.contains(flag), "{} in {}", flag, set_cookie);
+        }
+        for flag in ["HttpOnly", "Secure", "Expires"] {
+            assert!(!set_cookie.contains(flag), "{} in {}", flag, set_cookie);
+        }
+    }
+
+    #[tokio::test]
+    async fn secure() {
+        let key = Key::generate();
+        let store = SpyStore::<Session>::default();
+        let service = SessionManagerLayer::secure(store.clone(), "uid", key.clone()).layer(
+            tower::service_fn(|req: Request<String>| async move {
+                let session = req.extensions().get::<Session>().expect("session");
+                session.insert("hello", "world").expect("insert");
+                Ok::<_, Infallible>(Response::new(session.uid().to_string()))
+            }),
+        );
+
+        let res = testing::call(service, testing::request(None)).await;
+        let set_cookie = testing::set_cookies(&res).pop().expect("cookie");
+        let cookie = Cookie::parse(set_cookie).expect("valid cookie");
+        assert_eq!(Some(true), cookie.secure());
+        assert_eq!(Some(true), cookie.http_only());
+        assert_eq!(Some(SameSite::Strict), cookie.same_site());
+        let max_age = cookie.max_age().expect("Max-Age is set");
+        assert!(max_age <= time::Duration::try_from(SECURE_EXPIRATION).expect("duration"));
+        assert!(max_age > time::Duration::hours(11));
+
+        // Encrypted, the uid is not readable without the key
+        assert!(!cookie.value().contains(res.body().as_str()));
+        let jar = tower_cookies::Cookies::default();
+        jar.add(cookie.into_owned());
+        let decrypted = jar.private(&key).get("uid").expect("decrypted");
+        assert_eq!(res.body(), decrypted.value());
+    }
+
+    #[test]
+    fn rename_key() -> Result<()> {
+        let session = Session::new(DEFAULT_EXPIRATION);
+        session.insert("uid", 42)?;
+        session.insert("other", "value")?;
+        session.mark_saved();
+
+        assert!(session.rename_key("uid", "user_uid")?);
+        assert_eq!(None, session.get::<u64>("uid")?);
+        assert_eq!(Some(42), session.get::<u64>("user_uid")?);
+        assert_eq!(Some("value".to_owned()), session.get("other")?);
+        assert!(session.is_modified());
+
+        // Absent key, nothing happens
+        session.mark_saved();
+        assert!(!session.rename_key("uid", "user_uid")?);
+        assert_eq!(Some(42), session.get::<u64>("user_uid")?);
+        assert!(!session.is_modified());
+
+        // Replaces the destination
+        assert!(session.rename_key("other", "user_uid")?);
+        assert_eq!(Some("value".to_owned()), session.get("user_uid")?);
+        assert_eq!(1, session.data.lock().expect("poisoned").len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot() -> Result<()> {
+        let session = Session::new(DEFAULT_EXPIRATION);
+        session.insert("key", "value")?;
+        let snapshot = session.snapshot();
+        assert_eq!(session.uid(), snapshot.uid());
+        assert_eq!(session.expires_at(), snapshot.expires_at());
+
+        // Unlike clones, changes are not shared
+        session.insert("key", "changed")?;
+        session.cycle_uid();
+        snapshot.insert("other", 42)?;
+        assert_eq!(Some("value".to_owned()), snapshot.get("key")?);
+        assert_ne!(session.uid(), snapshot.uid());
+        assert_eq!(None, session.get::<u64>("other")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn poisoned() -> Result<()> {
+        let mut session = Session::new(DEFAULT_EXPIRATION);
+        session.insert("key", "value")?;
+
+        // A handler panics while holding the data lock
+        let data = session.data.clone();
+        let panicked = std::thread::spawn(move || {
+            let _guard = data.lock().expect("poisoned mutex");
+            panic!("handler panicked");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(session.data.is_poisoned());
+
+        // The session is still usable
+        assert_eq!(Some("value".to_owned()), session.get("key")?);
+        session.insert("other", 42)?;
+        assert_eq!(Some(42), session.remove::<u64>("other")?);
+        session.clear();
+        assert_eq!(None, session.get::<String>("key")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_lenient() -> Result<()> {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Cart {
+            items: Vec<String>,
+        }
+
+        let session = Session::new(DEFAULT_EXPIRATION);
+        assert_eq!(None, session.get_lenient::<Cart>("cart")?);
+
+        session.insert("cart", serde_json::json!({ "items": ["book"] }))?;
+        let cart = Cart {
+            items: vec!["book".to_owned()],
+        };
+        assert_eq!(Some(&cart), session.get_lenient::<Cart>("cart")?.as_ref());
+        assert_eq!(Some(cart), session.get::<Cart>("cart")?);
+
+        // Stored with an older schema
+        session.insert("cart", serde_json::json!({ "item": "book" }))?;
+        assert_eq!(None, session.get_lenient::<Cart>("cart")?);
+        assert!(session.get::<Cart>("cart").is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lazy_loading() {
+        let store = SpyStore::<Session>::default();
+        let session = Session::new(DEFAULT_EXPIRATION);
+        store.put(session.clone());
+
+        let layer = SessionManagerLayer::new(store.clone(), "uid").with_lazy_loading();
+        let service = layer.layer(tower::service_fn(|req: Request<String>| async move {
+            assert!(req.extensions().get::<Session>().is_none());
+            let lazy = req.extensions().get::<LazySession>().expect("lazy session");
+            if req.uri().path() == "/health" {
+                return Ok::<_, Infallible>(Response::new(String::new()));
+            }
+            let session = lazy.get().await.expect("load");
+            // Loaded once per request
+            assert_eq!(session.uid(), lazy.get().await.expect("load").uid());
+            session.insert("hello", "world").expect("insert");
+            Ok::<_, Infallible>(Response::new(session.uid().to_string()))
+        }));
+        let request = |path: &str| {
+            let mut req = testing::request(Some(format!("uid={}", session.uid())));
+            *req.uri_mut() = path.parse().expect("uri");
+            req
+        };
+
+        // Never accessed, never loaded
+        let res = testing::call(service.clone(), request("/health")).await;
+        assert_eq!(http::StatusCode::OK, res.status());
+        assert_eq!(0, store.loads());
+        assert_eq!(0, store.saves());
+        assert!(testing::set_cookies(&res).is_empty());
+
+        // Loaded on access, then saved as usual
+        let res = testing::call(service, request("/")).await;
+        assert_eq!(session.uid().to_string(), *res.body());
+        assert_eq!(1, store.loads());
+        assert_eq!(1, store.saves());
+        assert_eq!(1, testing::set_cookies(&res).len());
+    }
+
+    #[test]
+    fn touch_access() {
+        let session = Session::new(DEFAULT_EXPIRATION);
+        session.mark_saved();
+        let threshold = Duration::from_secs(60);
+        let now = SystemTime::now();
+        assert_eq!(None, session.last_accessed_at());
+
+        // Never persisted
+        assert!(session.touch_access(now, threshold));
+        session.mark_saved();
+
+        // Recorded, but not worth a write
+        let later = now + Duration::from_secs(30);
+        assert!(!session.touch_access(later, threshold));
+        assert_eq!(Some(later), session.last_accessed_at());
+        assert!(!session.is_modified());
+
+        // Never goes backward
+        assert!(!session.touch_access(now, threshold));
+        assert_eq!(Some(later), session.last_accessed_at());
+
+        let much_later = now + Duration::from_secs(90);
+        assert!(session.touch_access(much_later, threshold));
+        assert!(session.is_modified());
+    }
+
+    #[tokio::test]
+    async fn access_tracking() {
+        let store = SpyStore::<Session>::default();
+        let session = Session::new(DEFAULT_EXPIRATION);
+        store.put(session.clone());
+
+        let layer = SessionManagerLayer::new(store.clone(), "uid")
+            .with_access_tracking(Duration::from_secs(60));
+        let service = layer.layer(tower::service_fn(|req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().expect("session");
+            let visits = session.get::<u64>("visits").expect("get");
+            Ok::<_, Infallible>(Response::new(format!("{:?}", visits)))
+        }));
+
+        // The first access is persisted, the next ones are not worth a write
+        for _ in 0..10 {
+            let res = testing::call(
+                service.clone(),
+                testing::request(Some(format!("uid={}", session.uid()))),
+            )
+            .await;
+            assert_eq!(http::StatusCode::OK, res.status());
+        }
+        assert_eq!(10, store.loads());
+        assert_eq!(1, store.saves());
+        let saved = store.get(&session.uid()).expect("saved");
+        assert!(saved.last_accessed_at().is_some());
+    }
+
+    #[tokio::test]
+    async fn cycle_uid_in_handler() {
+        let store = SpyStore::<Session>::default();
+        let session = Session::new(DEFAULT_EXPIRATION);
+        session.insert("hello", "world").expect("insert");
+        session.mark_saved();
+        store.put(session.clone());
+        let old_uid = session.uid();
+
+        let layer = SessionManagerLayer::new(store.clone(), "uid");
+        let service = layer.layer(tower::service_fn(|req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().expect("session");
+            // Cycling twice only leaves the original uid to clean up
+            session.cycle_uid();
+            session.cycle_uid();
+            Ok::<_, Infallible>(Response::new(session.uid().to_string()))
+        }));
+
+        let res = testing::call(service, testing::request(Some(format!("uid={}", old_uid)))).await;
+        assert_eq!(http::StatusCode::OK, res.status());
+        let new_uid: Uuid = res.body().parse().expect("uid");
+
+        // The store only holds the session under its new uid
+        assert_eq!(1, store.deletes.load(Ordering::SeqCst));
+        assert!(store.get(&old_uid).is_none());
+        let stored = store.get(&new_uid).expect("saved under the new uid");
+        assert_eq!(Some("world".to_owned()), stored.get("hello").expect("get"));
+        assert_eq!(1, store.objects.lock().expect("poisoned mutex").len());
+
+        // And the cookie matches it
+        let cookies = testing::set_cookies(&res);
+        assert_eq!(1, cookies.len());
+        assert!(
+            cookies[0].starts_with(&format!("uid={};", new_uid)),
+            "{}",
+            cookies[0]
+        );
+    }
+
+    #[test]
+    fn max_keys() -> Result<()> {
+        let session = Session::new(DEFAULT_EXPIRATION).with_max_keys(2);
+        session.insert("a", 1)?;
+        session.insert("b", 2)?;
+        // Replacing a value is fine at the limit
+        session.insert("a", 3)?;
+        assert!(matches!(
+            session.insert("c", 4),
+            Err(Error::TooManyKeys { max: 2 })
+        ));
+        assert_eq!(None, session.get::<u64>("c")?);
+        assert!(matches!(
+            session.compare_and_swap("c", None, 4),
+            Err(Error::TooManyKeys { max: 2 })
+        ));
+        assert_eq!(None, session.get::<u64>("c")?);
+        assert!(session.compare_and_swap("a", Some(3), 5)?);
+
+        // All or nothing
+        let session = Session::new(DEFAULT_EXPIRATION).with_max_keys(2);
+        session.extend([("a", 1), ("a", 2)])?;
+        assert!(matches!(
+            session.extend([("b", 1), ("c", 2)]),
+            Err(Error::TooManyKeys { max: 2 })
+        ));
+        assert_eq!(None, session.get::<u64>("b")?);
+        session.extend([("a", 3), ("b", 4)])?;
+        assert_eq!(Some(3), session.get::<u64>("a")?);
+        assert_eq!(Some(4), session.get::<u64>("b")?);
+
+        // Not persisted
+        let restored: Session = serde_json::from_str(&serde_json::to_string(&session)?)?;
+        restored.insert("c", 5)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fallback_header() {
+        let store = SpyStore::<Session>::default();
+        let session = Session::new(DEFAULT_EXPIRATION);
+        store.put(session.clone());
+        let other = Session::new(DEFAULT_EXPIRATION);
+        store.put(other.clone());
+
+        let layer = SessionManagerLayer::new(store.clone(), "uid")
+            .with_fallback_header(HeaderName::from_static("x-session-uid"));
+        let service = layer.layer(tower::service_fn(|req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().expect("session");
+            Ok::<_, Infallible>(Response::new(session.uid().to_string()))
+        }));
+        let request = |cookie: Option<String>| {
+            let mut req = testing::request(cookie);
+            req.headers_mut().insert(
+                "x-session-uid",
+                session.uid().to_string().parse().expect("header"),
+            );
+            req
+        };
+
+        // Resolved through the header
+        let res = testing::call(service.clone(), request(None)).await;
+        assert_eq!(session.uid().to_string(), *res.body());
+
+        // The cookie wins when present
+        let res = testing::call(service, request(Some(format!("uid={}", other.uid())))).await;
+        assert_eq!(other.uid().to_string(), *res.body());
+    }
+
+    #[tokio::test]
+    async fn private_cookies() {
+        let store = SpyStore::<Session>::default();
+        let key = Key::generate();
+        let layer = SessionManagerLayer::new(store.clone(), "uid").with_private_cookies(key);
+        let service = layer.layer(tower::service_fn(|req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().expect("session");
+            let visits = session.get_or_default::<u64>("visits").expect("get") + 1;
+            session.insert("visits", visits).expect("insert");
+            Ok::<_, Infallible>(Response::new(format!("{} {}", session.uid(), visits)))
+        }));
+        let cookie = |res: &Response<String>| {
+            let set_cookie = testing::set_cookies(res).pop().expect("cookie");
+            set_cookie.split(';').next().expect("cookie").to_owned()
+        };
+
+        // The value on the wire is not the uid
+        let res = testing::call(service.clone(), testing::request(None)).await;
+        let (uid, visits) = res.body().split_once(' ').expect("body");
+        assert_eq!("1", visits);
+        let encrypted = cookie(&res);
+        assert!(!encrypted.contains(uid), "{}", encrypted);
+        assert!(encrypted.parse::<Uuid>().is_err());
+
+        // But resolves server-side
+        let res = testing::call(service.clone(), testing::request(Some(encrypted.clone()))).await;
+        assert_eq!(format!("{} 2", uid), *res.body());
+
+        // Tampering is detected before hitting the store, a new session is
+        // created
+        let (name, value) = encrypted.split_once('=').expect("cookie");
+        let mut tampered = value.to_owned().into_bytes();
+        let middle = tampered.len() / 2;
+        tampered[middle] = if tampered[middle] == b'A' { b'B' } else { b'A' };
+        let tampered = format!("{}={}", name, String::from_utf8(tampered).expect("ascii"));
+        let loads = store.loads();
+        let res = testing::call(service.clone(), testing::request(Some(tampered))).await;
+        assert!(res.body().ends_with(" 1"), "{}", res.body());
+        assert!(!res.body().starts_with(uid));
+        assert_eq!(loads, store.loads());
+
+        // A plaintext uid is accepted, and re-issued encrypted
+        let session = Session::new(DEFAULT_EXPIRATION);
+        store.put(session.clone());
+        let res = testing::call(
+            service.clone(),
+            testing::request(Some(format!("uid={}", session.uid()))),
+        )
+        .await;
+        assert_eq!(format!("{} 1", session.uid()), *res.body());
+        let reissued = cookie(&res);
+        assert!(!reissued.contains(&session.uid().to_string()));
+        let res = testing::call(service, testing::request(Some(reissued))).await;
+        assert_eq!(format!("{} 2", session.uid()), *res.body());
+    }
+
+    #[tokio::test]
+    async fn no_cookie() {
+        let store = SpyStore::<Session>::default();
+        let layer = SessionManagerLayer::new(store.clone(), "uid");
+        let service = layer.layer(tower::service_fn(|req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().expect("session");
+            if req.uri().path() == "/write" {
+                session.insert("hello", "world").expect("insert");
+            }
+            Ok::<_, Infallible>(Response::new(String::new()))
+        }));
+
+        // Anonymous request not touching the session: no load, no save, no cookie
+        let res = testing::call(service.clone(), testing::request(None)).await;
+        assert_eq!(http::StatusCode::OK, res.status());
+        assert_eq!(0, store.loads());
+        assert_eq!(0, store.saves());
+        assert!(testing::set_cookies(&res).is_empty());
+
+        // Writing to it persists it
+        let mut req = testing::request(None);
+        *req.uri_mut() = "/write".parse().expect("uri");
+        let res = testing::call(service, req).await;
+        assert_eq!(0, store.loads());
+        assert_eq!(1, store.saves());
+        assert_eq!(1, testing::set_cookies(&res).len());
+    }
+
+    #[tokio::test]
+    async fn invalidate() {
+        let store = SpyStore::<Session>::default();
+        let service = SessionManagerLayer::new(store.clone(), "uid").layer(tower::service_fn(
+            |req: Request<String>| async move {
+                let session = req.extensions().get::<Session>().expect("session");
+                match req.uri().path() {
+                    "/logout" => session.invalidate(),
+                    // Deletion wins over writes, whatever the order
+                    _ => {
+                        session.insert("before", true).expect("insert");
+                        session.invalidate();
+                        session.insert("after", true).expect("insert");
+                    }
+                }
+                Ok::<_, Infallible>(Response::new(String::new()))
+            },
+        ));
+        let request = |session: &Session, path: &str| {
+            let mut req = testing::request(Some(format!("uid={}", session.uid())));
+            *req.uri_mut() = path.parse().expect("uri");
+            req
+        };
+
+        for path in ["/logout", "/write"] {
+            let session = Session::new(DEFAULT_EXPIRATION);
+            store.put(session.clone());
+            let saves = store.saves();
+
+            let res = testing::call(service.clone(), request(&session, path)).await;
+            assert!(store.get(&session.uid()).is_none(), "{}", path);
+            assert_eq!(saves, store.saves(), "{}", path);
+            let set_cookie = testing::set_cookies(&res).pop().expect("removal cookie");
+            let removal = Cookie::parse(set_cookie).expect("valid cookie");
+            assert_eq!("uid", removal.name());
+            assert_eq!("", removal.value());
+            assert_eq!(Some(time::Duration::ZERO), removal.max_age());
+        }
+
+        // Without a session to end, nothing happens
+        let res = testing::call(service, testing::request(None)).await;
+        assert!(testing::set_cookies(&res).is_empty());
+        assert_eq!(2, store.deletes.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn error_response() {
+        let handler = tower::service_fn(|req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().expect("session");
+            session.insert("hello", "world").expect("insert");
+            Ok::<_, Infallible>(Response::new("handled".to_owned()))
+        });
+        let request = || testing::request(Some(format!("uid={}", Uuid::new_v4())));
+
+        // Empty 500 by default
+        let store = SpyStore::<Session>::default();
+        store.fail_load.store(true, Ordering::SeqCst);
+        let service = SessionManagerLayer::new(store.clone(), "uid").layer(handler);
+        let res = testing::call(service, request()).await;
+        assert_eq!(http::StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        assert_eq!("", res.body());
+
+        let layer = SessionManagerLayer::new(store.clone(), "uid").with_error_response(
+            |failure: SessionFailure| {
+                let mut res = Response::new(match failure {
+                    SessionFailure::Load(_) => "load failed".to_owned(),
+                    SessionFailure::Save(_) => "save failed".to_owned(),
+                    SessionFailure::Delete(_) => "delete failed".to_owned(),
+                });
+                *res.status_mut() = http::StatusCode::SERVICE_UNAVAILABLE;
+                res
+            },
+        );
+        let res = testing::call(layer.layer(handler), request()).await;
+        assert_eq!(http::StatusCode::SERVICE_UNAVAILABLE, res.status());
+        assert_eq!("load failed", res.body());
+
+        store.fail_load.store(false, Ordering::SeqCst);
+        store.fail_save.store(true, Ordering::SeqCst);
+        let res = testing::call(layer.layer(handler), request()).await;
+        assert_eq!(http::StatusCode::SERVICE_UNAVAILABLE, res.status());
+        assert_eq!("save failed", res.body());
+
+        // Another body type, ignored
+        let layer = SessionManagerLayer::new(store.clone(), "uid")
+            .with_error_response(|_| Response::new(Vec::<u8>::new()));
+        let res = testing::call(layer.layer(handler), request()).await;
+        assert_eq!(http::StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        assert_eq!("", res.body());
+    }
+
+    #[tokio::test]
+    async fn saved_not_modified() {
+        // Clones share the modified flag, keep one to look at it after the
+        // response
+        let handled = Arc::new(Mutex::new(None));
+        let kept = handled.clone();
+        let service = SessionManagerLayer::new(SpyStore::<Session>::default(), "uid").layer(
+            tower::service_fn(move |req: Request<String>| {
+                let handled = handled.clone();
+                async move {
+                    let session = req.extensions().get::<Session>().expect("session");
+                    session.insert("hello", "world").expect("insert");
+                    *handled.lock().expect("poisoned mutex") = Some(session.clone());
+                    Ok::<_, Infallible>(Response::new(String::new()))
+                }
+            }),
+        );
+
+        let res = testing::call(service, testing::request(None)).await;
+        assert_eq!(1, testing::set_cookies(&res).len());
+        let session = kept
+            .lock()
+            .expect("poisoned mutex")
+            .take()
+            .expect("handled");
+        assert!(!session.is_modified());
+    }
+
+    #[tokio::test]
+    async fn save_failure_policy() {
+        let call = |store: &SpyStore<Session>, policy| {
+            let service = SessionManagerLayer::new(store.clone(), "uid")
+                .with_save_failure_policy(policy)
+                .layer(tower::service_fn(|req: Request<String>| async move {
+                    let session = req.extensions().get::<Session>().expect("session");
+                    session.insert("hello", "world").expect("insert");
+                    Ok::<_, Infallible>(Response::new("handled".to_owned()))
+                }));
+            testing::call(service, testing::request(None))
+        };
+
+        let store = SpyStore::<Session>::default();
+        store.fail_save.store(true, Ordering::SeqCst);
+        let res = call(&store, SaveFailurePolicy::FailRequest).await;
+        assert_eq!(http::StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        assert_eq!(1, store.saves());
+
+        let store = SpyStore::<Session>::default();
+        store.fail_save.store(true, Ordering::SeqCst);
+        let res = call(&store, SaveFailurePolicy::LogAndContinue).await;
+        assert_eq!(http::StatusCode::OK, res.status());
+        assert_eq!("handled", res.body());
+        assert!(testing::set_cookies(&res).is_empty());
+        assert_eq!(1, store.saves());
+
+        // Always failing, retried then failed
+        let store = SpyStore::<Session>::default();
+        store.fail_save.store(true, Ordering::SeqCst);
+        let res = call(&store, SaveFailurePolicy::Retry(2)).await;
+        assert_eq!(http::StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        assert_eq!(3, store.saves());
+
+        // Transient failure, saved on retry
+        let store = SpyStore::<Session>::default();
+        store.fail_saves.store(1, Ordering::SeqCst);
+        let res = call(&store, SaveFailurePolicy::Retry(2)).await;
+        assert_eq!(http::StatusCode::OK, res.status());
+        assert_eq!(1, testing::set_cookies(&res).len());
+        assert_eq!(2, store.saves());
+        assert_eq!(1, store.objects.lock().expect("poisoned mutex").len());
+    }
+
+    #[tokio::test]
+    async fn delete_failure_policy() {
+        // Cycles the uid of the session, or invalidates it
+        let call = |store: &SpyStore<Session>, policy, invalidate: bool, uid: Uuid| {
+            let service = SessionManagerLayer::new(store.clone(), "uid")
+                .with_save_failure_policy(policy)
+                .layer(tower::service_fn(move |req: Request<String>| async move {
+                    let session = req.extensions().get::<Session>().expect("session");
+                    if invalidate {
+                        session.invalidate();
+                    } else {
+                        session.cycle_uid();
+                    }
+                    Ok::<_, Infallible>(Response::new("handled".to_owned()))
+                }));
+            testing::call(service, testing::request(Some(format!("uid={}", uid))))
+        };
+
+        for invalidate in [false, true] {
+            let store = SpyStore::<Session>::default();
+            let session = Session::new(DEFAULT_EXPIRATION);
+            store.put(session.clone());
+            store.fail_delete.store(true, Ordering::SeqCst);
+
+            let policy = SaveFailurePolicy::FailRequest;
+            let res = call(&store, policy, invalidate, session.uid()).await;
+            assert_eq!(http::StatusCode::INTERNAL_SERVER_ERROR, res.status());
+
+            let policy = SaveFailurePolicy::LogAndContinue;
+            let res = call(&store, policy, invalidate, session.uid()).await;
+            assert_eq!(http::StatusCode::OK, res.status());
+            assert_eq!("handled", res.body());
+            // The cookie of an invalidated session is removed anyway
+            let removed = testing::set_cookies(&res)
+                .iter()
+                .any(|cookie| cookie.starts_with("uid=;"));
+            assert_eq!(invalidate, removed);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_persist() {
+        let call = |store: &SpyStore<Session>| {
+            let service = SessionManagerLayer::new(store.clone(), "uid")
+                .with_async_persist(true)
+                .layer(tower::service_fn(|req: Request<String>| async move {
+                    let session = req.extensions().get::<Session>().expect("session");
+                    session.insert("hello", "world").expect("insert");
+                    Ok::<_, Infallible>(Response::new("handled".to_owned()))
+                }));
+            testing::call(service, testing::request(None))
+        };
+
+        // The single threaded runtime only runs the save once we yield
+        let store = SpyStore::<Session>::default();
+        let res = call(&store).await;
+        assert_eq!(1, testing::set_cookies(&res).len());
+        assert_eq!(0, store.saves());
+        tokio::task::yield_now().await;
+        assert_eq!(1, store.saves());
+        assert_eq!(1, store.objects.lock().expect("poisoned mutex").len());
+
+        // Failures cannot fail the request anymore
+        let store = SpyStore::<Session>::default();
+        store.fail_save.store(true, Ordering::SeqCst);
+        let res = call(&store).await;
+        assert_eq!(http::StatusCode::OK, res.status());
+        assert_eq!(1, testing::set_cookies(&res).len());
+        tokio::task::yield_now().await;
+        assert_eq!(1, store.saves());
+        assert!(store.objects.lock().expect("poisoned mutex").is_empty());
+    }
 }
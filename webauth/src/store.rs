@@ -1,4 +1,6 @@
-use std::{fmt::Display, future::Future};
+use crate::session::Session;
+use std::{fmt::Display, future::Future, time::SystemTime};
+use uuid::Uuid;
 
 #[derive(Debug)]
 pub enum LogicalError {
@@ -22,8 +24,25 @@ impl Display for LogicalError {
 pub enum Error {
     #[error("logical: {0}")]
     Logical(LogicalError),
+    /// The stored data is not what the store expects (missing fields, ...)
     #[error("storage: {0}")]
     Storage(String),
+    /// Failure of the underlying storage (connection, query, I/O, ...)
+    #[error("backend: {0}")]
+    Backend(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// Failure serializing a resource
+    #[error("encode: {0}")]
+    Encode(#[source] serde_json::Error),
+    /// Failure deserializing a stored resource
+    #[error("decode: {0}")]
+    Decode(#[source] serde_json::Error),
+}
+
+impl Error {
+    /// Wraps an error of the underlying storage, to use with `map_err`.
+    pub fn backend(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self::Backend(err.into())
+    }
 }
 
 /// An object that can be identified by a unique identifier.
@@ -46,9 +65,80 @@ where
     }
 }
 
+/// An object which can expire, stores must then handle it as missing.
+pub trait Expirable {
+    /// Returns when the object expires, `None` (the default) if it never does
+    fn expires_at(&self) -> Option<SystemTime> {
+        None
+    }
+}
+
+/// An object that stores keeping objects in memory (e.g. the memory store,
+/// or `WriteBehindStore`) can copy in and out without sharing any state with
+/// the copy: changes made to the caller's object must not reach the stored
+/// one until it is saved again.
+///
+/// Plain values need nothing more than a clone, the default.
+/// `Session` overrides it, its clones sharing their data.
+pub trait Detachable: Clone {
+    /// Returns a copy sharing no state with self, as if saved then loaded
+    /// back from a store
+    fn detach(&self) -> Self {
+        self.clone()
+    }
+}
+
 /// Trait to load, save and delete arbitrary types.
 /// This will be used to manipulate Sessions, and all other types that
 /// could be stored in a store.
+///
+/// # Example
+///
+/// A `Store` of `Session`s usable by the `SessionManagerLayer`:
+///
+/// ```
+/// use std::collections::HashMap;
+/// use std::future::Future;
+/// use std::sync::{Arc, Mutex};
+/// use std::time::{Duration, SystemTime};
+/// use webauth::session::{Session, SessionManagerLayer, Uuid};
+/// use webauth::store::{Error, Identifiable, Store};
+///
+/// #[derive(Clone, Default)]
+/// struct MapStore(Arc<Mutex<HashMap<Uuid, Session>>>);
+///
+/// impl Store for MapStore {
+///     type Object = Session;
+///
+///     fn load(&self, uid: &Uuid) -> impl Future<Output = Result<Option<Session>, Error>> + Send {
+///         let session = self.0.lock().unwrap().get(uid).cloned();
+///         let session = session.filter(|session| !session.is_expired_at(SystemTime::now()));
+///         async move { Ok(session) }
+///     }
+///
+///     fn save(&self, session: &Session) -> impl Future<Output = Result<(), Error>> + Send {
+///         self.0.lock().unwrap().insert(session.uid(), session.clone());
+///         async move { Ok(()) }
+///     }
+///
+///     fn delete(&self, uid: &Uuid) -> impl Future<Output = Result<(), Error>> + Send {
+///         self.0.lock().unwrap().remove(uid);
+///         async move { Ok(()) }
+///     }
+/// }
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let store = MapStore::default();
+/// let session = Session::new(Duration::from_secs(60));
+/// store.save(&session).await?;
+/// assert!(store.load(&session.uid()).await?.is_some());
+/// store.delete(&session.uid()).await?;
+/// assert!(store.load(&session.uid()).await?.is_none());
+///
+/// let layer = SessionManagerLayer::new(store, "uid");
+/// # Ok::<_, Error>(())
+/// # }).unwrap();
+/// ```
 pub trait Store {
     /// The type of the resource itself
     type Object: Identifiable;
@@ -71,4 +161,102 @@ pub trait Store {
         &self,
         _uid: &<Self::Object as Identifiable>::Uid,
     ) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Deletes the expired resources, returning how many were deleted.
+    /// `load` already ignores them, this reclaims their space: run it
+    /// periodically (e.g. from a `tokio::time::interval` task).
+    /// Does nothing by default, for stores expiring resources themselves
+    /// (e.g. with a TTL) or holding resources that never expire.
+    fn purge_expired(&self) -> impl Future<Output = Result<u64, Error>> + Send {
+        async { Ok(0) }
+    }
+}
+
+/// A `Store` that can be emptied entirely, for test teardown or an admin
+/// "reset everything" operation.
+/// This is a separate trait so that wiping a store is never reachable from
+/// code that only requires a `Store`.
+///
+/// The DynamoDB store does not implement it: emptying a table item by item
+/// is slow and costly, recreate the table instead.
+pub trait ClearableStore: Store {
+    /// Deletes every resource `Object` held by the store.
+    fn clear_all(&self) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+/// A `Store` able to list all its resources, for batch operations (e.g.
+/// migrations). Stores holding expirable resources only list valid ones.
+pub trait EnumerableStore: Store {
+    /// Loads every resource `Object` held by the store.
+    fn load_all(&self) -> impl Future<Output = Result<Vec<Self::Object>, Error>> + Send;
+}
+
+/// A `Store` of `Session`s able to find them by user, for "log out
+/// everywhere" or active devices features.
+/// Sessions are matched on the user uid stored under
+/// `auth::SESSION_USER_KEY` by `auth::login`, anonymous sessions are never
+/// returned.
+pub trait SessionStore: Store<Object = Session> {
+    /// Loads every valid `Session` of the given user.
+    fn sessions_for_user(
+        &self,
+        _user_uid: &Uuid,
+    ) -> impl Future<Output = Result<Vec<Session>, Error>> + Send;
+
+    /// Deletes every `Session` of the given user, expired ones included,
+    /// returning how many were deleted (for "log out everywhere", or after
+    /// a password change). Sessions without a user are left untouched.
+    fn delete_by_user(&self, _user_uid: &Uuid) -> impl Future<Output = Result<u64, Error>> + Send;
+}
+
+/// A `Store` keeping a version of each resource, changing every time the
+/// resource is saved (a counter, an ETag, ...).
+/// This allows optimistic concurrency: compare the version loaded with the
+/// current one before acting on a resource.
+pub trait VersionedStore: Store {
+    /// The type of the version
+    type Version: Clone + Eq + Send + Sync + std::fmt::Debug;
+
+    /// Load the resource `Object` along with its current version.
+    /// Behaves like `Store::load` otherwise.
+    fn load_versioned(
+        &self,
+        _uid: &<Self::Object as Identifiable>::Uid,
+    ) -> impl Future<Output = Result<Option<Versioned<Self>>, Error>> + Send;
+    /// Deletes the resource `Object` only if its current version is the
+    /// given one, returning whether it was deleted.
+    /// A resource saved again (or re-created) since the version was loaded
+    /// is left untouched, as is a missing one.
+    fn delete_if(
+        &self,
+        _uid: &<Self::Object as Identifiable>::Uid,
+        _version: &Self::Version,
+    ) -> impl Future<Output = Result<bool, Error>> + Send;
+}
+
+/// A resource loaded from a `VersionedStore`, along with its version
+pub type Versioned<S> = (<S as Store>::Object, <S as VersionedStore>::Version);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn error_source() {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "connection refused");
+        let err = Error::backend(io);
+        assert_eq!("backend: connection refused", err.to_string());
+        let source = err.source().expect("source");
+        assert_eq!(
+            Some(std::io::ErrorKind::ConnectionRefused),
+            source
+                .downcast_ref::<std::io::Error>()
+                .map(std::io::Error::kind)
+        );
+
+        let json = serde_json::from_str::<u64>("nope").expect_err("invalid json");
+        let err = Error::Decode(json);
+        assert!(err.to_string().starts_with("decode: "), "{}", err);
+        assert!(err.source().is_some());
+    }
 }
@@ -0,0 +1,325 @@
+use crate::_session::lock;
+use crate::session::{Error, Session};
+use crate::store::Identifiable;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+use tower_cookies::{Cookie, Cookies, Key};
+use uuid::Uuid;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A small summary of a `Session` (e.g. the user uid and roles), kept
+/// client-side in a signed cookie so common checks do not need the
+/// session's full data from the store.
+///
+/// The summary is bound to the session uid: it is dropped if the session it
+/// was issued for cannot be found anymore, and when the session uid is cycled
+/// (login, logout) unless the summary is written again during that request.
+/// With lazy loading, it is only checked against the session uid of the
+/// cookie until the session is loaded: reading it alone does not hit the
+/// store, but it outlives its session until then (e.g. after
+/// `auth::logout_everywhere`).
+/// It is signed, not encrypted: do not put secrets in it, and keep it small
+/// since it is sent with every request.
+#[derive(Debug, Clone, Default)]
+pub struct SessionSummary {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // The session uid the summary was issued for
+    uid: Option<Uuid>,
+    data: BTreeMap<String, Value>,
+    modified: bool,
+}
+
+// What is stored in the cookie
+#[derive(Serialize, Deserialize)]
+struct Repr {
+    uid: Uuid,
+    data: BTreeMap<String, Value>,
+}
+
+impl SessionSummary {
+    /// Get a value from the summary.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        let inner = lock(&self.inner);
+        inner
+            .data
+            .get(key)
+            .cloned()
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    /// Insert a value in the summary.
+    pub fn insert(&self, key: &str, value: impl Serialize) -> Result<()> {
+        let mut inner = lock(&self.inner);
+        inner
+            .data
+            .insert(key.to_string(), serde_json::to_value(value)?);
+        inner.modified = true;
+        Ok(())
+    }
+
+    /// Removes a value from the summary, returning if there was one.
+    pub fn remove(&self, key: &str) -> bool {
+        let mut inner = lock(&self.inner);
+        let removed = inner.data.remove(key).is_some();
+        inner.modified |= removed;
+        removed
+    }
+
+    /// Clears the summary.
+    pub fn clear(&self) {
+        let mut inner = lock(&self.inner);
+        inner.data.clear();
+        inner.modified = true;
+    }
+
+    // Returns if the summary cookie has to be written
+    pub(crate) fn is_modified(&self) -> bool {
+        lock(&self.inner).modified
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// Where and how the `SessionManager` keeps the `SessionSummary`
+#[derive(Clone)]
+pub(crate) struct SummaryConfig {
+    pub(crate) key: Key,
+    pub(crate) cookie_name: &'static str,
+}
+
+impl std::fmt::Debug for SummaryConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SummaryConfig")
+            .field("key", &"[redacted]")
+            .field("cookie_name", &self.cookie_name)
+            .finish()
+    }
+}
+
+impl SummaryConfig {
+    /// Reads the summary from the cookies, if it has a valid signature and
+    /// was issued for the given session uid.
+    pub(crate) fn read(&self, cookies: &Cookies, session_uid: Option<Uuid>) -> SessionSummary {
+        if cookies.get(self.cookie_name).is_none() {
+            return Default::default();
+        }
+
+        // `None` if the signature is invalid
+        let repr = cookies
+            .signed(&self.key)
+            .get(self.cookie_name)
+            .and_then(|cookie| URL_SAFE_NO_PAD.decode(cookie.value()).ok())
+            .and_then(|json| serde_json::from_slice::<Repr>(&json).ok());
+        let inner = match repr {
+            Some(repr) if Some(repr.uid) == session_uid => Inner {
+                uid: Some(repr.uid),
+                data: repr.data,
+                modified: false,
+            },
+            // Tampered with, unreadable or issued for another session, drop it
+            _ => Inner {
+                uid: None,
+                data: Default::default(),
+                modified: true,
+            },
+        };
+
+        SessionSummary {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// Writes the summary back to the cookies if needed, binding it to the
+    /// given session.
+    pub(crate) fn write(
+        &self,
+        cookies: &Cookies,
+        summary: &SessionSummary,
+        session: &Session,
+        cookie: impl FnOnce(&'static str, String) -> Cookie<'static>,
+    ) {
+        let mut inner = lock(&summary.inner);
+        let uid = session.uid();
+        if inner.uid.is_some_and(|summary_uid| summary_uid != uid) && !inner.modified {
+            // The session has been cycled, but the summary was not written
+            // again: it might not reflect the session anymore.
+            inner.data.clear();
+            inner.modified = true;
+        }
+        if !inner.modified {
+            return;
+        }
+
+        if inner.data.is_empty() {
+            // Even if tampered with, the cookie is useless: remove it
+            if cookies.get(self.cookie_name).is_some() {
+                cookies.remove(cookie(self.cookie_name, String::new()));
+            }
+        } else {
+            let repr = serde_json::to_vec(&Repr {
+                uid,
+                data: inner.data.clone(),
+            })
+            .expect("map of JSON values always serializes");
+            cookies
+                .signed(&self.key)
+                .add(cookie(self.cookie_name, URL_SAFE_NO_PAD.encode(repr)));
+        }
+        inner.uid = Some(uid);
+        inner.modified = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{LazySession, SessionManagerLayer, DEFAULT_EXPIRATION};
+    use crate::testing::{self, SpyStore};
+    use http::{Request, Response};
+    use std::convert::Infallible;
+    use tower_layer::Layer;
+
+    // Extracts the `name=value` part of the cookies
+    fn cookie_header(res: &Response<String>) -> String {
+        testing::set_cookies(res)
+            .iter()
+            .map(|cookie| cookie.split(';').next().expect("cookie").to_owned())
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    #[tokio::test]
+    async fn summary() {
+        let store = SpyStore::<Session>::default();
+        let key = Key::generate();
+        let layer = SessionManagerLayer::new(store.clone(), "uid").with_summary(key, "summary");
+        let service = layer.layer(tower::service_fn(|req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().expect("session");
+            let summary = req.extensions().get::<SessionSummary>().expect("summary");
+            let body = match req.uri().path() {
+                "/login" => {
+                    session.insert("profile", "a lot of data").expect("insert");
+                    summary.insert("roles", ["admin"]).expect("insert");
+                    String::new()
+                }
+                "/logout" => {
+                    session.cycle_uid();
+                    String::new()
+                }
+                _ => format!(
+                    "{:?} {:?}",
+                    summary.get::<Vec<String>>("roles").expect("roles"),
+                    session.get::<String>("profile").expect("profile"),
+                ),
+            };
+            Ok::<_, Infallible>(Response::new(body))
+        }));
+        let request = |path: &str, cookie: &str| {
+            let mut req = testing::request(Some(cookie.to_owned()));
+            *req.uri_mut() = path.parse().expect("uri");
+            req
+        };
+
+        let res = testing::call(service.clone(), testing::request(None)).await;
+        assert_eq!("None None", res.body());
+
+        // Log in, then roles come from the summary cookie, the profile from the store
+        let res = testing::call(service.clone(), request("/login", "")).await;
+        let cookies = cookie_header(&res);
+        let loads = store.loads();
+        let res = testing::call(service.clone(), request("/", &cookies)).await;
+        assert_eq!(r#"Some(["admin"]) Some("a lot of data")"#, res.body());
+        assert_eq!(loads + 1, store.loads());
+
+        // Tampering with the summary invalidates it
+        let tampered = cookies.replace("summary=", "summary=x");
+        let res = testing::call(service.clone(), request("/", &tampered)).await;
+        assert_eq!(r#"None Some("a lot of data")"#, res.body());
+        let removal = testing::set_cookies(&res)
+            .into_iter()
+            .find(|cookie| cookie.starts_with("summary=;"))
+            .expect("removal cookie");
+        // Removed from the path it was set for
+        assert!(removal.contains("Path=/"), "{}", removal);
+
+        // A summary issued for another session is dropped
+        let session = Session::new(DEFAULT_EXPIRATION);
+        store.put(session.clone());
+        let summary = cookies
+            .split("; ")
+            .find(|cookie| cookie.starts_with("summary="))
+            .expect("summary cookie");
+        let other = format!("uid={}; {}", session.uid(), summary);
+        let res = testing::call(service.clone(), request("/", &other)).await;
+        assert_eq!("None None", res.body());
+        assert!(testing::set_cookies(&res)
+            .iter()
+            .any(|cookie| cookie.starts_with("summary=;")));
+
+        // Cycling the session uid without writing the summary drops it
+        let res = testing::call(service.clone(), request("/logout", &cookies)).await;
+        assert!(testing::set_cookies(&res)
+            .iter()
+            .any(|cookie| cookie.starts_with("summary=;")));
+    }
+
+    #[tokio::test]
+    async fn lazy() {
+        let store = SpyStore::<Session>::default();
+        let layer = SessionManagerLayer::new(store.clone(), "uid")
+            .with_lazy_loading()
+            .with_summary(Key::generate(), "summary");
+        let service = layer.layer(tower::service_fn(|req: Request<String>| async move {
+            let summary = req.extensions().get::<SessionSummary>().expect("summary");
+            if req.uri().path() == "/login" {
+                let lazy = req.extensions().get::<LazySession>().expect("lazy session");
+                let session = lazy.get().await.expect("session");
+                session.insert("profile", "a lot of data").expect("insert");
+                summary.insert("roles", ["admin"]).expect("insert");
+            }
+            let roles = summary.get::<Vec<String>>("roles").expect("roles");
+            Ok::<_, Infallible>(Response::new(format!("{:?}", roles)))
+        }));
+        let request = |path: &str, cookie: &str| {
+            let mut req = testing::request(Some(cookie.to_owned()));
+            *req.uri_mut() = path.parse().expect("uri");
+            req
+        };
+
+        let res = testing::call(service.clone(), request("/login", "")).await;
+        let cookies = cookie_header(&res);
+        assert_eq!(1, store.saves());
+
+        // Handlers only reading the summary do not load the session
+        let loads = store.loads();
+        let res = testing::call(service.clone(), request("/", &cookies)).await;
+        assert_eq!(r#"Some(["admin"])"#, res.body());
+        assert_eq!(loads, store.loads());
+        assert!(testing::set_cookies(&res).is_empty());
+
+        // The summary is still checked against the uid of the cookie
+        let summary = cookies
+            .split("; ")
+            .find(|cookie| cookie.starts_with("summary="))
+            .expect("summary cookie");
+        let other = format!("uid={}; {}", uuid::Uuid::new_v4(), summary);
+        let res = testing::call(service.clone(), request("/", &other)).await;
+        assert_eq!("None", res.body());
+        assert!(testing::set_cookies(&res)
+            .iter()
+            .any(|cookie| cookie.starts_with("summary=;")));
+    }
+}
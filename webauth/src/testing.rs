@@ -0,0 +1,283 @@
+//! Helpers shared by the tests of this crate.
+
+use crate::auth::SESSION_USER_KEY;
+use crate::session::{Session, DEFAULT_EXPIRATION};
+use crate::store::{
+    EnumerableStore, Error, Identifiable, SessionStore, Store, Versioned, VersionedStore,
+};
+use http::{header, Request, Response};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// In-memory `Store` counting the calls made to it, and which can be told to fail.
+#[derive(Debug)]
+pub(crate) struct SpyStore<O>
+where
+    O: Identifiable,
+{
+    pub objects: Arc<Mutex<HashMap<O::Uid, O>>>,
+    // Version of each object, bumped on every put
+    versions: Arc<Mutex<HashMap<O::Uid, usize>>>,
+    version: Arc<AtomicUsize>,
+    pub loads: Arc<AtomicUsize>,
+    pub saves: Arc<AtomicUsize>,
+    pub deletes: Arc<AtomicUsize>,
+    pub fail_load: Arc<AtomicBool>,
+    pub fail_save: Arc<AtomicBool>,
+    pub fail_delete: Arc<AtomicBool>,
+    // Number of saves still to fail, on top of `fail_save`
+    pub fail_saves: Arc<AtomicUsize>,
+}
+
+impl<O> Clone for SpyStore<O>
+where
+    O: Identifiable,
+{
+    fn clone(&self) -> Self {
+        Self {
+            objects: self.objects.clone(),
+            versions: self.versions.clone(),
+            version: self.version.clone(),
+            loads: self.loads.clone(),
+            saves: self.saves.clone(),
+            deletes: self.deletes.clone(),
+            fail_load: self.fail_load.clone(),
+            fail_save: self.fail_save.clone(),
+            fail_delete: self.fail_delete.clone(),
+            fail_saves: self.fail_saves.clone(),
+        }
+    }
+}
+
+impl<O> Default for SpyStore<O>
+where
+    O: Identifiable,
+{
+    fn default() -> Self {
+        Self {
+            objects: Default::default(),
+            versions: Default::default(),
+            version: Default::default(),
+            loads: Default::default(),
+            saves: Default::default(),
+            deletes: Default::default(),
+            fail_load: Default::default(),
+            fail_save: Default::default(),
+            fail_delete: Default::default(),
+            fail_saves: Default::default(),
+        }
+    }
+}
+
+impl<O> SpyStore<O>
+where
+    O: Identifiable + Clone,
+    O::Uid: Hash + Eq,
+{
+    /// Inserts an object directly, without counting a save
+    pub fn put(&self, obj: O) {
+        let mut objects = self.objects.lock().expect("poisoned mutex");
+        let version = self.version.fetch_add(1, Ordering::SeqCst);
+        self.versions
+            .lock()
+            .expect("poisoned mutex")
+            .insert(obj.uid(), version);
+        objects.insert(obj.uid(), obj);
+    }
+
+    /// Gets an object directly, without counting a load
+    pub fn get(&self, uid: &O::Uid) -> Option<O> {
+        self.objects
+            .lock()
+            .expect("poisoned mutex")
+            .get(uid)
+            .cloned()
+    }
+
+    pub fn loads(&self) -> usize {
+        self.loads.load(Ordering::SeqCst)
+    }
+
+    pub fn saves(&self) -> usize {
+        self.saves.load(Ordering::SeqCst)
+    }
+}
+
+impl<O> Store for SpyStore<O>
+where
+    O: Identifiable + Clone + Send + 'static,
+    O::Uid: Hash + Eq + Clone + Send + Sync,
+{
+    type Object = O;
+
+    fn load(&self, uid: &O::Uid) -> impl Future<Output = Result<Option<O>, Error>> + Send {
+        self.loads.fetch_add(1, Ordering::SeqCst);
+        let res = if self.fail_load.load(Ordering::SeqCst) {
+            Err(Error::Storage("load failed".to_owned()))
+        } else {
+            Ok(self.get(uid))
+        };
+        async move { res }
+    }
+
+    fn save(&self, obj: &O) -> impl Future<Output = Result<(), Error>> + Send {
+        self.saves.fetch_add(1, Ordering::SeqCst);
+        let transient = self
+            .fail_saves
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok();
+        let res = if transient || self.fail_save.load(Ordering::SeqCst) {
+            Err(Error::Storage("save failed".to_owned()))
+        } else {
+            self.put(obj.clone());
+            Ok(())
+        };
+        async move { res }
+    }
+
+    fn delete(&self, uid: &O::Uid) -> impl Future<Output = Result<(), Error>> + Send {
+        self.deletes.fetch_add(1, Ordering::SeqCst);
+        let res = if self.fail_delete.load(Ordering::SeqCst) {
+            Err(Error::Storage("delete failed".to_owned()))
+        } else {
+            self.objects.lock().expect("poisoned mutex").remove(uid);
+            Ok(())
+        };
+        async move { res }
+    }
+}
+
+impl<O> VersionedStore for SpyStore<O>
+where
+    O: Identifiable + Clone + Send + 'static,
+    O::Uid: Hash + Eq + Clone + Send + Sync,
+{
+    type Version = usize;
+
+    fn load_versioned(
+        &self,
+        uid: &O::Uid,
+    ) -> impl Future<Output = Result<Option<Versioned<Self>>, Error>> + Send {
+        self.loads.fetch_add(1, Ordering::SeqCst);
+        let objects = self.objects.lock().expect("poisoned mutex");
+        let versions = self.versions.lock().expect("poisoned mutex");
+        let res = if self.fail_load.load(Ordering::SeqCst) {
+            Err(Error::Storage("load failed".to_owned()))
+        } else {
+            Ok(objects.get(uid).cloned().zip(versions.get(uid).copied()))
+        };
+        async move { res }
+    }
+
+    fn delete_if(
+        &self,
+        uid: &O::Uid,
+        version: &usize,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        self.deletes.fetch_add(1, Ordering::SeqCst);
+        let mut objects = self.objects.lock().expect("poisoned mutex");
+        let versions = self.versions.lock().expect("poisoned mutex");
+        let deleted = versions.get(uid) == Some(version) && objects.remove(uid).is_some();
+        async move { Ok(deleted) }
+    }
+}
+
+impl<O> EnumerableStore for SpyStore<O>
+where
+    O: Identifiable + Clone + Send + 'static,
+    O::Uid: Hash + Eq + Clone + Send + Sync,
+{
+    fn load_all(&self) -> impl Future<Output = Result<Vec<O>, Error>> + Send {
+        self.loads.fetch_add(1, Ordering::SeqCst);
+        let objects = self
+            .objects
+            .lock()
+            .expect("poisoned mutex")
+            .values()
+            .cloned()
+            .collect();
+        async move { Ok(objects) }
+    }
+}
+
+impl SessionStore for SpyStore<Session> {
+    fn sessions_for_user(
+        &self,
+        user_uid: &uuid::Uuid,
+    ) -> impl Future<Output = Result<Vec<Session>, Error>> + Send {
+        self.loads.fetch_add(1, Ordering::SeqCst);
+        let sessions = self
+            .objects
+            .lock()
+            .expect("poisoned mutex")
+            .values()
+            .filter(|session| {
+                session.get::<uuid::Uuid>(SESSION_USER_KEY).ok().flatten() == Some(*user_uid)
+            })
+            .cloned()
+            .collect();
+        async move { Ok(sessions) }
+    }
+
+    fn delete_by_user(
+        &self,
+        user_uid: &uuid::Uuid,
+    ) -> impl Future<Output = Result<u64, Error>> + Send {
+        self.deletes.fetch_add(1, Ordering::SeqCst);
+        let mut objects = self.objects.lock().expect("poisoned mutex");
+        let before = objects.len();
+        objects.retain(|_, session| {
+            session.get::<uuid::Uuid>(SESSION_USER_KEY).ok().flatten() != Some(*user_uid)
+        });
+        let deleted = (before - objects.len()) as u64;
+        async move { Ok(deleted) }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// Creates a new (unsaved) session with the given uid
+pub(crate) fn session_with_uid(uid: uuid::Uuid) -> Session {
+    let mut json = serde_json::to_value(Session::new(DEFAULT_EXPIRATION)).expect("serialize");
+    json["uid"] = serde_json::to_value(uid).expect("serialize");
+    serde_json::from_value(json).expect("deserialize")
+}
+
+/// Builds a GET request, with the given `Cookie` header if any
+pub(crate) fn request(cookie: Option<String>) -> Request<String> {
+    let mut req = Request::builder().uri("/");
+    if let Some(cookie) = cookie {
+        req = req.header(header::COOKIE, cookie);
+    }
+    req.body(String::new()).expect("valid request")
+}
+
+/// Calls the service once with the given request
+pub(crate) async fn call<S>(mut service: S, req: Request<String>) -> Response<String>
+where
+    S: tower_service::Service<Request<String>, Response = Response<String>, Error = Infallible>,
+{
+    std::future::poll_fn(|cx| service.poll_ready(cx))
+        .await
+        .expect("infallible");
+    service.call(req).await.expect("infallible")
+}
+
+/// Returns the `Set-Cookie` headers of the response
+pub(crate) fn set_cookies(res: &Response<String>) -> Vec<String> {
+    res.headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .map(|value| value.to_str().expect("valid header").to_owned())
+        .collect()
+}
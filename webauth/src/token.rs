@@ -0,0 +1,251 @@
+use crate::secret::Secret;
+use crate::store::{Detachable, Error, Expirable, Identifiable, Store, VersionedStore};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// What a `OneTimeToken` was issued for, a token only being accepted for its
+/// purpose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Purpose {
+    EmailVerification,
+    PasswordReset,
+    /// Passwordless login (see `magic_link`)
+    MagicLink,
+}
+
+/// A single use token, sent to a user (usually in a link by email) to prove
+/// it received it.
+///
+/// The token string given to the user holds the `selector`, to find the
+/// token, and a secret validator of which only the hash is stored: a leak of
+/// the store does not give usable tokens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OneTimeToken<Uid> {
+    pub selector: Uuid,
+    /// SHA-256 of the validator, encoded in base64
+    pub validator_hash: String,
+    pub user_uid: Uid,
+    pub purpose: Purpose,
+    pub expires_at: SystemTime,
+}
+
+impl<Uid> Identifiable for OneTimeToken<Uid> {
+    type Uid = Uuid;
+
+    fn uid(&self) -> Self::Uid {
+        self.selector
+    }
+}
+
+impl<Uid: Clone> Detachable for OneTimeToken<Uid> {}
+
+impl<Uid> Expirable for OneTimeToken<Uid> {
+    fn expires_at(&self) -> Option<SystemTime> {
+        Some(self.expires_at)
+    }
+}
+
+/// Issues a token for `user_uid`, valid for `ttl`, and saves it in `store`.
+///
+/// Returns the token string to give to the user (it is URL-safe), which is
+/// not stored, and the stored token.
+pub async fn issue<S, Uid>(
+    store: &S,
+    user_uid: Uid,
+    purpose: Purpose,
+    ttl: Duration,
+) -> Result<(String, OneTimeToken<Uid>), Error>
+where
+    S: Store<Object = OneTimeToken<Uid>>,
+{
+    let secret = Secret::generate()?;
+    let token = OneTimeToken {
+        selector: secret.selector,
+        validator_hash: secret.hash(),
+        user_uid,
+        purpose,
+        expires_at: SystemTime::now() + ttl,
+    };
+    store.save(&token).await?;
+    Ok((secret.to_string(), token))
+}
+
+/// Returns the user of the token string if valid for `purpose`, deleting the
+/// token so it cannot be used twice.
+///
+/// Malformed, unknown, forged or expired tokens, and tokens issued for
+/// another purpose, give `None`.
+///
+/// The token is deleted only if unchanged since it was loaded (see
+/// `VersionedStore::delete_if`): of concurrent calls with the same token,
+/// only the one whose delete removed it gets the user.
+pub async fn consume<S, Uid>(store: &S, token: &str, purpose: Purpose) -> Result<Option<Uid>, Error>
+where
+    S: VersionedStore<Object = OneTimeToken<Uid>>,
+{
+    let Some(secret) = Secret::parse(token) else {
+        tracing::warn!("possible funny business, invalid one-time token");
+        return Ok(None);
+    };
+    let Some((token, version)) = store.load_versioned(&secret.selector).await? else {
+        return Ok(None);
+    };
+    if !secret.verify(&token.validator_hash) {
+        tracing::warn!(selector = %token.selector, "possible funny business, invalid one-time token validator");
+        return Ok(None);
+    }
+    if token.purpose != purpose {
+        tracing::warn!(selector = %token.selector, "possible funny business, one-time token used for another purpose");
+        return Ok(None);
+    }
+    if !store.delete_if(&token.selector, &version).await? {
+        tracing::debug!(selector = %token.selector, "one-time token consumed concurrently");
+        return Ok(None);
+    }
+    if token.expires_at <= SystemTime::now() {
+        return Ok(None);
+    }
+    Ok(Some(token.user_uid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::SpyStore;
+
+    type Tokens = SpyStore<OneTimeToken<u64>>;
+
+    const TTL: Duration = Duration::from_secs(3600);
+
+    #[tokio::test]
+    async fn consume_once() {
+        let tokens = Tokens::default();
+        let (raw, token) = issue(&tokens, 42, Purpose::EmailVerification, TTL)
+            .await
+            .expect("issue");
+
+        // Only the hash is stored
+        let stored = tokens.get(&token.selector).expect("stored");
+        let (_, validator) = raw.split_once('.').expect("validator");
+        assert!(!stored.validator_hash.contains(validator));
+        assert_eq!(token.validator_hash, stored.validator_hash);
+
+        let consume = |raw: String| {
+            let tokens = tokens.clone();
+            async move { consume(&tokens, &raw, Purpose::EmailVerification).await }
+        };
+        assert_eq!(Some(42), consume(raw.clone()).await.expect("consume"));
+        assert!(tokens.get(&token.selector).is_none());
+        assert_eq!(None, consume(raw).await.expect("consume"));
+    }
+
+    #[tokio::test]
+    async fn wrong_purpose() {
+        let tokens = Tokens::default();
+        let (raw, token) = issue(&tokens, 42, Purpose::EmailVerification, TTL)
+            .await
+            .expect("issue");
+
+        let user = consume(&tokens, &raw, Purpose::PasswordReset).await;
+        assert_eq!(None, user.expect("consume"));
+        // Still usable for its purpose
+        assert!(tokens.get(&token.selector).is_some());
+        let user = consume(&tokens, &raw, Purpose::EmailVerification).await;
+        assert_eq!(Some(42), user.expect("consume"));
+    }
+
+    #[tokio::test]
+    async fn expired() {
+        let tokens = Tokens::default();
+        let (raw, token) = issue(&tokens, 42, Purpose::PasswordReset, TTL)
+            .await
+            .expect("issue");
+        tokens.put(OneTimeToken {
+            expires_at: SystemTime::now() - Duration::from_secs(1),
+            ..token.clone()
+        });
+
+        let user = consume(&tokens, &raw, Purpose::PasswordReset).await;
+        assert_eq!(None, user.expect("consume"));
+        assert!(tokens.get(&token.selector).is_none());
+    }
+
+    #[tokio::test]
+    async fn invalid() {
+        let tokens = Tokens::default();
+        let (_, token) = issue(&tokens, 42, Purpose::EmailVerification, TTL)
+            .await
+            .expect("issue");
+
+        for raw in [
+            "",
+            "garbage",
+            "not-a-uuid.AAAA",
+            &format!("{}.%%%", token.selector),
+            &format!("{}.{}", token.selector, "A".repeat(43)),
+            &format!("{}.{}", Uuid::new_v4(), "A".repeat(43)),
+        ] {
+            let user = consume(&tokens, raw, Purpose::EmailVerification).await;
+            assert_eq!(None, user.expect("consume"), "{}", raw);
+        }
+        // A forged validator does not burn the token of the user
+        assert!(tokens.get(&token.selector).is_some());
+    }
+
+    // Yields between the load and the delete, so that concurrent consumes
+    // all load the token before any of them deletes it
+    #[derive(Clone)]
+    struct Racy(Tokens);
+
+    impl Store for Racy {
+        type Object = OneTimeToken<u64>;
+
+        async fn load(&self, uid: &Uuid) -> Result<Option<Self::Object>, Error> {
+            self.0.load(uid).await
+        }
+
+        async fn save(&self, token: &Self::Object) -> Result<(), Error> {
+            self.0.save(token).await
+        }
+
+        async fn delete(&self, uid: &Uuid) -> Result<(), Error> {
+            self.0.delete(uid).await
+        }
+    }
+
+    impl VersionedStore for Racy {
+        type Version = usize;
+
+        async fn load_versioned(
+            &self,
+            uid: &Uuid,
+        ) -> Result<Option<crate::store::Versioned<Self>>, Error> {
+            let res = self.0.load_versioned(uid).await;
+            tokio::task::yield_now().await;
+            res
+        }
+
+        async fn delete_if(&self, uid: &Uuid, version: &usize) -> Result<bool, Error> {
+            self.0.delete_if(uid, version).await
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent() {
+        let tokens = Racy(Tokens::default());
+        let (raw, token) = issue(&tokens, 42, Purpose::MagicLink, TTL)
+            .await
+            .expect("issue");
+
+        let (first, second) = tokio::join!(
+            consume(&tokens, &raw, Purpose::MagicLink),
+            consume(&tokens, &raw, Purpose::MagicLink),
+        );
+        assert_eq!(2, tokens.0.loads());
+        let mut users = [first.expect("consume"), second.expect("consume")];
+        users.sort();
+        assert_eq!([None, Some(42)], users);
+        assert!(tokens.0.get(&token.selector).is_none());
+    }
+}
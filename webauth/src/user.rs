@@ -1,15 +1,32 @@
 use crate::{
-    _store::Identifiable,
-    session::{Session, SessionManager},
+    _store::{Error, Identifiable},
+    auth::{AuthBackend, SESSION_USER_KEY},
+    session::{LazySession, Session, SessionManager, SessionManagerLayer},
 };
-use http::{Request, Response};
+use http::{HeaderValue, Request, Response};
 use serde::Deserialize;
-use std::{fmt::Debug, future::Future, pin::Pin};
+use std::{fmt::Debug, future::Future, pin::Pin, sync::Arc};
 use tower_cookies::CookieManager;
 use tower_service::Service;
 
 // ----------------------------------------------------------------------------
 
+/// A user who can verify its email address (e.g. with a confirmation link).
+pub trait Verifiable: Identifiable {
+    /// Returns if the user has verified its email address
+    fn is_verified(&self) -> bool;
+}
+
+/// Where visitors who are not logged in are sent (e.g. the login page), see
+/// `UserManagerLayer::with_unauthenticated_redirect`.
+///
+/// The `UserManager` puts it in the request extensions of the anonymous
+/// requests it lets through, for the axum `ProtectedUser` extractor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoginRedirect(pub &'static str);
+
+// ----------------------------------------------------------------------------
+
 #[derive(Debug, Clone)]
 pub struct UserManager<Service, User, Store>
 where
@@ -18,6 +35,11 @@ where
 {
     inner: Service,
     store: Store,
+    bearer: Option<BearerResolver<User>>,
+    anonymous: bool,
+    redirect: Option<LoginRedirect>,
+    #[cfg(feature = "remember-me")]
+    remember: Option<crate::_remember::Restorer>,
 }
 
 impl<ReqBody, ResBody, S, User, Store> Service<Request<ReqBody>> for UserManager<S, User, Store>
@@ -25,7 +47,7 @@ where
     S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
     S::Future: Send,
     ReqBody: Send + 'static,
-    ResBody: Default,
+    ResBody: Default + Send + 'static,
     User: Identifiable + Clone + Send + Sync + 'static,
     for<'de> <User as Identifiable>::Uid: Send + std::fmt::Debug + Deserialize<'de>,
     Store: crate::store::Store<Object = User> + Clone + Send + 'static,
@@ -47,68 +69,215 @@ where
         let clone = self.inner.clone();
         let mut inner = std::mem::replace(&mut self.inner, clone);
         let store = self.store.clone();
+        let bearer = self.bearer.clone();
+        let anonymous = self.anonymous;
+        let redirect = self.redirect;
+        #[cfg(feature = "remember-me")]
+        let remember = self.remember.clone();
 
         fn return_error<ResBody: Default, Error>(
             code: http::StatusCode,
         ) -> std::result::Result<Response<ResBody>, Error> {
             let mut res = Response::default();
-            // TODO: headers
             *res.status_mut() = code;
             Ok(res)
         }
 
         Box::pin(async move {
-            // Start by getting the session
-            let Some(session) = req.extensions().get::<Session>() else {
-                // this should not be possible but we are in a protected space,
-                // redirect
-                tracing::warn!("not session found");
-                return return_error(http::StatusCode::UNAUTHORIZED);
+            // Try the session first, then the bearer token. Resolving the
+            // user needs the session, a lazy one is loaded.
+            let session = match (
+                req.extensions().get::<Session>().cloned(),
+                req.extensions().get::<LazySession>().cloned(),
+            ) {
+                (Some(session), _) => Some(session),
+                (None, Some(lazy)) => match lazy.get().await {
+                    Ok(session) => Some(session),
+                    Err(err) => {
+                        tracing::error!(err = %err, "failed to load session");
+                        return return_error(http::StatusCode::INTERNAL_SERVER_ERROR);
+                    }
+                },
+                (None, None) => None,
             };
-
-            // Get the user_uid from the session
-            let user_uid = match session.get::<<User as Identifiable>::Uid>("user_uid") {
-                Ok(Some(user_uid)) => user_uid,
-                Ok(None) => {
-                    // Session not authenticated
-                    tracing::warn!(suid = %session.uid(), "no user_uid found in session");
-                    return return_error(http::StatusCode::UNAUTHORIZED);
-                }
-                Err(err) => {
-                    // Unable to get the user_uid from the session
-                    tracing::warn!(err = %err, suid = %session.uid(), "unable to get user_uid from session");
-                    return return_error(http::StatusCode::INTERNAL_SERVER_ERROR);
+            #[cfg(feature = "remember-me")]
+            let remember = match (
+                remember,
+                &session,
+                req.extensions().get::<tower_cookies::Cookies>(),
+            ) {
+                (Some(remember), Some(session), Some(cookies)) => {
+                    let authenticated = remember.restore(cookies.clone(), session.clone()).await;
+                    Some((remember, cookies.clone(), session.clone(), authenticated))
                 }
+                _ => None,
+            };
+            let user = match session_user(session, store).await {
+                Ok(user) => user,
+                Err(code) => return return_error(code),
+            };
+            let user = match (user, &bearer) {
+                (Some(user), _) => Some(user),
+                (None, Some(bearer)) => match bearer_user(bearer_token(&req), bearer).await {
+                    Ok(user) => user,
+                    Err(code) => return return_error(code),
+                },
+                (None, None) => None,
             };
 
-            // Get the user
-            let user = match store.load(&user_uid).await {
-                Ok(Some(user)) => user,
-                Ok(None) => {
-                    // We have a valid session, with a user_uid that does not
-                    // resolve to a valid user. This should not happen.
-                    tracing::warn!(uid = %session.uid(), user_uid = ?user_uid, "unable to resolve a valid user");
-                    return return_error(http::StatusCode::UNAUTHORIZED);
+            match user {
+                Some(user) => {
+                    tracing::trace!(uid = ?user.uid(), "user used");
+                    req.extensions_mut().insert(user);
                 }
-                Err(err) => {
-                    // Unable to load user
-                    tracing::warn!(err = %err, uid = %session.uid(), user_uid = ?user_uid, "unable to resolve a valid user");
-                    return return_error(http::StatusCode::INTERNAL_SERVER_ERROR);
+                None if anonymous => {
+                    tracing::trace!("anonymous user");
+                    if let Some(redirect) = redirect {
+                        req.extensions_mut().insert(redirect);
+                    }
                 }
-            };
-
-            tracing::trace!(uid = ?user_uid, "user used");
-            req.extensions_mut().insert(user.clone());
+                None => {
+                    let Some(LoginRedirect(location)) = redirect else {
+                        return return_error(http::StatusCode::UNAUTHORIZED);
+                    };
+                    let mut res = return_error(http::StatusCode::SEE_OTHER)?;
+                    res.headers_mut()
+                        .insert(http::header::LOCATION, HeaderValue::from_static(location));
+                    return Ok(res);
+                }
+            }
 
             let res = inner.call(req).await?;
 
+            #[cfg(feature = "remember-me")]
+            if let Some((remember, cookies, session, authenticated)) = remember {
+                remember.cleanup(cookies, session, authenticated).await;
+            }
+
             Ok(res)
         })
     }
 }
 
+// Resolves the user from the session, `None` if the session is not
+// authenticated.
+async fn session_user<User, Store>(
+    session: Option<Session>,
+    store: Store,
+) -> Result<Option<User>, http::StatusCode>
+where
+    User: Identifiable,
+    for<'de> <User as Identifiable>::Uid: std::fmt::Debug + Deserialize<'de>,
+    Store: crate::store::Store<Object = User>,
+{
+    // Start by getting the session
+    let Some(session) = session else {
+        // this should not be possible but we are in a protected space
+        tracing::warn!("not session found");
+        return Ok(None);
+    };
+
+    // Get the user_uid from the session
+    let user_uid = match session.get::<<User as Identifiable>::Uid>(SESSION_USER_KEY) {
+        Ok(Some(user_uid)) => user_uid,
+        Ok(None) => {
+            // Session not authenticated
+            tracing::debug!(suid = %session.uid(), "no user_uid found in session");
+            return Ok(None);
+        }
+        Err(err) => {
+            // Unable to get the user_uid from the session
+            tracing::warn!(err = %err, suid = %session.uid(), "unable to get user_uid from session");
+            return Err(http::StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    // Get the user
+    match store.load(&user_uid).await {
+        Ok(Some(user)) => Ok(Some(user)),
+        Ok(None) => {
+            // We have a valid session, with a user_uid that does not
+            // resolve to a valid user. This should not happen.
+            tracing::warn!(uid = %session.uid(), user_uid = ?user_uid, "unable to resolve a valid user");
+            Ok(None)
+        }
+        Err(err) => {
+            // Unable to load user
+            tracing::warn!(err = %err, uid = %session.uid(), user_uid = ?user_uid, "unable to resolve a valid user");
+            Err(http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// Extracts the token of the `Authorization: Bearer` header, if any.
+fn bearer_token<ReqBody>(req: &Request<ReqBody>) -> Option<String> {
+    req.headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim())
+        .filter(|token| !token.is_empty())
+        .map(str::to_owned)
+}
+
+// Resolves the user from the bearer token, if any.
+async fn bearer_user<User>(
+    token: Option<String>,
+    bearer: &BearerResolver<User>,
+) -> Result<Option<User>, http::StatusCode> {
+    let Some(token) = token else {
+        return Ok(None);
+    };
+
+    bearer.resolve(token).await.map_err(|err| {
+        tracing::warn!(err = %err, "unable to resolve a user from the bearer token");
+        http::StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Resolves users from bearer tokens (e.g. API keys), for clients which do
+/// not use cookies. Returns `None` if the token is unknown.
+pub struct BearerResolver<User>(Arc<dyn Fn(String) -> BearerFuture<User> + Send + Sync>);
+
+type BearerFuture<User> = Pin<Box<dyn Future<Output = Result<Option<User>, Error>> + Send>>;
+
+impl<User> BearerResolver<User> {
+    /// Creates a resolver from the given function
+    pub fn new<F, Fut>(resolver: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Option<User>, Error>> + Send + 'static,
+    {
+        Self(Arc::new(move |token| Box::pin(resolver(token))))
+    }
+
+    /// Resolves the user owning the token
+    pub fn resolve(&self, token: String) -> impl Future<Output = Result<Option<User>, Error>> {
+        (self.0)(token)
+    }
+}
+
+impl<User> Clone for BearerResolver<User> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<User> std::fmt::Debug for BearerResolver<User> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BearerResolver")
+    }
+}
+
 // ----------------------------------------------------------------------------
 
+/// Resolves the user of the session (see `auth::login`) on the routes it
+/// wraps, rejecting requests without one.
+///
+/// The sessions are managed by the given `SessionManagerLayer`, with all of
+/// its settings: use the one configured for the other routes so both agree
+/// on the cookie (e.g. private cookies).
 #[derive(Debug, Clone)]
 pub struct UserManagerLayer<StoreUser, StoreSession, User>
 where
@@ -117,8 +286,12 @@ where
     User: Identifiable,
 {
     store_user: StoreUser,
-    store_session: StoreSession,
-    cookie_name: &'static str,
+    session: SessionManagerLayer<StoreSession>,
+    bearer: Option<BearerResolver<User>>,
+    anonymous: bool,
+    redirect: Option<LoginRedirect>,
+    #[cfg(feature = "remember-me")]
+    remember: Option<crate::_remember::Restorer>,
 }
 
 impl<StoreUser, StoreSession, User> UserManagerLayer<StoreUser, StoreSession, User>
@@ -127,17 +300,77 @@ where
     StoreSession: crate::store::Store<Object = Session>,
     User: Identifiable,
 {
-    pub fn new(
-        store_session: StoreSession,
-        store_user: StoreUser,
-        cookie_name: &'static str,
-    ) -> Self {
+    pub fn new(session: SessionManagerLayer<StoreSession>, store_user: StoreUser) -> Self {
         Self {
-            store_session,
             store_user,
-            cookie_name,
+            session,
+            bearer: None,
+            anonymous: false,
+            redirect: None,
+            #[cfg(feature = "remember-me")]
+            remember: None,
         }
     }
+
+    /// Also accepts requests authenticated with an `Authorization: Bearer`
+    /// header, used when the session does not resolve to a user.
+    pub fn with_bearer(mut self, resolver: BearerResolver<User>) -> Self {
+        self.bearer = Some(resolver);
+        self
+    }
+
+    /// Lets requests without a user through instead of rejecting them with a
+    /// 401, for routes rendering differently for anonymous visitors (see
+    /// `MaybeUser`). The user is only in the request extensions if any.
+    pub fn with_anonymous(mut self) -> Self {
+        self.anonymous = true;
+        self
+    }
+
+    /// Redirects requests without a user to `location` (e.g. the login
+    /// page) with a 303, instead of rejecting them with a 401. Better suited
+    /// to browsers, API clients expect the 401.
+    ///
+    /// With `with_anonymous`, requests are let through and the axum
+    /// `ProtectedUser` extractor redirects them the same way instead: this is
+    /// the only place to configure the redirect.
+    ///
+    /// Panics if `location` is not a valid header value.
+    pub fn with_unauthenticated_redirect(mut self, location: &'static str) -> Self {
+        // Checked now rather than on the first request
+        let _ = HeaderValue::from_static(location);
+        self.redirect = Some(LoginRedirect(location));
+        self
+    }
+
+    /// Adds the `AuthBackend` to the request extensions, next to the user
+    /// (see `SessionManagerLayer::with_backend` for routes open to anonymous
+    /// users, like the login one).
+    pub fn with_backend<B>(mut self, backend: B) -> Self
+    where
+        B: AuthBackend<User = User> + Clone + Send + Sync + 'static,
+    {
+        self.session = self.session.with_backend(backend);
+        self
+    }
+
+    /// Logs users back in from their remember-me cookie when their session
+    /// is anonymous, and adds the `RememberMe` to the request extensions,
+    /// for the login and logout handlers.
+    #[cfg(feature = "remember-me")]
+    pub fn with_remember_me<S, Uid>(mut self, remember: crate::remember::RememberMe<S>) -> Self
+    where
+        S: crate::store::Store<Object = crate::remember::RememberMeToken<Uid>>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        Uid: serde::Serialize + serde::de::DeserializeOwned + Send + Sync + 'static,
+    {
+        self.remember = Some(remember.restorer());
+        self.session = self.session.with_extension(remember);
+        self
+    }
 }
 
 impl<S, StoreUser, StoreSession, User> tower_layer::Layer<S>
@@ -153,12 +386,12 @@ where
         let user_manager = UserManager {
             inner,
             store: self.store_user.clone(),
+            bearer: self.bearer.clone(),
+            anonymous: self.anonymous,
+            redirect: self.redirect,
+            #[cfg(feature = "remember-me")]
+            remember: self.remember.clone(),
         };
-        let sess_manager = SessionManager {
-            inner: user_manager,
-            store: self.store_session.clone(),
-            cookie_name: self.cookie_name,
-        };
-        CookieManager::new(sess_manager)
+        tower_layer::Layer::layer(&self.session, user_manager)
     }
 }
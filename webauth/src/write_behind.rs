@@ -0,0 +1,450 @@
+use crate::_session::lock;
+use crate::store::{Detachable, Error, Identifiable, Store};
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+// A write waiting to be flushed to the inner store
+#[derive(Debug, Clone)]
+enum Pending<Object> {
+    Save(Object),
+    Delete,
+}
+
+// A pending write, with the sequence number of the write so a flush can
+// tell whether the resource was written again while flushing it
+#[derive(Debug)]
+struct Entry<Object> {
+    write: Pending<Object>,
+    sequence: u64,
+    // Being written to the inner store, not to be flushed again until done
+    flushing: bool,
+}
+
+#[derive(Debug)]
+struct Buffer<Object>
+where
+    Object: Identifiable,
+{
+    entries: HashMap<Object::Uid, Entry<Object>>,
+    sequence: u64,
+}
+
+impl<Object> Default for Buffer<Object>
+where
+    Object: Identifiable,
+{
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            sequence: 0,
+        }
+    }
+}
+
+/// A `Store` buffering writes in memory before flushing them to the inner
+/// `Store`, for workloads saving the same resources over and over (e.g.
+/// access tracking touching sessions on every request).
+///
+/// Writes to the same resource are coalesced: only the last one reaches the
+/// inner store. They are flushed when `max_pending` resources are waiting,
+/// or when calling `flush`, which should be done periodically (e.g. from a
+/// `tokio::time::interval` task) and on shutdown: nothing flushes them
+/// when the store is dropped, awaiting `flush` before exiting is up to the
+/// caller. Resources stay visible from this store until written.
+///
+/// Objects are buffered detached (see `Detachable`): a `Session` changed
+/// after being saved is not seen until saved again.
+///
+/// This trades durability for throughput: writes not flushed yet are lost
+/// if the process crashes, and are only visible to this instance (other
+/// instances sharing the inner store read stale resources until flushed).
+pub struct WriteBehindStore<S>
+where
+    S: Store,
+{
+    inner: S,
+    pending: Arc<Mutex<Buffer<S::Object>>>,
+    max_pending: usize,
+}
+
+impl<S> WriteBehindStore<S>
+where
+    S: Store + Clone + Send + 'static,
+    S::Object: Detachable + Send,
+    <S::Object as Identifiable>::Uid: Hash + Eq + Clone + Send,
+{
+    /// Wraps `inner`, flushing once `max_pending` resources are waiting.
+    pub fn new(inner: S, max_pending: usize) -> Self {
+        Self {
+            inner,
+            pending: Default::default(),
+            max_pending,
+        }
+    }
+
+    /// Returns the number of resources waiting to be flushed.
+    pub fn pending(&self) -> usize {
+        lock(&self.pending).entries.len()
+    }
+
+    /// Writes all the pending resources to the inner store, except those
+    /// already being written by another flush.
+    /// Resources are only removed from the buffer once written (and if not
+    /// written again meanwhile), failing ones are kept pending and the last
+    /// error is returned.
+    pub fn flush(&self) -> impl Future<Output = Result<(), Error>> + Send {
+        let writes = lock(&self.pending)
+            .entries
+            .iter_mut()
+            .filter(|(_, entry)| !entry.flushing)
+            .map(|(uid, entry)| {
+                entry.flushing = true;
+                (uid.clone(), entry.write.clone(), entry.sequence)
+            })
+            .collect::<Vec<_>>();
+        let inner = self.inner.clone();
+        let pending = self.pending.clone();
+        async move {
+            let mut result = Ok(());
+            for (uid, write, sequence) in writes {
+                let written = match &write {
+                    Pending::Save(obj) => inner.save(obj).await,
+                    Pending::Delete => inner.delete(&uid).await,
+                };
+                if let Err(err) = &written {
+                    tracing::warn!(err = %err, "failed to flush write");
+                }
+                let mut pending = lock(&pending);
+                match pending.entries.get_mut(&uid) {
+                    Some(entry) if entry.sequence == sequence && written.is_ok() => {
+                        pending.entries.remove(&uid);
+                    }
+                    Some(entry) => entry.flushing = false,
+                    None => {}
+                }
+                drop(pending);
+                if let Err(err) = written {
+                    result = Err(err);
+                }
+            }
+            result
+        }
+    }
+
+    // Buffers the write, flushing everything if too many are waiting.
+    // Failing to flush is not an error of the write: it stays pending.
+    fn write(
+        &self,
+        uid: <S::Object as Identifiable>::Uid,
+        write: Pending<S::Object>,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        let mut pending = lock(&self.pending);
+        pending.sequence += 1;
+        let sequence = pending.sequence;
+        // Not flushed again while an older write is, to keep them in order
+        let flushing = pending
+            .entries
+            .get(&uid)
+            .is_some_and(|entry| entry.flushing);
+        pending.entries.insert(
+            uid,
+            Entry {
+                write,
+                sequence,
+                flushing,
+            },
+        );
+        let flush = (pending.entries.len() >= self.max_pending).then(|| {
+            drop(pending);
+            self.flush()
+        });
+        async move {
+            if let Some(flush) = flush {
+                // Already logged
+                let _ = flush.await;
+            }
+            Ok(())
+        }
+    }
+}
+
+impl<S> Clone for WriteBehindStore<S>
+where
+    S: Store + Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            pending: self.pending.clone(),
+            max_pending: self.max_pending,
+        }
+    }
+}
+
+impl<S> std::fmt::Debug for WriteBehindStore<S>
+where
+    S: Store + std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WriteBehindStore")
+            .field("inner", &self.inner)
+            .field("pending", &lock(&self.pending).entries.len())
+            .field("max_pending", &self.max_pending)
+            .finish()
+    }
+}
+
+/// Writes still pending when the last clone is dropped are logged and lost:
+/// dropping cannot await them, `flush` must be awaited before.
+impl<S> Drop for WriteBehindStore<S>
+where
+    S: Store,
+{
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.pending) > 1 {
+            return;
+        }
+        let pending = self
+            .pending
+            .lock()
+            .map(|pending| pending.entries.len())
+            .unwrap_or(0);
+        if pending > 0 {
+            tracing::error!(pending = pending, "dropping unflushed writes");
+        }
+    }
+}
+
+impl<S> Store for WriteBehindStore<S>
+where
+    S: Store + Clone + Send + 'static,
+    S::Object: Detachable + Send,
+    <S::Object as Identifiable>::Uid: Hash + Eq + Clone + Send,
+{
+    type Object = S::Object;
+
+    fn load(
+        &self,
+        uid: &<Self::Object as Identifiable>::Uid,
+    ) -> impl Future<Output = Result<Option<Self::Object>, Error>> + Send {
+        let buffered = lock(&self.pending)
+            .entries
+            .get(uid)
+            .map(|entry| entry.write.clone());
+        let inner = self.inner.clone();
+        let uid = uid.clone();
+        async move {
+            match buffered {
+                Some(Pending::Save(obj)) => Ok(Some(obj.detach())),
+                Some(Pending::Delete) => Ok(None),
+                None => inner.load(&uid).await,
+            }
+        }
+    }
+
+    fn save(&self, obj: &Self::Object) -> impl Future<Output = Result<(), Error>> + Send {
+        self.write(obj.uid(), Pending::Save(obj.detach()))
+    }
+
+    fn delete(
+        &self,
+        uid: &<Self::Object as Identifiable>::Uid,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        self.write(uid.clone(), Pending::Delete)
+    }
+
+    // Pending writes are flushed later, expired or not
+    fn purge_expired(&self) -> impl Future<Output = Result<u64, Error>> + Send {
+        self.inner.purge_expired()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{Session, DEFAULT_EXPIRATION};
+    use crate::testing::SpyStore;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use uuid::Uuid;
+
+    // Holds the writes until opened, to act while a flush is writing
+    #[derive(Clone, Default)]
+    struct Gated(SpyStore<Session>, Arc<AtomicBool>);
+
+    impl Gated {
+        async fn wait(&self) {
+            while !self.1.load(Ordering::SeqCst) {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        fn open(&self) {
+            self.1.store(true, Ordering::SeqCst)
+        }
+    }
+
+    impl Store for Gated {
+        type Object = Session;
+
+        async fn load(&self, uid: &Uuid) -> Result<Option<Session>, Error> {
+            self.0.load(uid).await
+        }
+
+        async fn save(&self, session: &Session) -> Result<(), Error> {
+            self.wait().await;
+            self.0.save(session).await
+        }
+
+        async fn delete(&self, uid: &Uuid) -> Result<(), Error> {
+            self.wait().await;
+            self.0.delete(uid).await
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesce() -> Result<(), Error> {
+        let inner = SpyStore::<Session>::default();
+        let store = WriteBehindStore::new(inner.clone(), 10);
+
+        let session = Session::new(DEFAULT_EXPIRATION);
+        for visits in 0..5 {
+            session.insert("visits", visits).expect("insert");
+            store.save(&session).await?;
+        }
+        // Nothing written yet, but visible from this store
+        assert_eq!(0, inner.saves());
+        assert_eq!(1, store.pending());
+        let loaded = store.load(&session.uid()).await?.expect("buffered");
+        assert_eq!(Some(4), loaded.get::<u32>("visits").expect("get"));
+        assert_eq!(0, inner.loads());
+
+        // Five saves, one write
+        store.flush().await?;
+        assert_eq!(1, inner.saves());
+        assert_eq!(0, store.pending());
+        let stored = inner.get(&session.uid()).expect("flushed");
+        assert_eq!(Some(4), stored.get::<u32>("visits").expect("get"));
+
+        // Deletes are buffered too
+        store.delete(&session.uid()).await?;
+        assert!(store.load(&session.uid()).await?.is_none());
+        assert!(inner.get(&session.uid()).is_some());
+        store.flush().await?;
+        assert!(inner.get(&session.uid()).is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn threshold() -> Result<(), Error> {
+        let inner = SpyStore::<Session>::default();
+        let store = WriteBehindStore::new(inner.clone(), 3);
+
+        let sessions = (0..3)
+            .map(|_| Session::new(DEFAULT_EXPIRATION))
+            .collect::<Vec<_>>();
+        store.save(&sessions[0]).await?;
+        store.save(&sessions[1]).await?;
+        store.save(&sessions[1]).await?;
+        assert_eq!(0, inner.saves());
+
+        // Third resource pending, everything is flushed
+        store.save(&sessions[2]).await?;
+        assert_eq!(3, inner.saves());
+        assert_eq!(0, store.pending());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_failure() -> Result<(), Error> {
+        let inner = SpyStore::<Session>::default();
+        let store = WriteBehindStore::new(inner.clone(), 10);
+
+        let session = Session::new(DEFAULT_EXPIRATION);
+        store.save(&session).await?;
+        inner.fail_save.store(true, Ordering::SeqCst);
+        assert!(store.flush().await.is_err());
+
+        // Kept pending, and flushed once the inner store is back
+        assert_eq!(1, store.pending());
+        inner.fail_save.store(false, Ordering::SeqCst);
+        store.flush().await?;
+        assert_eq!(0, store.pending());
+        assert!(inner.get(&session.uid()).is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detached() -> Result<(), Error> {
+        let inner = SpyStore::<Session>::default();
+        let store = WriteBehindStore::new(inner.clone(), 10);
+
+        let session = Session::new(DEFAULT_EXPIRATION);
+        session.insert("key", "saved").expect("insert");
+        store.save(&session).await?;
+        let uid = session.uid();
+
+        // Changes after the save are not buffered until saved again
+        session.insert("key", "changed").expect("insert");
+        session.cycle_uid();
+        let loaded = store.load(&uid).await?.expect("buffered");
+        assert_eq!(uid, loaded.uid());
+        assert_eq!(Some("saved".to_owned()), loaded.get("key").expect("get"));
+        assert!(store.load(&session.uid()).await?.is_none());
+
+        // Nor changes to a loaded session
+        loaded.insert("key", "loaded").expect("insert");
+        store.flush().await?;
+        let stored = inner.get(&uid).expect("flushed");
+        assert_eq!(uid, stored.uid());
+        assert_eq!(Some("saved".to_owned()), stored.get("key").expect("get"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn visible_while_flushing() -> Result<(), Error> {
+        let inner = Gated::default();
+        let store = WriteBehindStore::new(inner.clone(), 10);
+
+        let session = Session::new(DEFAULT_EXPIRATION);
+        session.insert("visits", 1).expect("insert");
+        store.save(&session).await?;
+
+        // Read while the flush is writing
+        let (flushed, loaded) = tokio::join!(store.flush(), async {
+            let loaded = store.load(&session.uid()).await;
+            assert!(inner.0.get(&session.uid()).is_none());
+            inner.open();
+            loaded
+        });
+        flushed?;
+        let loaded = loaded?.expect("still buffered");
+        assert_eq!(Some(1), loaded.get::<u32>("visits").expect("get"));
+        assert_eq!(0, store.pending());
+
+        // Written again while flushing: kept pending, not to lose it
+        inner.1.store(false, Ordering::SeqCst);
+        store.save(&session).await?;
+        let (flushed, saved) = tokio::join!(store.flush(), async {
+            session.insert("visits", 2).expect("insert");
+            let saved = store.save(&session).await;
+            inner.open();
+            saved
+        });
+        flushed?;
+        saved?;
+        assert_eq!(1, store.pending());
+        store.flush().await?;
+        let stored = inner.0.get(&session.uid()).expect("flushed");
+        assert_eq!(Some(2), stored.get::<u32>("visits").expect("get"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,507 @@
+//! The managers only rely on `http` and `tower`, they can be used without axum.
+
+use http::{header, Request, Response, StatusCode};
+use std::convert::Infallible;
+use tower::{service_fn, ServiceExt};
+use tower_layer::Layer;
+use uuid::Uuid;
+use webauth::auth::{self, AuthBackend, SESSION_USER_KEY};
+use webauth::session::{CookieConfig, Key, Session, SessionManagerLayer, DEFAULT_EXPIRATION};
+use webauth::store::{Detachable, Expirable, Identifiable, SessionStore, Store as _};
+use webauth::user::{BearerResolver, UserManagerLayer};
+use webauth_store_memory::Store;
+
+#[derive(Debug, Clone)]
+struct User {
+    uid: Uuid,
+    name: &'static str,
+}
+
+impl Identifiable for User {
+    type Uid = Uuid;
+
+    fn uid(&self) -> Self::Uid {
+        self.uid
+    }
+}
+
+impl Detachable for User {}
+
+impl Expirable for User {}
+
+fn request(cookie: Option<&str>) -> Request<String> {
+    let mut req = Request::builder().uri("/");
+    if let Some(cookie) = cookie {
+        req = req.header(header::COOKIE, cookie);
+    }
+    req.body(String::new()).unwrap()
+}
+
+#[tokio::test]
+async fn session_manager() {
+    let store = Store::<Session>::new();
+    let service = SessionManagerLayer::new(store, "uid").layer(service_fn(
+        |req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().unwrap();
+            let visits = session.get::<u64>("visits").unwrap().unwrap_or(0) + 1;
+            session.insert("visits", visits).unwrap();
+            Ok::<_, Infallible>(Response::new(visits.to_string()))
+        },
+    ));
+
+    // First visit creates a session and sets the cookie
+    let res = service.clone().oneshot(request(None)).await.unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("1", res.body());
+    let cookie = res
+        .headers()
+        .get(header::SET_COOKIE)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_owned();
+
+    // Second visit reuses it
+    let res = service.oneshot(request(Some(&cookie))).await.unwrap();
+    assert_eq!("2", res.body());
+}
+
+#[tokio::test]
+async fn cycle_uid_deletes_old_session() {
+    let store = Store::<Session>::new();
+    let session = Session::new(DEFAULT_EXPIRATION);
+    session.insert("hello", "world").unwrap();
+    store.save(&session).await.unwrap();
+
+    let service = SessionManagerLayer::new(store.clone(), "uid").layer(service_fn(
+        |req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().unwrap();
+            session.cycle_uid();
+            Ok::<_, Infallible>(Response::new(session.uid().to_string()))
+        },
+    ));
+    // Clones of a session share its uid, including the one in the memory
+    // store, so keep the old one aside
+    let old_uid = session.uid();
+    let cookie = format!("uid={}", old_uid);
+    let res = service.oneshot(request(Some(&cookie))).await.unwrap();
+    let new_uid: Uuid = res.body().parse().unwrap();
+    assert_ne!(old_uid, new_uid);
+
+    // The old uid cannot be used anymore, the data moved to the new one
+    assert!(store.load(&old_uid).await.unwrap().is_none());
+    let cycled = store.load(&new_uid).await.unwrap().unwrap();
+    assert_eq!(Some("world".to_owned()), cycled.get("hello").unwrap());
+}
+
+#[tokio::test]
+async fn user_manager() {
+    let sessions = Store::<Session>::new();
+    let users = Store::<User>::new();
+
+    let user = User {
+        uid: Uuid::new_v4(),
+        name: "alice",
+    };
+    users.save(&user).await.unwrap();
+    let session = Session::new(DEFAULT_EXPIRATION);
+    session.insert(SESSION_USER_KEY, user.uid).unwrap();
+    sessions.save(&session).await.unwrap();
+
+    let service = UserManagerLayer::new(SessionManagerLayer::new(sessions, "uid"), users).layer(
+        service_fn(|req: Request<String>| async move {
+            let user = req.extensions().get::<User>().unwrap();
+            Ok::<_, Infallible>(Response::new(user.name.to_owned()))
+        }),
+    );
+
+    // Anonymous requests are rejected
+    let res = service.clone().oneshot(request(None)).await.unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+    // Authenticated requests reach the handler with the user
+    let cookie = format!("uid={}", session.uid());
+    let res = service.oneshot(request(Some(&cookie))).await.unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("alice", res.body());
+}
+
+#[tokio::test]
+async fn user_manager_anonymous() {
+    let sessions = Store::<Session>::new();
+    let users = Store::<User>::new();
+
+    let user = User {
+        uid: Uuid::new_v4(),
+        name: "alice",
+    };
+    users.save(&user).await.unwrap();
+    let session = Session::new(DEFAULT_EXPIRATION);
+    session.insert(SESSION_USER_KEY, user.uid).unwrap();
+    sessions.save(&session).await.unwrap();
+
+    let service = UserManagerLayer::new(SessionManagerLayer::new(sessions, "uid"), users)
+        .with_anonymous()
+        .layer(service_fn(|req: Request<String>| async move {
+            let name = req
+                .extensions()
+                .get::<User>()
+                .map_or("anonymous", |user| user.name);
+            Ok::<_, Infallible>(Response::new(name.to_owned()))
+        }));
+
+    // Anonymous requests reach the handler, without a user
+    let res = service.clone().oneshot(request(None)).await.unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("anonymous", res.body());
+
+    let cookie = format!("uid={}", session.uid());
+    let res = service.oneshot(request(Some(&cookie))).await.unwrap();
+    assert_eq!("alice", res.body());
+}
+
+#[tokio::test]
+async fn user_manager_lazy_loading() {
+    let sessions = Store::<Session>::new();
+    let users = Store::<User>::new();
+
+    let user = User {
+        uid: Uuid::new_v4(),
+        name: "alice",
+    };
+    users.save(&user).await.unwrap();
+    let session = Session::new(DEFAULT_EXPIRATION);
+    session.insert(SESSION_USER_KEY, user.uid).unwrap();
+    sessions.save(&session).await.unwrap();
+
+    let layer = SessionManagerLayer::new(sessions, "uid").with_lazy_loading();
+    let service =
+        UserManagerLayer::new(layer, users).layer(service_fn(|req: Request<String>| async move {
+            let user = req.extensions().get::<User>().unwrap();
+            Ok::<_, Infallible>(Response::new(user.name.to_owned()))
+        }));
+
+    // The lazy session is loaded to resolve the user
+    let cookie = format!("uid={}", session.uid());
+    let res = service
+        .clone()
+        .oneshot(request(Some(&cookie)))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("alice", res.body());
+
+    let res = service.oneshot(request(None)).await.unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+}
+
+#[cfg(feature = "remember-me")]
+#[tokio::test]
+async fn user_manager_remember_me() {
+    for lazy in [false, true] {
+        remember_me(lazy).await;
+    }
+}
+
+#[cfg(feature = "remember-me")]
+async fn remember_me(lazy: bool) {
+    use std::time::Duration;
+    use tower_cookies::Cookies;
+    use webauth::remember::{RememberMe, RememberMeToken, DEFAULT_COOKIE_NAME};
+
+    let sessions = Store::<Session>::new();
+    let users = Store::<User>::new();
+    let tokens = Store::<RememberMeToken<Uuid>>::new();
+    let remember = RememberMe::new(tokens, Duration::from_secs(3600));
+
+    let user = User {
+        uid: Uuid::new_v4(),
+        name: "alice",
+    };
+    users.save(&user).await.unwrap();
+    // As a login route would
+    let jar = Cookies::default();
+    remember.remember(&jar, user.uid).await.unwrap();
+    let token = jar.get(DEFAULT_COOKIE_NAME).unwrap().value().to_owned();
+
+    let mut layer = SessionManagerLayer::new(sessions, "uid");
+    if lazy {
+        layer = layer.with_lazy_loading();
+    }
+    let service = UserManagerLayer::new(layer, users)
+        .with_remember_me(remember)
+        .layer(service_fn(|req: Request<String>| async move {
+            let user = req.extensions().get::<User>().unwrap();
+            Ok::<_, Infallible>(Response::new(user.name.to_owned()))
+        }));
+
+    // No session, but the user is remembered
+    let cookie = format!("{}={}", DEFAULT_COOKIE_NAME, token);
+    let res = service
+        .clone()
+        .oneshot(request(Some(&cookie)))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("alice", res.body());
+    let set_cookies: Vec<_> = res.headers().get_all(header::SET_COOKIE).iter().collect();
+    assert_eq!(2, set_cookies.len());
+
+    // The token has been rotated
+    let res = service.oneshot(request(Some(&cookie))).await.unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+}
+
+#[tokio::test]
+async fn user_manager_redirect() {
+    let sessions = Store::<Session>::new();
+    let users = Store::<User>::new();
+
+    let service = UserManagerLayer::new(SessionManagerLayer::new(sessions, "uid"), users)
+        .with_unauthenticated_redirect("/login")
+        .layer(service_fn(|_: Request<String>| async move {
+            Ok::<_, Infallible>(Response::new(String::new()))
+        }));
+
+    let res = service.oneshot(request(None)).await.unwrap();
+    assert_eq!(StatusCode::SEE_OTHER, res.status());
+    assert_eq!("/login", res.headers()[header::LOCATION]);
+}
+
+// Backend authenticating any known user by name
+#[derive(Clone)]
+struct Backend(Store<User>, Vec<User>);
+
+impl AuthBackend for Backend {
+    type User = User;
+    type Credentials = &'static str;
+    type Error = Infallible;
+
+    async fn authenticate(&self, name: &'static str) -> Result<Option<User>, Infallible> {
+        Ok(self.1.iter().find(|user| user.name == name).cloned())
+    }
+
+    async fn get_user(&self, uid: &Uuid) -> Result<Option<User>, Infallible> {
+        Ok(self.0.load(uid).await.ok().flatten())
+    }
+}
+
+fn session_cookie<B>(res: &Response<B>) -> String {
+    res.headers()
+        .get(header::SET_COOKIE)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_owned()
+}
+
+#[tokio::test]
+async fn login_then_user_manager() {
+    let sessions = Store::<Session>::new();
+    let users = Store::<User>::new();
+    let user = User {
+        uid: Uuid::new_v4(),
+        name: "alice",
+    };
+    users.save(&user).await.unwrap();
+    let backend = Backend(users.clone(), vec![user]);
+
+    // Login and logout routes, only behind the session manager, which hands
+    // them the backend
+    let login = SessionManagerLayer::new(sessions.clone(), "uid")
+        .with_backend(backend.clone())
+        .layer(service_fn(|req: Request<String>| async move {
+            let session = req.extensions().get::<Session>().unwrap();
+            let backend = req.extensions().get::<Backend>().unwrap();
+            let user = backend.authenticate("alice").await.unwrap().unwrap();
+            auth::login(session, &user).unwrap();
+            Ok::<_, Infallible>(Response::new(String::new()))
+        }));
+    let logout = SessionManagerLayer::new(sessions.clone(), "uid")
+        .with_backend(backend.clone())
+        .layer(service_fn(|req: Request<String>| async move {
+            let mut session = req.extensions().get::<Session>().unwrap().clone();
+            let backend = req.extensions().get::<Backend>().unwrap();
+            auth::logout(&mut session, backend).await.unwrap();
+            Ok::<_, Infallible>(Response::new(String::new()))
+        }));
+    // Protected route, getting the backend next to the user
+    let protected = UserManagerLayer::new(SessionManagerLayer::new(sessions, "uid"), users)
+        .with_backend(backend)
+        .layer(service_fn(|req: Request<String>| async move {
+            let user = req.extensions().get::<User>().unwrap();
+            assert!(req.extensions().get::<Backend>().is_some());
+            Ok::<_, Infallible>(Response::new(user.name.to_owned()))
+        }));
+
+    // The cookie set on login resolves the user
+    let res = login.oneshot(request(None)).await.unwrap();
+    let cookie = session_cookie(&res);
+    let res = protected
+        .clone()
+        .oneshot(request(Some(&cookie)))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("alice", res.body());
+
+    // Not anymore after logout
+    let res = logout.oneshot(request(Some(&cookie))).await.unwrap();
+    assert_ne!(cookie, session_cookie(&res));
+    let res = protected.oneshot(request(Some(&cookie))).await.unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+}
+
+#[tokio::test]
+async fn private_cookies_user_manager() {
+    let sessions = Store::<Session>::new();
+    let users = Store::<User>::new();
+    let user = User {
+        uid: Uuid::new_v4(),
+        name: "alice",
+    };
+    users.save(&user).await.unwrap();
+    let user_uid = user.uid;
+
+    // The same session settings for both routes
+    let layer = SessionManagerLayer::secure(sessions.clone(), "uid", Key::generate());
+    let login = layer.clone().layer(service_fn(move |req: Request<String>| {
+        let user = user.clone();
+        async move {
+            let session = req.extensions().get::<Session>().unwrap();
+            auth::login(session, &user).unwrap();
+            Ok::<_, Infallible>(Response::new(String::new()))
+        }
+    }));
+    let protected =
+        UserManagerLayer::new(layer, users).layer(service_fn(|req: Request<String>| async move {
+            let user = req.extensions().get::<User>().unwrap();
+            req.extensions()
+                .get::<Session>()
+                .unwrap()
+                .insert("visited", true)
+                .unwrap();
+            Ok::<_, Infallible>(Response::new(user.name.to_owned()))
+        }));
+
+    let res = login.oneshot(request(None)).await.unwrap();
+    let cookie = session_cookie(&res);
+    let res = protected.oneshot(request(Some(&cookie))).await.unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("alice", res.body());
+
+    // The cookie re-issued on write is still encrypted
+    let reissued = session_cookie(&res);
+    let (_, value) = reissued.split_once('=').unwrap();
+    assert!(value.parse::<Uuid>().is_err());
+    let saved = sessions.sessions_for_user(&user_uid).await.unwrap();
+    assert_eq!(1, saved.len());
+    assert_eq!(Some(true), saved[0].get::<bool>("visited").unwrap());
+}
+
+#[tokio::test]
+async fn user_manager_cookie_config() {
+    let sessions = Store::<Session>::new();
+    let users = Store::<User>::new();
+
+    let layer = SessionManagerLayer::new(sessions, "uid")
+        .with_cookie_config(CookieConfig {
+            path: Some("/app".to_owned()),
+            domain: Some("example.com".to_owned()),
+            ..Default::default()
+        })
+        .with_expiration(std::time::Duration::from_secs(600));
+    let service = UserManagerLayer::new(layer, users)
+        .with_anonymous()
+        .layer(service_fn(|req: Request<String>| async move {
+            req.extensions()
+                .get::<Session>()
+                .unwrap()
+                .insert("visited", true)
+                .unwrap();
+            Ok::<_, Infallible>(Response::new(String::new()))
+        }));
+
+    // The cookie of the new session follows the settings of the session
+    // layer
+    let res = service.oneshot(request(None)).await.unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    let set_cookie = res.headers()[header::SET_COOKIE].to_str().unwrap();
+    assert!(set_cookie.contains("Path=/app"), "{}", set_cookie);
+    assert!(set_cookie.contains("Domain=example.com"), "{}", set_cookie);
+    let max_age: u64 = set_cookie
+        .split("; ")
+        .find_map(|attr| attr.strip_prefix("Max-Age="))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((590..=600).contains(&max_age), "{}", set_cookie);
+}
+
+#[tokio::test]
+async fn user_manager_bearer() {
+    let sessions = Store::<Session>::new();
+    let users = Store::<User>::new();
+
+    let user = User {
+        uid: Uuid::new_v4(),
+        name: "alice",
+    };
+    users.save(&user).await.unwrap();
+    let session = Session::new(DEFAULT_EXPIRATION);
+    session.insert(SESSION_USER_KEY, user.uid).unwrap();
+    sessions.save(&session).await.unwrap();
+
+    let tokens = users.clone();
+    let bearer = BearerResolver::new(move |token: String| {
+        let tokens = tokens.clone();
+        async move {
+            match token.as_str() {
+                "alice-token" => tokens.load(&user.uid).await,
+                _ => Ok(None),
+            }
+        }
+    });
+    let service = UserManagerLayer::new(SessionManagerLayer::new(sessions, "uid"), users)
+        .with_bearer(bearer)
+        .layer(service_fn(|req: Request<String>| async move {
+            let user = req.extensions().get::<User>().unwrap();
+            Ok::<_, Infallible>(Response::new(user.name.to_owned()))
+        }));
+    let with_token = |token: &str| {
+        let mut req = request(None);
+        req.headers_mut()
+            .insert(header::AUTHORIZATION, token.parse().unwrap());
+        req
+    };
+
+    // Same route, through the session cookie or the bearer token
+    let cookie = format!("uid={}", session.uid());
+    let res = service
+        .clone()
+        .oneshot(request(Some(&cookie)))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("alice", res.body());
+
+    let res = service
+        .clone()
+        .oneshot(with_token("Bearer alice-token"))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("alice", res.body());
+
+    // Unknown tokens and other schemes are rejected
+    for token in ["Bearer unknown", "Basic alice-token", "Bearer "] {
+        let res = service.clone().oneshot(with_token(token)).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status(), "{}", token);
+    }
+}